@@ -1,6 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use kvs::error::KvStoreError;
-use kvs::{KvStore, SledEngine, KvsEngine};
+use kvs::{KvStore, KvStoreOptions, SledEngine, KvsEngine};
+use kvs::compression::Compression;
 use tempfile::TempDir;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
@@ -29,6 +30,31 @@ pub fn criterion_benchmark_kvs(c: &mut Criterion) {
     }));
 }
 
+/// repetitive JSON of up to 100 KB
+pub fn get_random_json(gen: &mut ThreadRng) -> String {
+    let cnt = gen.gen_range(1, 4000);
+    let items: Vec<String> = (0..cnt).map(|i| format!(r#"{{"id":{},"name":"item"}}"#, i)).collect();
+    format!("[{}]", items.join(","))
+}
+
+pub fn criterion_benchmark_kvs_lz4(c: &mut Criterion) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { compression: Compression::Lz4, ..Default::default() };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options).expect("unable to create KvStore");
+    let mut gen = rand::thread_rng();
+    let mut keys = vec![];
+    c.bench_function("kvs_lz4_write", |b| b.iter(|| {
+        let key = get_random_string(&mut gen);
+        let value = get_random_json(&mut gen);
+        keys.push(key.clone());
+        store.set(key, value).expect("failed to set value");
+    }));
+    c.bench_function("kvs_lz4_read", |b| b.iter(|| {
+        let key = keys[gen.gen_range(0, keys.len())].clone();
+        store.get(key).expect("failed to get key");
+    }));
+}
+
 pub fn criterion_benchmark_sled(c: &mut Criterion) {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = SledEngine::open(temp_dir.path()).expect("unable to create KvStore");
@@ -46,5 +72,5 @@ pub fn criterion_benchmark_sled(c: &mut Criterion) {
     }));
 }
 
-criterion_group!(benches, criterion_benchmark_kvs, criterion_benchmark_sled);
+criterion_group!(benches, criterion_benchmark_kvs, criterion_benchmark_kvs_lz4, criterion_benchmark_sled);
 criterion_main!(benches);
@@ -0,0 +1,169 @@
+//! defines token authentication and permissions of tokens on key prefixes
+//!
+//! A server requiring authentication loads tokens from a JSON config file, e.g.
+//!
+//! ```json
+//! {"tokens": [{"token": "secret", "name": "app", "grants": [
+//!     {"prefix": "app/", "permission": "write"},
+//!     {"namespace": "team-a", "permission": "read"}
+//! ]}]}
+//! ```
+//!
+//! A client sends `Auth` with its token before any other request. `Write`
+//! includes `Read`, and `Admin` includes both. Requests not on keys, like
+//! `Stats` or `Checkpoint`, need `Admin` on all keys of all namespaces, and
+//! `DropNamespace` needs `Admin` on all keys of the namespace.
+
+use crate::{CommandRequest, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+/// `permission` on keys starting with `prefix`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Grant {
+    /// namespace the grant applies to, all namespaces if omitted
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// all keys if empty
+    #[serde(default)]
+    pub prefix: String,
+    pub permission: Permission,
+}
+
+/// a token with the permissions granted to its holder
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    /// name of the holder, used in logs instead of the token
+    #[serde(default)]
+    pub name: String,
+    pub grants: Vec<Grant>,
+}
+
+/// tokens accepted by a server, see module docs for the file format
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuthConfig {
+    pub tokens: Vec<TokenConfig>,
+}
+
+impl AuthConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// the config of `token`, `None` if it's not accepted
+    pub fn authenticate(&self, token: &str) -> Option<&TokenConfig> {
+        self.tokens
+            .iter()
+            .find(|x| constant_time_eq(x.token.as_bytes(), token.as_bytes()))
+    }
+}
+
+impl TokenConfig {
+    /// whether the holder may send `request`
+    ///
+    /// Requests in a transaction are checked one by one, so `Begin`, `Commit`
+    /// and `Abort` are always allowed.
+    pub fn allows(&self, request: &CommandRequest) -> bool {
+        let (namespace, request) = request.namespaced();
+        if let Some(key) = request.key() {
+            let permission = if request.is_write() {
+                Permission::Write
+            } else {
+                Permission::Read
+            };
+            return self.allows_key(namespace, permission, key);
+        }
+        match request {
+            CommandRequest::MGet { keys } => keys
+                .iter()
+                .all(|key| self.allows_key(namespace, Permission::Read, key)),
+            CommandRequest::MSet { pairs } => pairs
+                .iter()
+                .all(|(key, _)| self.allows_key(namespace, Permission::Write, key)),
+            CommandRequest::Scan { start, end, .. } => {
+                self.allows_range(namespace, Permission::Read, start, end.as_deref())
+            }
+            CommandRequest::Watch { prefix } => self.allows_range(
+                namespace,
+                Permission::Read,
+                prefix,
+                prefix_end(prefix).as_deref(),
+            ),
+            CommandRequest::Begin {} | CommandRequest::Commit {} | CommandRequest::Abort {} => true,
+            CommandRequest::DropNamespace { namespace } => {
+                self.allows_range(namespace, Permission::Admin, b"", None)
+            }
+            CommandRequest::Checkpoint { .. }
+            | CommandRequest::Stats {}
+            | CommandRequest::Replicate { .. }
+            | CommandRequest::AddNode { .. }
+            | CommandRequest::RemoveNode { .. } => self.grants.iter().any(|x| {
+                x.namespace.is_none() && x.prefix.is_empty() && x.permission == Permission::Admin
+            }),
+            _ => false,
+        }
+    }
+
+    fn allows_key(&self, namespace: &str, permission: Permission, key: &[u8]) -> bool {
+        self.grants(namespace, permission)
+            .any(|x| key.starts_with(x.prefix.as_bytes()))
+    }
+
+    /// whether all keys from `start` and before `end` are allowed
+    fn allows_range(
+        &self,
+        namespace: &str,
+        permission: Permission,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> bool {
+        self.grants(namespace, permission).any(|x| {
+            let prefix = x.prefix.as_bytes();
+            start.starts_with(prefix)
+                && match prefix_end(prefix) {
+                    Some(prefix_end) => matches!(end, Some(end) if end <= prefix_end.as_slice()),
+                    None => true,
+                }
+        })
+    }
+
+    /// grants with at least `permission` in `namespace`
+    fn grants<'a>(
+        &'a self,
+        namespace: &'a str,
+        permission: Permission,
+    ) -> impl Iterator<Item = &'a Grant> {
+        self.grants.iter().filter(move |x| {
+            x.permission >= permission && x.namespace.iter().all(|x| x == namespace)
+        })
+    }
+}
+
+/// smallest key greater than all keys starting with `prefix`, `None` if there's
+/// no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// compare tokens without leaking the position of the first difference by timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
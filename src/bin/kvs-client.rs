@@ -1,8 +1,10 @@
 use clap::clap_app;
+use kvs::client::{ClientOptions, KvsClient};
 use kvs::error::KvStoreError;
-use kvs::{CommandRequest, CommandResponse};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
+use kvs::shard::{HashRing, ShardedClient};
+use kvs::tls::TlsConnector;
+use kvs::{CommandRequest, CommandResponse, WatchEvent};
+use std::io::BufRead;
 use std::process::exit;
 
 fn main() -> Result<(), failure::Error> {
@@ -10,72 +12,373 @@ fn main() -> Result<(), failure::Error> {
         (version: env!("CARGO_PKG_VERSION"))
         (author: env!("CARGO_PKG_AUTHORS"))
         (about: "A key-value store client")
+        (@arg HEX: --hex +global "keys and values are hex encoded")
+        (@arg SHARDS: --shards +global +takes_value
+            "send requests to shards addr[=weight],... by consistent hashing instead of addr")
+        (@arg NS: --ns +global +takes_value "namespace of keys, the default namespace if omitted")
+        (@arg TOKEN: --token +global +takes_value "token to authenticate with")
+        (@arg TLS_CA: --("tls-ca") +global +takes_value
+            "connect over TLS, trusting server certificates signed by CA certificates in file")
+        (@arg TLS_CERT: --("tls-cert") +global +takes_value requires[TLS_KEY TLS_CA]
+            "present client certificate chain in PEM file")
+        (@arg TLS_KEY: --("tls-key") +global +takes_value requires[TLS_CERT]
+            "private key of client certificate in PEM file")
         (@subcommand set =>
             (about: "set key-value pair")
             (@arg KEY: +required "key")
             (@arg VALUE: +required "value")
-            (@arg ADDR: --addr +takes_value "addr")
+            (@arg TTL: --ttl +takes_value "time to live in seconds")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
         )
         (@subcommand get =>
             (about: "get key-value pair by key")
             (@arg KEY: +required "key")
-            (@arg ADDR: --addr +takes_value "addr")
+            (@arg SHOW_VERSION: --("show-version") "print the version of the value")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
         )
         (@subcommand rm =>
             (about: "remove key-value pair by key")
             (@arg KEY: +required "key")
-            (@arg ADDR: --addr +takes_value "addr")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand cas =>
+            (about: "set key-value pair if the current value matches")
+            (@arg KEY: +required "key")
+            (@arg VALUE: +required "value")
+            (@arg EXPECTED: --expected +takes_value conflicts_with[EXPECTED_VERSION]
+                "expected value, key must not exist if omitted")
+            (@arg EXPECTED_VERSION: --("expected-version") +takes_value "expected version")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand setnx =>
+            (about: "set key-value pair if key doesn't exist")
+            (@arg KEY: +required "key")
+            (@arg VALUE: +required "value")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand mget =>
+            (about: "get values of multiple keys")
+            (@arg KEY: +required +multiple "keys")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand mset =>
+            (about: "set multiple key-value pairs")
+            (@arg PAIR: +required +multiple "key value ...")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand ttl =>
+            (about: "get remaining time to live of key in seconds")
+            (@arg KEY: +required "key")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand persist =>
+            (about: "remove expiry of key")
+            (@arg KEY: +required "key")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand scan =>
+            (about: "print key-value pairs in ascending order of keys")
+            (@arg START: "first key, from the smallest if omitted")
+            (@arg END: "end of keys, excluded, to the largest if omitted")
+            (@arg LIMIT: --limit +takes_value "maximum number of pairs")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand rebalance =>
+            (about: "move keys to the shards owning them, e.g. after adding a shard")
+        )
+        (@subcommand backup =>
+            (about: "write a checkpoint of the store into a directory on the server")
+            (@arg DIR: +required "directory")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand stats =>
+            (about: "show statistics of the store")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand watch =>
+            (about: "print changes of keys with prefix as they happen")
+            (@arg PREFIX: "key prefix, all keys if omitted")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand add_node =>
+            (name: "add-node")
+            (about: "add a node to the cluster")
+            (@arg ID: +required "node id")
+            (@arg RAFT_ADDR: +required "addr of the node for Raft messages")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand remove_node =>
+            (name: "remove-node")
+            (about: "remove a node from the cluster")
+            (@arg ID: +required "node id")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand drop_namespace =>
+            (name: "drop-namespace")
+            (about: "remove a namespace with all its keys")
+            (@arg NAME: +required "namespace")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
+        )
+        (@subcommand txn =>
+            (about: "run a transaction of get, set, rm commands read from stdin")
+            (@arg ADDR: --addr +takes_value "addr, or unix:PATH")
         )
     )
     .get_matches();
 
+    let hex = matches.is_present("HEX");
+    let decode = |x: &str| -> Result<Vec<u8>, failure::Error> {
+        if hex {
+            Ok(hex::decode(x)?)
+        } else {
+            Ok(x.as_bytes().to_vec())
+        }
+    };
+    let encode = |x: &[u8]| {
+        if hex {
+            hex::encode(x)
+        } else {
+            String::from_utf8_lossy(x).into_owned()
+        }
+    };
+
+    let shards = matches.value_of("SHARDS").map(parse_shards).transpose()?;
+    let namespace = matches.value_of("NS").unwrap_or(kvs::DEFAULT_NAMESPACE);
+    let identity = match (matches.value_of("TLS_CERT"), matches.value_of("TLS_KEY")) {
+        (Some(cert), Some(key)) => Some((cert.as_ref(), key.as_ref())),
+        _ => None,
+    };
+    let options = ClientOptions {
+        token: matches.value_of("TOKEN").map(Into::into),
+        tls: matches
+            .value_of("TLS_CA")
+            .map(|ca| TlsConnector::new(ca.as_ref(), identity))
+            .transpose()?,
+    };
+
     let command;
     let addr;
+    let mut show_version = false;
 
     {
         match matches.subcommand() {
             ("set", Some(cmd)) => {
-                let key = cmd
-                    .value_of("KEY")
-                    .ok_or(KvStoreError::CliError {
-                        parameter: "key".into(),
-                        required_by: "set".into(),
-                    })?
-                    .into();
-                let value = cmd
-                    .value_of("VALUE")
-                    .ok_or(KvStoreError::CliError {
-                        parameter: "value".into(),
-                        required_by: "set".into(),
-                    })?
-                    .into();
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "set".into(),
+                })?)?;
+                let value = decode(cmd.value_of("VALUE").ok_or(KvStoreError::CliError {
+                    parameter: "value".into(),
+                    required_by: "set".into(),
+                })?)?;
+
+                let ttl = match cmd.value_of("TTL") {
+                    Some(ttl) => Some(ttl.parse::<u64>()? * 1000),
+                    None => None,
+                };
 
                 addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
-                command = CommandRequest::Set { key, value };
+                command = CommandRequest::Set { key, value, ttl };
             }
             ("get", Some(cmd)) => {
-                let key = cmd
-                    .value_of("KEY")
-                    .ok_or(KvStoreError::CliError {
-                        parameter: "KEY".into(),
-                        required_by: "get".into(),
-                    })?
-                    .into();
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "KEY".into(),
+                    required_by: "get".into(),
+                })?)?;
 
                 addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                show_version = cmd.is_present("SHOW_VERSION");
                 command = CommandRequest::Get { key };
             }
             ("rm", Some(cmd)) => {
-                let key = cmd
-                    .value_of("KEY")
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "rm".into(),
+                })?)?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Remove { key };
+            }
+            ("cas", Some(cmd)) => {
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "cas".into(),
+                })?)?;
+                let value = decode(cmd.value_of("VALUE").ok_or(KvStoreError::CliError {
+                    parameter: "value".into(),
+                    required_by: "cas".into(),
+                })?)?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = match cmd.value_of("EXPECTED_VERSION") {
+                    Some(version) => CommandRequest::CompareVersionAndSwap {
+                        key,
+                        expected: Some(version.parse()?),
+                        value,
+                    },
+                    None => CommandRequest::CompareAndSwap {
+                        key,
+                        expected: cmd.value_of("EXPECTED").map(decode).transpose()?,
+                        value,
+                    },
+                };
+            }
+            ("setnx", Some(cmd)) => {
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "setnx".into(),
+                })?)?;
+                let value = decode(cmd.value_of("VALUE").ok_or(KvStoreError::CliError {
+                    parameter: "value".into(),
+                    required_by: "setnx".into(),
+                })?)?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::SetIfNotExists { key, value };
+            }
+            ("mget", Some(cmd)) => {
+                let keys = cmd
+                    .values_of("KEY")
                     .ok_or(KvStoreError::CliError {
                         parameter: "key".into(),
-                        required_by: "rm".into(),
+                        required_by: "mget".into(),
                     })?
-                    .into();
+                    .map(|x| Ok(decode(x)?.into()))
+                    .collect::<Result<_, failure::Error>>()?;
 
                 addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
-                command = CommandRequest::Remove { key };
+                command = CommandRequest::MGet { keys };
+            }
+            ("mset", Some(cmd)) => {
+                let args = cmd
+                    .values_of("PAIR")
+                    .ok_or(KvStoreError::CliError {
+                        parameter: "key".into(),
+                        required_by: "mset".into(),
+                    })?
+                    .collect::<Vec<_>>();
+                if args.len() % 2 != 0 {
+                    return Err(KvStoreError::CliError {
+                        parameter: "value".into(),
+                        required_by: "mset".into(),
+                    }
+                    .into());
+                }
+                let pairs = args
+                    .chunks(2)
+                    .map(|x| Ok((decode(x[0])?.into(), decode(x[1])?.into())))
+                    .collect::<Result<_, failure::Error>>()?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::MSet { pairs };
+            }
+            ("ttl", Some(cmd)) => {
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "ttl".into(),
+                })?)?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Ttl { key };
+            }
+            ("persist", Some(cmd)) => {
+                let key = decode(cmd.value_of("KEY").ok_or(KvStoreError::CliError {
+                    parameter: "key".into(),
+                    required_by: "persist".into(),
+                })?)?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Persist { key };
+            }
+            ("scan", Some(cmd)) => {
+                let start = decode(cmd.value_of("START").unwrap_or(""))?;
+                let end = cmd.value_of("END").map(decode).transpose()?;
+                let limit = cmd.value_of("LIMIT").map(str::parse).transpose()?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Scan { start, end, limit };
+            }
+            ("rebalance", Some(_)) => {
+                let ring = shards.ok_or(KvStoreError::CliError {
+                    parameter: "shards".into(),
+                    required_by: "rebalance".into(),
+                })?;
+                let moved = ShardedClient::with_options(ring, options).rebalance()?;
+                println!("{} keys moved", moved);
+                return Ok(());
+            }
+            ("backup", Some(cmd)) => {
+                let path = cmd.value_of("DIR").ok_or(KvStoreError::CliError {
+                    parameter: "dir".into(),
+                    required_by: "backup".into(),
+                })?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Checkpoint { path: path.into() };
+            }
+            ("stats", Some(cmd)) => {
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::Stats {};
+            }
+            ("watch", Some(cmd)) => {
+                let prefix = decode(cmd.value_of("PREFIX").unwrap_or(""))?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                for event in
+                    KvsClient::connect_with_options(addr, &options)?.watch_in(namespace, prefix)?
+                {
+                    match event? {
+                        WatchEvent::Set {
+                            key, value, seq, ..
+                        } => println!("{} set {} {}", seq, encode(&key), encode(&value)),
+                        WatchEvent::Remove { key, seq, .. } => {
+                            println!("{} rm {}", seq, encode(&key))
+                        }
+                        WatchEvent::DropNamespace { seq, .. } => println!("{} drop", seq),
+                    }
+                }
+                return Ok(());
+            }
+            ("add-node", Some(cmd)) => {
+                let id = cmd.value_of("ID").ok_or(KvStoreError::CliError {
+                    parameter: "id".into(),
+                    required_by: "add-node".into(),
+                })?;
+                let raft_addr = cmd.value_of("RAFT_ADDR").ok_or(KvStoreError::CliError {
+                    parameter: "raft addr".into(),
+                    required_by: "add-node".into(),
+                })?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::AddNode {
+                    id: id.parse()?,
+                    addr: raft_addr.into(),
+                };
+            }
+            ("remove-node", Some(cmd)) => {
+                let id = cmd.value_of("ID").ok_or(KvStoreError::CliError {
+                    parameter: "id".into(),
+                    required_by: "remove-node".into(),
+                })?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::RemoveNode { id: id.parse()? };
+            }
+            ("drop-namespace", Some(cmd)) => {
+                let name = cmd.value_of("NAME").ok_or(KvStoreError::CliError {
+                    parameter: "name".into(),
+                    required_by: "drop-namespace".into(),
+                })?;
+
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                command = CommandRequest::DropNamespace {
+                    namespace: name.into(),
+                };
+            }
+            ("txn", Some(cmd)) => {
+                addr = cmd.value_of("ADDR").unwrap_or("127.0.0.1:4000");
+                let client = KvsClient::connect_with_options(addr, &options)?;
+                return transaction(client, namespace, &decode, &encode);
             }
             _ => {
                 eprintln!("unknown command");
@@ -84,27 +387,135 @@ fn main() -> Result<(), failure::Error> {
         }
     }
 
-    let mut connection = TcpStream::connect(addr)?;
-    let mut writer = BufWriter::new(&mut connection);
-    serde_json::to_writer(&mut writer, &command)?;
-    writeln!(writer)?;
-    drop(writer);
-    let reader = BufReader::new(&mut connection);
-
-    match serde_json::from_reader(reader)? {
+    let command = command.in_namespace(namespace);
+    let response = match shards {
+        Some(ring) => ShardedClient::with_options(ring, options).request(&command)?,
+        None => KvsClient::connect_with_options(addr, &options)?.request(&command)?,
+    };
+    match response {
         CommandResponse::Error { reason } => {
             eprintln!("{}", reason);
             return Err(KvStoreError::RequestError { reason }.into());
         }
         CommandResponse::Success {} => {}
-        CommandResponse::Value { value } => match value {
-            Some(value) => println!("{}", value),
+        CommandResponse::Value { value, version } => match value {
+            Some(value) => {
+                println!("{}", encode(&value));
+                if let (true, Some(version)) = (show_version, version) {
+                    println!("version: {}", version);
+                }
+            }
             None => println!("Key not found"),
         },
         CommandResponse::KeyNotFound { .. } => {
             eprintln!("Key not found");
             exit(1);
         }
+        CommandResponse::ConditionFailed {} => {
+            eprintln!("Condition failed");
+            exit(1);
+        }
+        CommandResponse::PermissionDenied {} => {
+            eprintln!("Permission denied");
+            exit(1);
+        }
+        CommandResponse::Values { values } => {
+            for value in values {
+                match value {
+                    Some(value) => println!("{}", encode(&value)),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        CommandResponse::Pairs { pairs } => {
+            for (key, value) in pairs {
+                println!("{} {}", encode(&key), encode(&value));
+            }
+        }
+        CommandResponse::Ttl { ttl } => match ttl {
+            Some(ttl) => println!("{}", ttl.div_ceil(1000)),
+            None => println!("No expiry"),
+        },
+        CommandResponse::Stats { stats } => println!("{}", stats),
+        CommandResponse::Event { .. }
+        | CommandResponse::FileChunk { .. }
+        | CommandResponse::Replicated { .. } => {}
+    }
+    Ok(())
+}
+
+/// parse shards given as `addr[=weight],...`
+fn parse_shards(shards: &str) -> Result<HashRing, failure::Error> {
+    let mut ring = HashRing::default();
+    for shard in shards.split(',').filter(|x| !x.is_empty()) {
+        match shard.split_once('=') {
+            Some((addr, weight)) => ring.add(addr, weight.parse()?),
+            None => ring.add(shard, 1),
+        }
+    }
+    Ok(ring)
+}
+
+/// run a transaction of commands read from stdin, one per line
+///
+/// Commands are `get KEY`, `set KEY VALUE`, `rm KEY`, `commit` and `abort`.
+/// The transaction is committed at the end of input.
+fn transaction(
+    mut client: KvsClient,
+    namespace: &str,
+    decode: &dyn Fn(&str) -> Result<Vec<u8>, failure::Error>,
+    encode: &dyn Fn(&[u8]) -> String,
+) -> Result<(), failure::Error> {
+    let mut request = |command: &CommandRequest| -> Result<CommandResponse, failure::Error> {
+        match client.request(&command.clone().in_namespace(namespace))? {
+            CommandResponse::Error { reason } => {
+                eprintln!("{}", reason);
+                Err(KvStoreError::RequestError { reason }.into())
+            }
+            CommandResponse::PermissionDenied {} => {
+                eprintln!("Permission denied");
+                Err(KvStoreError::PermissionDenied {}.into())
+            }
+            response => Ok(response),
+        }
+    };
+
+    request(&CommandRequest::Begin {})?;
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let command = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => continue,
+            ["get", key] => CommandRequest::Get { key: decode(key)? },
+            ["set", key, value] => CommandRequest::Set {
+                key: decode(key)?,
+                value: decode(value)?,
+                ttl: None,
+            },
+            ["rm", key] => CommandRequest::Remove { key: decode(key)? },
+            ["commit"] => break,
+            ["abort"] => {
+                request(&CommandRequest::Abort {})?;
+                return Ok(());
+            }
+            _ => {
+                eprintln!("unknown command");
+                return Err(KvStoreError::CliUnknownCommand {}.into());
+            }
+        };
+        match request(&command)? {
+            CommandResponse::Value {
+                value: Some(value), ..
+            } => println!("{}", encode(&value)),
+            CommandResponse::Value { value: None, .. } => println!("Key not found"),
+            CommandResponse::KeyNotFound {} => eprintln!("Key not found"),
+            _ => {}
+        }
+    }
+
+    if let CommandResponse::ConditionFailed {} = request(&CommandRequest::Commit {})? {
+        eprintln!("Transaction conflicted");
+        exit(1);
     }
     Ok(())
 }
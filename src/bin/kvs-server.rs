@@ -1,11 +1,21 @@
 use clap::clap_app;
+use kvs::auth::AuthConfig;
+use kvs::client::ClientOptions;
+use kvs::encryption::Keyring;
 use kvs::error::KvStoreError;
+use kvs::raft::{RaftConfig, RaftNode, TcpTransport};
+use kvs::replication::{Replication, REPLICA_FILE};
 use kvs::server::KvsServer;
-use kvs::{KvStore, KvsEngine, SledEngine};
-use slog::{info, o, Drain};
+use kvs::stream::Listener;
+use kvs::tls::{TlsAcceptor, TlsConnector};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SledEngine};
+use slog::{info, o, warn, Drain};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
 
 fn get_current_engine() -> Option<String> {
     let mut current_engine = String::new();
@@ -18,13 +28,56 @@ fn get_current_engine() -> Option<String> {
     }
 }
 
+/// parse members of a cluster given as `id=addr,...`
+fn parse_peers(peers: &str) -> Result<BTreeMap<u64, String>, failure::Error> {
+    let mut members = BTreeMap::new();
+    for peer in peers.split(',').filter(|x| !x.is_empty()) {
+        let (id, addr) = peer.split_once('=').ok_or(KvStoreError::CliError {
+            parameter: "peers".into(),
+            required_by: "".into(),
+        })?;
+        members.insert(id.parse()?, addr.to_owned());
+    }
+    Ok(members)
+}
+
 fn main() -> Result<(), failure::Error> {
     let matches = clap_app!(kvs_server =>
         (version: env!("CARGO_PKG_VERSION"))
         (author: env!("CARGO_PKG_AUTHORS"))
         (about: "A key-value store server")
-        (@arg ADDR: --addr +takes_value "addr")
+        (@arg ADDR: --addr +takes_value "addr, or unix:PATH for a Unix domain socket")
+        (@arg SOCKET_MODE: --("socket-mode") +takes_value
+            "permissions of the Unix domain socket in octal, e.g. 660, from umask if omitted")
         (@arg ENGINE: --engine +required +takes_value "engine")
+        (@arg ARCHIVE: --archive +takes_value "archive compacted generations of kvs engine into directory")
+        (@arg ENCRYPTION_KEY_FILE: --("encryption-key-file") +takes_value
+            "encrypt kvs engine with keys in file, or in KVS_ENCRYPTION_KEY if omitted and set")
+        (@arg COMPRESSION: --compression +takes_value "compress values of kvs engine with none or lz4")
+        (@arg COMPRESSION_THRESHOLD: --("compression-threshold") +takes_value
+            "compress values of at least this many bytes, 256 if omitted")
+        (@arg BLOB_THRESHOLD: --("blob-threshold") +takes_value
+            "store values of kvs engine of at least this many bytes in blob files")
+        (@arg METRICS_ADDR: --("metrics-addr") +takes_value "serve Prometheus metrics over HTTP on addr")
+        (@arg AUTH_CONFIG: --("auth-config") +takes_value
+            "require clients to authenticate with tokens defined in file")
+        (@arg REPLICA_OF: --("replica-of") +takes_value conflicts_with[NODE_ID]
+            "replicate from primary at addr into empty directory, or resume replicating into it")
+        (@arg PRIMARY_TOKEN: --("primary-token") +takes_value requires[REPLICA_OF]
+            "token to authenticate to primary with")
+        (@arg PRIMARY_TLS_CA: --("primary-tls-ca") +takes_value requires[REPLICA_OF]
+            "connect to primary over TLS, trusting CA certificates in file")
+        (@arg TLS_CERT: --("tls-cert") +takes_value requires[TLS_KEY]
+            "serve clients over TLS with certificate chain in PEM file")
+        (@arg TLS_KEY: --("tls-key") +takes_value requires[TLS_CERT] "private key of certificate in PEM file")
+        (@arg TLS_CLIENT_CA: --("tls-client-ca") +takes_value requires[TLS_CERT]
+            "require client certificates signed by CA certificates in file")
+        (@arg NODE_ID: --("node-id") +takes_value requires[RAFT_ADDR] "id of this node in a Raft cluster")
+        (@arg RAFT_ADDR: --("raft-addr") +takes_value requires[NODE_ID] "addr to exchange Raft messages with peers")
+        (@arg PEERS: --peers +takes_value requires[NODE_ID] "other members of the cluster as id=addr,...")
+        (@arg JOIN: --join requires[NODE_ID] "join an existing cluster, after being added by kvs-client add-node")
+        (@arg RAFT_TOKEN: --("raft-token") +takes_value requires[NODE_ID]
+            "token shared by the cluster to authenticate peers, or KVS_RAFT_TOKEN if omitted and set")
     )
     .get_matches();
 
@@ -40,6 +93,28 @@ fn main() -> Result<(), failure::Error> {
         required_by: "".into(),
     })?;
 
+    // checkpoint of primary includes `.config`, which is checked below
+    let replication = match matches.value_of("REPLICA_OF") {
+        Some(primary) => {
+            let options = ClientOptions {
+                token: matches.value_of("PRIMARY_TOKEN").map(Into::into),
+                tls: matches
+                    .value_of("PRIMARY_TLS_CA")
+                    .map(|ca| TlsConnector::new(ca.as_ref(), None))
+                    .transpose()?,
+            };
+            let dir = std::env::current_dir()?;
+            if dir.join(REPLICA_FILE).exists() {
+                info!(log, "resuming replica"; "primary" => primary);
+                Some(Replication::resume(primary, &options, &dir)?)
+            } else {
+                info!(log, "bootstrapping replica"; "primary" => primary);
+                Some(Replication::bootstrap(primary, &options, &dir)?)
+            }
+        }
+        None => None,
+    };
+
     if let Some(current_engine) = get_current_engine() {
         if engine != current_engine {
             return Err(KvStoreError::CliError {
@@ -66,7 +141,37 @@ fn main() -> Result<(), failure::Error> {
 
     match engine {
         "sled" => kvs_engine = Box::new(SledEngine::open(std::env::current_dir()?)?),
-        "kvs" => kvs_engine = Box::new(KvStore::open(std::env::current_dir()?)?),
+        "kvs" => {
+            let keyring =
+                Keyring::load_or_env(matches.value_of("ENCRYPTION_KEY_FILE").map(Path::new))?;
+            if let Some(keyring) = &keyring {
+                info!(log, "encrypting"; "key_id" => keyring.active_id());
+            }
+            let mut options = KvStoreOptions {
+                archive_dir: matches.value_of("ARCHIVE").map(Into::into),
+                keyring,
+                ..Default::default()
+            };
+            if let Some(compression) = matches.value_of("COMPRESSION") {
+                options.compression = compression.parse()?;
+            }
+            if let Some(threshold) = matches.value_of("COMPRESSION_THRESHOLD") {
+                options.compression_threshold = threshold.parse()?;
+            }
+            if !options.compression.is_none() {
+                info!(log, "compressing"; "codec" => %options.compression,
+                    "threshold" => options.compression_threshold);
+            }
+            if let Some(threshold) = matches.value_of("BLOB_THRESHOLD") {
+                let threshold: usize = threshold.parse()?;
+                info!(log, "storing large values in blob files"; "threshold" => threshold);
+                options.blob_threshold = Some(threshold);
+            }
+            kvs_engine = Box::new(KvStore::open_with_options(
+                std::env::current_dir()?,
+                options,
+            )?)
+        }
         _ => {
             return Err(KvStoreError::CliError {
                 parameter: "engine".into(),
@@ -76,9 +181,62 @@ fn main() -> Result<(), failure::Error> {
         }
     }
 
-    let listener = TcpListener::bind(addr)?;
+    let socket_mode = matches
+        .value_of("SOCKET_MODE")
+        .map(|x| u32::from_str_radix(x, 8))
+        .transpose()?;
+    let listener = Listener::bind(addr, socket_mode)?;
 
-    KvsServer::new(listener, kvs_engine).serve(&log)?;
+    let mut server = KvsServer::new(listener, kvs_engine);
+    if let Some(path) = matches.value_of("AUTH_CONFIG") {
+        let config = AuthConfig::load(path.as_ref())?;
+        info!(log, "requiring authentication"; "tokens" => config.tokens.len());
+        server.require_auth(config);
+    }
+    if let (Some(cert), Some(key)) = (matches.value_of("TLS_CERT"), matches.value_of("TLS_KEY")) {
+        let client_ca = matches.value_of("TLS_CLIENT_CA").map(AsRef::as_ref);
+        info!(log, "serving over tls"; "verify_clients" => client_ca.is_some());
+        server.use_tls(TlsAcceptor::new(cert.as_ref(), key.as_ref(), client_ca)?);
+    }
+    if let Some(replication) = replication {
+        server.replicate(replication, &log);
+    }
+    if let (Some(id), Some(raft_addr)) =
+        (matches.value_of("NODE_ID"), matches.value_of("RAFT_ADDR"))
+    {
+        let id = id.parse()?;
+        let mut members = parse_peers(matches.value_of("PEERS").unwrap_or(""))?;
+        members.insert(id, raft_addr.to_owned());
+        info!(log, "starting raft node"; "id" => id, "raft_addr" => raft_addr);
+        let config = RaftConfig {
+            id,
+            members: if matches.is_present("JOIN") {
+                BTreeMap::new()
+            } else {
+                members.clone()
+            },
+            dir: Some(std::env::current_dir()?.join("raft")),
+        };
+        let token = matches
+            .value_of("RAFT_TOKEN")
+            .map(Into::into)
+            .or_else(|| std::env::var("KVS_RAFT_TOKEN").ok());
+        if token.is_none() {
+            warn!(
+                log,
+                "raft peers are not authenticated, raft addr must only be reachable by them"
+            );
+        }
+        let transport = Arc::new(TcpTransport::new(members, token.clone()));
+        let node = RaftNode::start(config, server.engine(), transport, &log)?;
+        TcpTransport::listen(TcpListener::bind(raft_addr)?, node.clone(), token, &log);
+        server.join_cluster(node);
+    }
+    if let Some(metrics_addr) = matches.value_of("METRICS_ADDR") {
+        info!(log, "serving metrics"; "addr" => metrics_addr);
+        server.serve_metrics(TcpListener::bind(metrics_addr)?, &log);
+    }
+    server.serve(&log)?;
 
     Ok(())
 }
@@ -1,12 +1,64 @@
-use clap::clap_app;
+use clap::{clap_app, ArgMatches};
+use kvs::dump::{self, DumpFormat};
+use kvs::encryption::Keyring;
 use kvs::error::KvStoreError;
-use kvs::{KvStore, KvsEngine};
+use kvs::verify::{self, LogFilter};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, RestorePoint, SledEngine};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::process::exit;
+
+/// engine of the store in current directory, as recorded by kvs-server
+fn get_current_engine() -> Result<String, failure::Error> {
+    match std::fs::read_to_string(".config") {
+        Ok(engine) => Ok(engine),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok("kvs".into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn open_engine(
+    engine: &str,
+    path: &Path,
+    keyring: Option<&Keyring>,
+) -> Result<Box<dyn KvsEngine>, failure::Error> {
+    match engine {
+        "kvs" => {
+            let options = KvStoreOptions {
+                keyring: keyring.cloned(),
+                ..Default::default()
+            };
+            Ok(Box::new(KvStore::open_with_options(path, options)?))
+        }
+        "sled" => Ok(Box::new(SledEngine::open(path)?)),
+        _ => Err(KvStoreError::CliError {
+            parameter: "engine".into(),
+            required_by: "".into(),
+        }
+        .into()),
+    }
+}
+
+fn dump_format(cmd: &ArgMatches, required_by: &str) -> Result<DumpFormat, failure::Error> {
+    match cmd.value_of("FORMAT").unwrap_or("json") {
+        "json" => Ok(DumpFormat::Json),
+        "binary" => Ok(DumpFormat::Binary),
+        _ => Err(KvStoreError::CliError {
+            parameter: "format".into(),
+            required_by: required_by.into(),
+        }
+        .into()),
+    }
+}
 
 fn main() -> Result<(), failure::Error> {
     let matches = clap_app!(kvs =>
         (version: env!("CARGO_PKG_VERSION"))
         (author: env!("CARGO_PKG_AUTHORS"))
         (about: "A key-value store")
+        (@arg ENCRYPTION_KEY_FILE: --("encryption-key-file") +takes_value +global
+            "keys of kvs engine in file, or in KVS_ENCRYPTION_KEY if omitted and set")
         (@subcommand set =>
             (about: "set key-value pair")
             (@arg KEY: +required "key")
@@ -20,10 +72,86 @@ fn main() -> Result<(), failure::Error> {
             (about: "remove key-value pair by key")
             (@arg KEY: +required "key")
         )
+        (@subcommand backup =>
+            (about: "write a checkpoint of the store into a directory")
+            (@arg DIR: +required "directory")
+        )
+        (@subcommand restore =>
+            (about: "rebuild the store as of an earlier point into a new directory")
+            (@arg DIR: +required "directory")
+            (@arg UNTIL: --until +takes_value "sequence number, or @ followed by unix time in seconds")
+            (@arg ARCHIVE: --archive +takes_value "directory of archived generations")
+        )
+        (@subcommand dump =>
+            (about: "write all key-value pairs in a portable format")
+            (@arg FILE: "output file, stdout if omitted")
+            (@arg FORMAT: --format +takes_value "json or binary, json by default")
+        )
+        (@subcommand load =>
+            (about: "read key-value pairs written by dump")
+            (@arg FILE: "input file, stdin if omitted")
+            (@arg FORMAT: --format +takes_value "json or binary, json by default")
+        )
+        (@subcommand verify =>
+            (about: "check all records in a data directory of kvs engine")
+            (@arg DIR: +required "directory")
+        )
+        (@subcommand log_dump =>
+            (name: "log-dump")
+            (about: "print records of a generation file of kvs engine")
+            (@arg FILE: +required "generation file")
+            (@arg KEY: --key +takes_value "only records of key")
+            (@arg START: --start +takes_value "only records at or after offset")
+            (@arg END: --end +takes_value "only records before offset")
+        )
+        (@subcommand migrate =>
+            (about: "copy the store into a new directory with another engine")
+            (@arg DIR: +required "directory")
+            (@arg FROM: --from +takes_value "engine of the store, checked against the current one")
+            (@arg TO: --to +required +takes_value "engine of the new store")
+        )
     )
     .get_matches();
 
-    let mut kvstore = KvStore::open(std::env::current_dir()?)?;
+    let keyring = Keyring::load_or_env(matches.value_of("ENCRYPTION_KEY_FILE").map(Path::new))?;
+
+    // offline tools must not open the store
+    match matches.subcommand() {
+        ("verify", Some(cmd)) => {
+            let dir = cmd.value_of("DIR").ok_or(KvStoreError::CliError {
+                parameter: "dir".into(),
+                required_by: "verify".into(),
+            })?;
+            let report = verify::verify(Path::new(dir), keyring.as_ref())?;
+            println!("{}", report);
+            if report.problems() > 0 {
+                exit(1);
+            }
+            return Ok(());
+        }
+        ("log-dump", Some(cmd)) => {
+            let file = cmd.value_of("FILE").ok_or(KvStoreError::CliError {
+                parameter: "file".into(),
+                required_by: "log-dump".into(),
+            })?;
+            let filter = LogFilter {
+                key: cmd.value_of("KEY").map(|x| x.as_bytes().to_vec()),
+                start: cmd.value_of("START").map(str::parse).transpose()?,
+                end: cmd.value_of("END").map(str::parse).transpose()?,
+            };
+            verify::log_dump(
+                Path::new(file),
+                keyring.as_ref(),
+                &filter,
+                std::io::stdout().lock(),
+            )?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let current_engine = get_current_engine()?;
+    let mut kvstore = open_engine(&current_engine, &std::env::current_dir()?, keyring.as_ref())?;
     match matches.subcommand() {
         ("set", Some(cmd)) => {
             let key = cmd.value_of("KEY").ok_or(KvStoreError::CliError {
@@ -64,6 +192,84 @@ fn main() -> Result<(), failure::Error> {
                 e
             })?;
         }
+        ("backup", Some(cmd)) => {
+            let dir = cmd.value_of("DIR").ok_or(KvStoreError::CliError {
+                parameter: "dir".into(),
+                required_by: "backup".into(),
+            })?;
+            kvstore.checkpoint(Path::new(dir))?;
+        }
+        ("restore", Some(cmd)) => {
+            let dir = cmd.value_of("DIR").ok_or(KvStoreError::CliError {
+                parameter: "dir".into(),
+                required_by: "restore".into(),
+            })?;
+            let until = match cmd.value_of("UNTIL") {
+                // the whole second is included
+                Some(until) if until.starts_with('@') => {
+                    RestorePoint::Time((until[1..].parse::<u64>()? + 1) * 1000 - 1)
+                }
+                Some(until) => RestorePoint::Seq(until.parse()?),
+                None => RestorePoint::Latest,
+            };
+            let options = KvStoreOptions {
+                archive_dir: cmd.value_of("ARCHIVE").map(Into::into),
+                keyring,
+                ..Default::default()
+            };
+            KvStore::restore_with_options(
+                &std::env::current_dir()?,
+                &options,
+                Path::new(dir),
+                until,
+            )?;
+        }
+        ("dump", Some(cmd)) => {
+            let format = dump_format(cmd, "dump")?;
+            match cmd.value_of("FILE") {
+                Some(file) => {
+                    dump::dump(&mut *kvstore, BufWriter::new(File::create(file)?), format)?
+                }
+                None => dump::dump(&mut *kvstore, std::io::stdout().lock(), format)?,
+            };
+        }
+        ("load", Some(cmd)) => {
+            let format = dump_format(cmd, "load")?;
+            match cmd.value_of("FILE") {
+                Some(file) => dump::load(&mut *kvstore, BufReader::new(File::open(file)?), format)?,
+                None => dump::load(&mut *kvstore, std::io::stdin().lock(), format)?,
+            };
+        }
+        ("migrate", Some(cmd)) => {
+            let dir = cmd.value_of("DIR").ok_or(KvStoreError::CliError {
+                parameter: "dir".into(),
+                required_by: "migrate".into(),
+            })?;
+            let to = cmd.value_of("TO").ok_or(KvStoreError::CliError {
+                parameter: "to".into(),
+                required_by: "migrate".into(),
+            })?;
+            if let Some(from) = cmd.value_of("FROM") {
+                if from != current_engine {
+                    return Err(KvStoreError::CliError {
+                        parameter: "from".into(),
+                        required_by: "migrate".into(),
+                    }
+                    .into());
+                }
+            }
+            let dir = Path::new(dir);
+            if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    "directory not empty",
+                )
+                .into());
+            }
+            let mut target = open_engine(to, dir, keyring.as_ref())?;
+            dump::migrate(&mut *kvstore, &mut *target)?;
+            std::fs::write(dir.join(".config"), to)?;
+        }
         _ => {
             eprintln!("unknown command");
             return Err(KvStoreError::CliUnknownCommand {}.into());
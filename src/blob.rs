@@ -0,0 +1,124 @@
+//! defines blob files of KvStore
+//!
+//! Values of at least `KvStoreOptions::blob_threshold` bytes are appended to a
+//! blob file `{id}.blob` instead of the generation, and the `Set` record only
+//! holds a `BlobRef` to it. Compaction of generations then copies these small
+//! records without touching values. Blob files are compacted on their own once
+//! more than half of their bytes belong to values no longer live, by copying live
+//! values into a new blob file and appending records referring to the copies.
+//!
+//! Ids of blob files are never reused, as records in archived generations may
+//! still refer to blob files dropped by compaction.
+
+use crate::encryption::Keyring;
+use crate::error::KvStoreError;
+use crate::log::{BlobRef, Codec, Command};
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub(crate) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+/// ids of blob files in `dir` in ascending order
+pub(crate) fn all_blob_files(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids = std::fs::read_dir(dir)?
+        .flat_map(|f| -> Result<_> { Ok(f?.path()) })
+        .filter(|f| f.is_file() && f.extension() == Some("blob".as_ref()))
+        .flat_map(|f| f.file_stem()?.to_str()?.parse::<u64>().ok())
+        .collect::<Vec<u64>>();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// blob files opened for reading
+#[derive(Default)]
+pub(crate) struct BlobFiles {
+    files: HashMap<u64, File>,
+    codecs: HashMap<u64, Codec>,
+}
+
+impl BlobFiles {
+    /// open blob file `id` in `dir`, reading its header with keys in `keyring`
+    pub fn open(&mut self, dir: &Path, id: u64, keyring: Option<&Keyring>) -> Result<()> {
+        let mut reader = BufReader::new(File::open(blob_path(dir, id))?);
+        let codec = Codec::open(&mut reader, keyring)?;
+        self.insert(id, reader.into_inner(), codec);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, id: u64, file: File, codec: Codec) {
+        self.files.insert(id, file);
+        self.codecs.insert(id, codec);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.files.remove(&id);
+        self.codecs.remove(&id);
+    }
+
+    pub fn codec(&self, id: u64) -> &Codec {
+        &self.codecs[&id]
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.files.contains_key(&id)
+    }
+
+    /// sizes of blob files by id
+    pub fn sizes(&self) -> Result<HashMap<u64, u64>> {
+        self.files
+            .iter()
+            .map(|(id, file)| Ok((*id, file.metadata()?.len())))
+            .collect()
+    }
+
+    /// read the value `blob` refers to, as stored
+    pub fn read(&mut self, blob: &BlobRef) -> Result<Vec<u8>> {
+        let file = self
+            .files
+            .get_mut(&blob.file)
+            .ok_or(KvStoreError::BlobFileNotFound { file: blob.file })?;
+        file.seek(SeekFrom::Start(blob.offset))?;
+        self.codecs[&blob.file].decode_blob(BufReader::new(&mut *file))
+    }
+
+    /// move the value of `record` from its blob file into the record
+    pub fn inline(&mut self, record: Command) -> Result<Command> {
+        match record {
+            Command::Set {
+                key,
+                seq,
+                expires_at,
+                timestamp,
+                ns,
+                compression,
+                blob: Some(blob),
+                ..
+            } => Ok(Command::Set {
+                key,
+                value: self.read(&blob)?,
+                seq,
+                expires_at,
+                timestamp,
+                ns,
+                compression,
+                blob: None,
+            }),
+            record => Ok(record),
+        }
+    }
+
+    /// decompressed value of `Set` record `record`
+    pub fn value(&mut self, record: Command) -> Result<Vec<u8>> {
+        match self.inline(record)? {
+            Command::Set {
+                value, compression, ..
+            } => compression.decompress(value),
+            _ => panic!("invalid record"),
+        }
+    }
+}
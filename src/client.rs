@@ -1 +1,126 @@
+//! defines client of kvs-server
 
+use crate::error::KvStoreError;
+use crate::stream::{self, Stream};
+use crate::tls::TlsConnector;
+use crate::{CommandRequest, CommandResponse, Result, WatchEvent, DEFAULT_NAMESPACE};
+use serde::Deserialize;
+use std::io::{BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// Options of connections to kvs-server
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    /// authenticate with this token after connecting, see `auth`
+    pub token: Option<String>,
+    /// wrap connections in TLS, see `tls`
+    pub tls: Option<TlsConnector>,
+}
+
+/// Kvs Client, sends requests to kvs-server over one connection
+pub struct KvsClient {
+    connection: Box<dyn Stream>,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Self {
+            connection: Box::new(TcpStream::connect(addr)?),
+        })
+    }
+
+    /// connect to `addr`, which may be `unix:PATH`, with `options`
+    pub fn connect_with_options(addr: &str, options: &ClientOptions) -> Result<Self> {
+        let stream = stream::connect(addr)?;
+        let mut client = Self {
+            connection: match &options.tls {
+                Some(tls) => tls.connect(addr, stream)?,
+                None => stream,
+            },
+        };
+        if let Some(token) = &options.token {
+            client.authenticate(token)?;
+        }
+        Ok(client)
+    }
+
+    /// authenticate the connection with `token`, see `auth`
+    pub fn authenticate(&mut self, token: &str) -> Result<()> {
+        let request = CommandRequest::Auth {
+            token: token.to_owned(),
+        };
+        match self.request(&request)? {
+            CommandResponse::Success {} => Ok(()),
+            CommandResponse::PermissionDenied {} => Err(KvStoreError::PermissionDenied {}),
+            response => Err(unexpected_response(Some(response))),
+        }
+    }
+
+    /// send `request` and wait for the response
+    pub fn request(&mut self, request: &CommandRequest) -> Result<CommandResponse> {
+        let mut writer = BufWriter::new(&mut self.connection);
+        serde_cbor::to_writer(&mut writer, request)?;
+        writer.flush()?;
+        drop(writer);
+        Ok(CommandResponse::deserialize(
+            &mut serde_cbor::Deserializer::from_reader(&mut self.connection),
+        )?)
+    }
+
+    /// send `request` and turn the connection into a stream of responses
+    pub fn stream(
+        mut self,
+        request: &CommandRequest,
+    ) -> Result<impl Iterator<Item = Result<CommandResponse>>> {
+        let mut writer = BufWriter::new(&mut self.connection);
+        serde_cbor::to_writer(&mut writer, request)?;
+        writer.flush()?;
+        drop(writer);
+        Ok(serde_cbor::Deserializer::from_reader(self.connection)
+            .into_iter::<CommandResponse>()
+            .map(|response| Ok(response?)))
+    }
+
+    /// watch changes of keys starting with `prefix`, the connection can't be used
+    /// for other requests afterwards
+    ///
+    /// The server disconnects after an error if the client falls too far behind.
+    pub fn watch(self, prefix: Vec<u8>) -> Result<impl Iterator<Item = Result<WatchEvent>>> {
+        self.watch_in(DEFAULT_NAMESPACE, prefix)
+    }
+
+    /// watch changes of keys starting with `prefix` in `namespace`, see `watch`
+    pub fn watch_in(
+        self,
+        namespace: &str,
+        prefix: Vec<u8>,
+    ) -> Result<impl Iterator<Item = Result<WatchEvent>>> {
+        let request = CommandRequest::Watch { prefix }.in_namespace(namespace);
+        let mut responses = self.stream(&request)?;
+        match responses.next().transpose()? {
+            Some(CommandResponse::Success {}) => {}
+            Some(CommandResponse::PermissionDenied {}) => {
+                return Err(KvStoreError::PermissionDenied {})
+            }
+            Some(CommandResponse::Error { reason }) => {
+                return Err(KvStoreError::RequestError { reason })
+            }
+            response => return Err(unexpected_response(response)),
+        }
+        Ok(responses.map(|response| match response? {
+            CommandResponse::Event { event } => Ok(event),
+            CommandResponse::Error { reason } => Err(KvStoreError::RequestError { reason }),
+            response => Err(unexpected_response(Some(response))),
+        }))
+    }
+}
+
+/// error of a response not expected by the request, `None` if connection is closed
+pub(crate) fn unexpected_response(response: Option<CommandResponse>) -> KvStoreError {
+    KvStoreError::RequestError {
+        reason: match response {
+            Some(response) => format!("unexpected response {:?}", response),
+            None => "connection closed".to_owned(),
+        },
+    }
+}
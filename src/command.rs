@@ -1,20 +1,282 @@
 //! defines logging
 
+use crate::{Stats, WatchEvent, DEFAULT_NAMESPACE};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
 /// Kvs Client Request
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Requests and responses are encoded in CBOR, keys and values are byte strings.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CommandRequest {
-    Set { key: String, value: String },
-    Remove { key: String },
-    Get { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// time to live in milliseconds
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    CompareVersionAndSwap {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        expected: Option<u64>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    SetIfNotExists {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    SetIfExists {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Ttl {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Persist {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    MGet {
+        keys: Vec<ByteBuf>,
+    },
+    MSet {
+        pairs: Vec<(ByteBuf, ByteBuf)>,
+    },
+    /// get up to `limit` pairs in ascending order of keys, with keys from `start`
+    /// on and before `end` if given
+    Scan {
+        #[serde(with = "serde_bytes")]
+        start: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        end: Option<Vec<u8>>,
+        limit: Option<u64>,
+    },
+    /// start a transaction on this connection
+    ///
+    /// Following `Get`, `Set` without ttl and `Remove` requests on the connection
+    /// are part of the transaction until `Commit` or `Abort`.
+    Begin {},
+    Commit {},
+    Abort {},
+    /// write a checkpoint of the store into directory `path` on the server
+    Checkpoint {
+        path: String,
+    },
+    Stats {},
+    /// stream changes of keys starting with `prefix` as `Event` responses
+    ///
+    /// The connection serves no other requests afterwards.
+    Watch {
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+    },
+    /// stream a checkpoint and then all changes to a replica, or only changes after
+    /// sequence number `since` to a replica resuming, see `replication`
+    Replicate {
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    /// add node `id` with Raft messages sent to `addr` to the cluster, see `raft`
+    AddNode {
+        id: u64,
+        addr: String,
+    },
+    RemoveNode {
+        id: u64,
+    },
+    /// serve `request` in namespace `namespace` instead of the default one
+    ///
+    /// A transaction stays in the namespace it was begun in.
+    InNamespace {
+        namespace: String,
+        request: Box<CommandRequest>,
+    },
+    /// remove namespace `namespace` with all its keys
+    DropNamespace {
+        namespace: String,
+    },
+    /// authenticate the connection with `token`, see `auth`
+    Auth {
+        token: String,
+    },
+}
+
+impl CommandRequest {
+    /// name of the request type, used in logs and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandRequest::Set { .. } => "set",
+            CommandRequest::Remove { .. } => "rm",
+            CommandRequest::Get { .. } => "get",
+            CommandRequest::CompareAndSwap { .. } => "cas",
+            CommandRequest::CompareVersionAndSwap { .. } => "cas_version",
+            CommandRequest::SetIfNotExists { .. } => "setnx",
+            CommandRequest::SetIfExists { .. } => "setxx",
+            CommandRequest::Ttl { .. } => "ttl",
+            CommandRequest::Persist { .. } => "persist",
+            CommandRequest::MGet { .. } => "mget",
+            CommandRequest::MSet { .. } => "mset",
+            CommandRequest::Scan { .. } => "scan",
+            CommandRequest::Begin {} => "begin",
+            CommandRequest::Commit {} => "commit",
+            CommandRequest::Abort {} => "abort",
+            CommandRequest::Checkpoint { .. } => "checkpoint",
+            CommandRequest::Stats {} => "stats",
+            CommandRequest::Watch { .. } => "watch",
+            CommandRequest::Replicate { .. } => "replicate",
+            CommandRequest::AddNode { .. } => "add_node",
+            CommandRequest::RemoveNode { .. } => "remove_node",
+            CommandRequest::InNamespace { request, .. } => request.name(),
+            CommandRequest::DropNamespace { .. } => "drop_namespace",
+            CommandRequest::Auth { .. } => "auth",
+        }
+    }
+
+    /// wrap the request to be served in `namespace`, unless it's the default one
+    pub fn in_namespace(self, namespace: &str) -> Self {
+        if namespace == DEFAULT_NAMESPACE {
+            self
+        } else {
+            CommandRequest::InNamespace {
+                namespace: namespace.to_owned(),
+                request: Box::new(self),
+            }
+        }
+    }
+
+    /// namespace the request is served in, together with the request unwrapped
+    pub fn namespaced(&self) -> (&str, &CommandRequest) {
+        match self {
+            CommandRequest::InNamespace { namespace, request } => (namespace, request),
+            request => (DEFAULT_NAMESPACE, request),
+        }
+    }
+
+    /// whether the request may modify the store
+    pub fn is_write(&self) -> bool {
+        if let CommandRequest::InNamespace { request, .. } = self {
+            return request.is_write();
+        }
+        matches!(
+            self,
+            CommandRequest::Set { .. }
+                | CommandRequest::Remove { .. }
+                | CommandRequest::CompareAndSwap { .. }
+                | CommandRequest::CompareVersionAndSwap { .. }
+                | CommandRequest::SetIfNotExists { .. }
+                | CommandRequest::SetIfExists { .. }
+                | CommandRequest::Persist { .. }
+                | CommandRequest::MSet { .. }
+                | CommandRequest::Begin {}
+                | CommandRequest::Commit {}
+                | CommandRequest::DropNamespace { .. }
+        )
+    }
+
+    /// whether the request reads keys from the store
+    pub fn is_read(&self) -> bool {
+        if let CommandRequest::InNamespace { request, .. } = self {
+            return request.is_read();
+        }
+        matches!(
+            self,
+            CommandRequest::Get { .. }
+                | CommandRequest::Ttl { .. }
+                | CommandRequest::MGet { .. }
+                | CommandRequest::Scan { .. }
+        )
+    }
+
+    /// the only key accessed by the request, if any
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            CommandRequest::Set { key, .. }
+            | CommandRequest::Remove { key }
+            | CommandRequest::Get { key }
+            | CommandRequest::CompareAndSwap { key, .. }
+            | CommandRequest::CompareVersionAndSwap { key, .. }
+            | CommandRequest::SetIfNotExists { key, .. }
+            | CommandRequest::SetIfExists { key, .. }
+            | CommandRequest::Ttl { key }
+            | CommandRequest::Persist { key } => Some(key),
+            CommandRequest::InNamespace { request, .. } => request.key(),
+            _ => None,
+        }
+    }
 }
 
 /// Kvs Server Response
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum CommandResponse {
     Success {},
-    Error { reason: String },
-    Value { value: Option<String> },
+    Error {
+        reason: String,
+    },
+    Value {
+        #[serde(with = "serde_bytes")]
+        value: Option<Vec<u8>>,
+        #[serde(default)]
+        version: Option<u64>,
+    },
     KeyNotFound {},
+    ConditionFailed {},
+    /// the connection isn't authenticated, or its token lacks the permission
+    PermissionDenied {},
+    /// remaining time to live in milliseconds
+    Ttl {
+        ttl: Option<u64>,
+    },
+    Values {
+        values: Vec<Option<ByteBuf>>,
+    },
+    Pairs {
+        pairs: Vec<(ByteBuf, ByteBuf)>,
+    },
+    Stats {
+        stats: Stats,
+    },
+    Event {
+        event: WatchEvent,
+    },
+    /// part of a file of the checkpoint sent to a replica, appended to `path`
+    /// relative to the checkpoint directory
+    FileChunk {
+        path: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// change applied on the primary, or a heartbeat if `event` is `None`
+    Replicated {
+        event: Option<WatchEvent>,
+        /// time on the primary in milliseconds since unix epoch
+        timestamp: u64,
+        /// sequence number of the latest change sent, which a replica resumes after
+        #[serde(default)]
+        seq: u64,
+    },
 }
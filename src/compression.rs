@@ -0,0 +1,83 @@
+//! defines compression of values of KvStore records
+//!
+//! Values are compressed one by one when written, if they are at least
+//! `KvStoreOptions::compression_threshold` bytes long and get smaller. The codec
+//! is recorded in each record, so records written with different options can be
+//! read back together, and compaction copies them as is.
+
+use crate::error::KvStoreError;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// codec of a value, see module docs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format prefixed with the uncompressed size
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(KvStoreError::CliError {
+                parameter: "compression".into(),
+                required_by: "".into(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl Compression {
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    /// compressed `value`, `None` if it doesn't get smaller
+    pub(crate) fn compress(&self, value: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::None => return None,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value),
+        };
+        if compressed.len() < value.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn decompress(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(value),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&value)
+                .map_err(|_| KvStoreError::DecompressionFailed {}),
+        }
+    }
+
+    /// size of `value` once decompressed, read without decompressing
+    pub(crate) fn uncompressed_len(&self, value: &[u8]) -> usize {
+        match self {
+            Compression::None => value.len(),
+            Compression::Lz4 => {
+                lz4_flex::block::uncompressed_size(value).map_or(value.len(), |(len, _)| len)
+            }
+        }
+    }
+}
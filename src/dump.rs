@@ -0,0 +1,312 @@
+//! defines portable dump format of key-value pairs
+//!
+//! A dump starts with a header, followed by one record per key. In JSON format,
+//! the header and every record are written on their own line. Keys and values are
+//! strings if valid UTF-8, and `{"hex": "..."}` otherwise. Records of keys outside
+//! the default namespace name their namespace.
+//!
+//! ```text
+//! {"format":"kvs-dump","version":2}
+//! {"key":"key1","value":"value1"}
+//! {"key":{"hex":"00ff"},"value":"value2","expires_at":1583136000000}
+//! {"namespace":"users","key":"key1","value":"value3"}
+//! ```
+//!
+//! In binary format, the header and records are CBOR items with byte strings.
+
+use crate::engine::unix_millis;
+use crate::error::KvStoreError;
+use crate::{KvsEngine, Result, DEFAULT_NAMESPACE};
+use serde::de::Deserializer;
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+const FORMAT: &str = "kvs-dump";
+/// version 2 added namespaces, version 1 dumps hold the default namespace only
+const VERSION: u32 = 2;
+
+/// number of records without expiry written to engine at once when loading
+const LOAD_BATCH: usize = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum DumpFormat {
+    Json,
+    Binary,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    namespace: String,
+    key: Data,
+    value: Data,
+    /// expiry timestamp in milliseconds since unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// bytes written as text in human readable formats and as byte string otherwise
+struct Data(Vec<u8>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    Utf8(String),
+    Hex { hex: String },
+}
+
+impl Serialize for Data {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(&self.0);
+        }
+        match std::str::from_utf8(&self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("hex", &hex::encode(&self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return Ok(Data(ByteBuf::deserialize(deserializer)?.into_vec()));
+        }
+        match Text::deserialize(deserializer)? {
+            Text::Utf8(text) => Ok(Data(text.into_bytes())),
+            Text::Hex { hex } => hex::decode(hex).map(Data).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+fn write_item(writer: &mut impl Write, item: &impl Serialize, format: DumpFormat) -> Result<()> {
+    match format {
+        DumpFormat::Json => {
+            serde_json::to_writer(&mut *writer, item)?;
+            writeln!(writer)?;
+        }
+        DumpFormat::Binary => serde_cbor::to_writer(writer, item)?,
+    }
+    Ok(())
+}
+
+/// names of all namespaces of `engine`, including the default one
+fn namespaces<E: KvsEngine + ?Sized>(engine: &mut E) -> Result<Vec<String>> {
+    let mut namespaces = engine
+        .stats()?
+        .namespaces
+        .into_iter()
+        .map(|x| x.name)
+        .collect::<Vec<_>>();
+    if !namespaces.iter().any(|x| x == DEFAULT_NAMESPACE) {
+        namespaces.insert(0, DEFAULT_NAMESPACE.to_owned());
+    }
+    Ok(namespaces)
+}
+
+/// call `f` with every live record of every namespace of `engine`
+///
+/// Each namespace is read from its own snapshot, so writes during the call are
+/// not included. `engine` uses the default namespace afterwards.
+fn for_each_record<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    mut f: impl FnMut(Record) -> Result<()>,
+) -> Result<()> {
+    for namespace in namespaces(engine)? {
+        engine.use_namespace(&namespace)?;
+        let mut snapshot = engine.snapshot()?;
+        for key in snapshot.keys_bytes() {
+            let expires_at = snapshot.expires_at_bytes(&key);
+            if let Some(value) = snapshot.get_bytes(key.clone())? {
+                f(Record {
+                    namespace: namespace.clone(),
+                    key: Data(key),
+                    value: Data(value),
+                    expires_at,
+                })?;
+            }
+        }
+    }
+    engine.use_namespace(DEFAULT_NAMESPACE)
+}
+
+/// write all live keys of all namespaces of `engine` into `writer`, returns number
+/// of keys written
+///
+/// Keys are read from a snapshot of each namespace, so writes during dump are not
+/// included. `engine` uses the default namespace afterwards.
+pub fn dump<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    mut writer: impl Write,
+    format: DumpFormat,
+) -> Result<usize> {
+    let header = Header {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+    };
+    write_item(&mut writer, &header, format)?;
+    let mut cnt = 0;
+    for_each_record(engine, |record| {
+        write_item(&mut writer, &record, format)?;
+        cnt += 1;
+        Ok(())
+    })?;
+    writer.flush()?;
+    Ok(cnt)
+}
+
+/// read items of a dump from `reader`
+fn read_items<'a, T: serde::de::DeserializeOwned + 'a>(
+    reader: impl BufRead + 'a,
+    format: DumpFormat,
+) -> Box<dyn Iterator<Item = Result<T>> + 'a> {
+    match format {
+        DumpFormat::Json => Box::new(
+            reader
+                .lines()
+                .filter(|line| !line.as_ref().is_ok_and(|x| x.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        DumpFormat::Binary => Box::new(
+            serde_cbor::Deserializer::from_reader(reader)
+                .into_iter()
+                .map(|item| Ok(item?)),
+        ),
+    }
+}
+
+/// writes key-value pairs into an engine in batches
+struct Loader<'a, E: KvsEngine + ?Sized> {
+    engine: &'a mut E,
+    /// namespace `engine` uses, `None` until the first key is written
+    namespace: Option<String>,
+    batch: Vec<(Vec<u8>, Vec<u8>)>,
+    cnt: usize,
+}
+
+impl<'a, E: KvsEngine + ?Sized> Loader<'a, E> {
+    fn new(engine: &'a mut E) -> Self {
+        Self {
+            engine,
+            namespace: None,
+            batch: vec![],
+            cnt: 0,
+        }
+    }
+
+    /// write pending keys, and use `namespace` from now on
+    fn use_namespace(&mut self, namespace: &str) -> Result<()> {
+        if self.namespace.as_deref() == Some(namespace) {
+            return Ok(());
+        }
+        if !self.batch.is_empty() {
+            self.engine
+                .set_many_bytes(std::mem::take(&mut self.batch))?;
+        }
+        self.engine.use_namespace(namespace)?;
+        self.namespace = Some(namespace.to_owned());
+        Ok(())
+    }
+
+    /// write `key` of `record`, which is skipped if already expired
+    fn put(&mut self, record: Record) -> Result<()> {
+        let Record {
+            namespace,
+            key: Data(key),
+            value: Data(value),
+            expires_at,
+        } = record;
+        self.use_namespace(&namespace)?;
+        match expires_at {
+            Some(expires_at) => {
+                let now = unix_millis();
+                if expires_at <= now {
+                    return Ok(());
+                }
+                let ttl = Duration::from_millis(expires_at - now);
+                self.engine.set_with_ttl_bytes(key, value, ttl)?;
+            }
+            None => {
+                self.batch.push((key, value));
+                if self.batch.len() >= LOAD_BATCH {
+                    self.engine
+                        .set_many_bytes(std::mem::take(&mut self.batch))?;
+                }
+            }
+        }
+        self.cnt += 1;
+        Ok(())
+    }
+
+    /// write remaining keys and use the default namespace again, returns number of
+    /// keys written
+    fn finish(mut self) -> Result<usize> {
+        if !self.batch.is_empty() {
+            self.engine
+                .set_many_bytes(std::mem::take(&mut self.batch))?;
+        }
+        if self.namespace.as_deref() != Some(DEFAULT_NAMESPACE) {
+            self.engine.use_namespace(DEFAULT_NAMESPACE)?;
+        }
+        Ok(self.cnt)
+    }
+}
+
+/// write all keys in dump from `reader` into `engine`, returns number of keys written
+///
+/// Keys already expired are skipped. Keys are written into the namespace they were
+/// dumped from, and `engine` uses the default namespace afterwards.
+pub fn load<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    mut reader: impl BufRead,
+    format: DumpFormat,
+) -> Result<usize> {
+    let header: Header = match format {
+        DumpFormat::Json => {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            serde_json::from_str(&line)?
+        }
+        DumpFormat::Binary => {
+            Header::deserialize(&mut serde_cbor::Deserializer::from_reader(reader.by_ref()))?
+        }
+    };
+    if header.format != FORMAT || header.version > VERSION {
+        return Err(KvStoreError::InvalidDump {
+            reason: format!("unsupported format {} {}", header.format, header.version),
+        });
+    }
+
+    let mut loader = Loader::new(engine);
+    for record in read_items::<Record>(reader, format) {
+        loader.put(record?)?;
+    }
+    loader.finish()
+}
+
+/// copy all live keys of all namespaces of engine `from` into engine `to`, returns
+/// number of keys copied
+///
+/// Keys are read from a snapshot of each namespace of `from`, so writes during
+/// migration are not included. Both engines use the default namespace afterwards.
+pub fn migrate<F: KvsEngine + ?Sized, T: KvsEngine + ?Sized>(
+    from: &mut F,
+    to: &mut T,
+) -> Result<usize> {
+    let mut loader = Loader::new(to);
+    for_each_record(from, |record| loader.put(record))?;
+    loader.finish()
+}
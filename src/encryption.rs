@@ -0,0 +1,131 @@
+//! defines encryption of KvStore records at rest
+//!
+//! Keys are given as a keyring, one key per line as `ID:KEY`, where `KEY` is
+//! 32 bytes in hex, e.g. generated by `openssl rand -hex 32`. Lines may also be
+//! separated by commas, so that a keyring fits in the environment variable
+//! `KVS_ENCRYPTION_KEY`.
+//!
+//! ```text
+//! 2020-06:5b0ea7c6d16e0b2bd5d1ba0ee8fbd2e25c3e0d0bd1e5c8e57a4b1c7f0a3d9e21
+//! 2020-01:0f1e2d3c4b5a69788796a5b4c3d2e1f00f1e2d3c4b5a69788796a5b4c3d2e1f0
+//! ```
+//!
+//! The first key encrypts new generations, and the others only decrypt
+//! generations written before. Records are encrypted with XChaCha20-Poly1305
+//! under a random nonce. The id of the key is stored in the header of every
+//! generation and blob file, so a key is rotated by putting a new key first.
+//! Compaction rewrites generations and blob files encrypted with other keys
+//! with the active key, after which older keys may be removed.
+
+use crate::error::KvStoreError;
+use crate::Result;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::path::Path;
+
+/// environment variable holding a keyring, read if no key file is given
+pub const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+const NONCE_LEN: usize = 24;
+
+fn invalid(reason: String) -> KvStoreError {
+    KvStoreError::InvalidEncryptionKey { reason }
+}
+
+/// a key of a keyring
+#[derive(Clone)]
+pub(crate) struct Key {
+    pub id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    /// encrypt `plaintext` into nonce followed by ciphertext
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, plaintext)
+                .expect("plaintext too long"),
+        );
+        sealed
+    }
+
+    /// decrypt data written by `seal`, `None` if it's not sealed with this key
+    /// or has been tampered with
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
+
+/// keys to encrypt and decrypt generations with, see module docs
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    /// parse keys in the format described in module docs
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut keys: Vec<Key> = vec![];
+        for line in text
+            .split(['\n', ','])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+        {
+            let (id, key) = line
+                .split_once(':')
+                .ok_or_else(|| invalid("expected ID:KEY".to_owned()))?;
+            let key =
+                hex::decode(key.trim()).map_err(|_| invalid(format!("key {} is not hex", id)))?;
+            let cipher = XChaCha20Poly1305::new_from_slice(&key)
+                .map_err(|_| invalid(format!("key {} is not 32 bytes", id)))?;
+            if keys.iter().any(|x| x.id == id) {
+                return Err(invalid(format!("duplicate key {}", id)));
+            }
+            keys.push(Key {
+                id: id.to_owned(),
+                cipher,
+            });
+        }
+        if keys.is_empty() {
+            return Err(invalid("no key".to_owned()));
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// keyring in file `path` if given, or in `KVS_ENCRYPTION_KEY` if set
+    pub fn load_or_env(path: Option<&Path>) -> Result<Option<Self>> {
+        match path {
+            Some(path) => Ok(Some(Self::load(path)?)),
+            None => match std::env::var(KEY_ENV) {
+                Ok(text) => Ok(Some(Self::parse(&text)?)),
+                Err(_) => Ok(None),
+            },
+        }
+    }
+
+    /// id of the key encrypting new generations
+    pub fn active_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    pub(crate) fn active(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<&Key> {
+        self.keys.iter().find(|x| x.id == id)
+    }
+}
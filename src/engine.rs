@@ -1,7 +1,277 @@
-use crate::Result;
+use crate::{Result, Stats, WatchEvent};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn get(&mut self, key: String) -> Result<Option<String>>;
-    fn remove(&mut self, key: String) -> Result<()>;
+/// name of the namespace operated on until another is selected, see
+/// `KvsEngine::use_namespace`
+pub const DEFAULT_NAMESPACE: &str = "";
+
+/// milliseconds since unix epoch, used as expiry timestamp of keys
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+/// create directory `dest` for a checkpoint, which must be empty if it exists
+pub(crate) fn create_checkpoint_dir(dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    if std::fs::read_dir(dest)?.next().is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "checkpoint directory not empty").into());
+    }
+    Ok(())
+}
+
+/// Key-value storage engine
+///
+/// Keys and values are arbitrary bytes. Methods without the `_bytes` suffix are
+/// a convenience layer over the byte-oriented ones for UTF-8 keys and values.
+pub trait KvsEngine: Send {
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// get values of all `keys`, in the same order as `keys`
+    fn get_many_bytes(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// set all key-value `pairs`
+    fn set_many_bytes(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()>;
+
+    /// get `value` of `key` together with the sequence number of the write
+    /// that produced it
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// set `key` to `value` only if its current value equals `expected`
+    ///
+    /// `expected` being `None` means that `key` must not exist.
+    /// Returns whether the value has been written.
+    fn set_if_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+    ) -> Result<bool>;
+
+    /// set `key` to `value` only if its current sequence number equals `expected`
+    ///
+    /// `expected` being `None` means that `key` must not exist.
+    /// Returns whether the value has been written.
+    fn set_if_version_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<u64>,
+        value: Vec<u8>,
+    ) -> Result<bool>;
+
+    /// set `key` to `value` only if `key` already exists
+    fn set_if_exists_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool>;
+
+    /// set `key` to `value`, `key` expires after `ttl`
+    fn set_with_ttl_bytes(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// get remaining time to live of `key`
+    ///
+    /// Returns `None` if `key` never expires, and `KeyNotFound` if `key` doesn't exist.
+    fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// remove expiry of `key`
+    fn persist_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    /// drop all expired keys, returns number of keys dropped
+    fn purge_expired(&mut self) -> Result<usize>;
+
+    /// apply `writes` only if the version of every key in `reads` is unchanged
+    ///
+    /// A version of `None` means that the key must not exist, and a write of `None`
+    /// removes the key. Returns whether the writes have been applied.
+    /// See `Transaction` for a higher-level interface.
+    fn commit_bytes(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<bool>;
+
+    /// take a read-only snapshot of current state
+    ///
+    /// Writes after the snapshot is taken are not visible through it.
+    /// `SledEngine` copies all live records of the namespace in use into memory,
+    /// as sled 0.31 has no read snapshots to iterate lazily.
+    fn snapshot(&mut self) -> Result<Box<dyn KvsSnapshot>>;
+
+    /// write a consistent copy of all data into directory `dest`
+    ///
+    /// `dest` also gets a `.config` naming the engine, so that `kvs-server` can be
+    /// started on it directly.
+    fn checkpoint(&mut self, dest: &Path) -> Result<()>;
+
+    /// get statistics of storage and operations
+    fn stats(&mut self) -> Result<Stats>;
+
+    /// watch changes of keys starting with `prefix`
+    ///
+    /// Every set and remove from now on is sent to the returned receiver in the
+    /// order applied, until the receiver is dropped. Expiry of keys is not reported.
+    /// At most `capacity` changes are buffered. Once more are pending, the watcher
+    /// is dropped, and the receiver disconnects after the buffered ones.
+    fn watch(&mut self, prefix: Vec<u8>, capacity: usize) -> Result<Receiver<WatchEvent>>;
+
+    /// watch changes of all keys in all namespaces like `watch`, e.g. to replicate
+    /// them
+    fn watch_all(&mut self, capacity: usize) -> Result<Receiver<WatchEvent>>;
+
+    /// get a sequence number at least that of every write so far, and below that
+    /// of every later write
+    fn seq(&mut self) -> Result<u64>;
+
+    /// apply a change of another engine keeping its sequence number, used by replicas
+    fn apply_event(&mut self, event: WatchEvent) -> Result<()>;
+
+    /// operate on namespace `name` from now on, which is created by the first write
+    /// to it
+    ///
+    /// Namespaces are separate keyspaces sharing the storage of one engine. Reads,
+    /// writes, snapshots and watchers apply to the namespace in use, which is
+    /// `DEFAULT_NAMESPACE` when the engine is opened. Expiry, checkpoints and
+    /// stats cover all namespaces.
+    fn use_namespace(&mut self, name: &str) -> Result<()>;
+
+    /// remove namespace `name` with all its keys
+    ///
+    /// The default namespace can't be dropped. If `name` is in use, the default
+    /// namespace is used from now on.
+    fn drop_namespace(&mut self, name: &str) -> Result<()>;
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned_bytes(key)?.map(|(value, _)| value))
+    }
+
+    /// get at most `limit` pairs with keys from `start` up to but excluding `end`,
+    /// in ascending order of keys
+    fn scan_bytes(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut snapshot = self.snapshot()?;
+        let mut pairs = vec![];
+        for key in snapshot.keys_bytes() {
+            if key < start {
+                continue;
+            }
+            if matches!(&end, Some(end) if key >= *end)
+                || matches!(limit, Some(limit) if pairs.len() as u64 >= limit)
+            {
+                break;
+            }
+            if let Some(value) = snapshot.get_bytes(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// set `key` to `value` only if `key` doesn't exist
+    fn set_if_not_exists_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.set_if_bytes(key, None, value)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(match self.get_bytes(key.into_bytes())? {
+            Some(value) => Some(String::from_utf8(value)?),
+            None => None,
+        })
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let keys = keys.into_iter().map(String::into_bytes).collect();
+        self.get_many_bytes(keys)?
+            .into_iter()
+            .map(|value| Ok(value.map(String::from_utf8).transpose()?))
+            .collect()
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+            .collect();
+        self.set_many_bytes(pairs)
+    }
+
+    fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        Ok(match self.get_versioned_bytes(key.into_bytes())? {
+            Some((value, seq)) => Some((String::from_utf8(value)?, seq)),
+            None => None,
+        })
+    }
+
+    fn set_if(&mut self, key: String, expected: Option<String>, value: String) -> Result<bool> {
+        self.set_if_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            value.into_bytes(),
+        )
+    }
+
+    fn set_if_version(
+        &mut self,
+        key: String,
+        expected: Option<u64>,
+        value: String,
+    ) -> Result<bool> {
+        self.set_if_version_bytes(key.into_bytes(), expected, value.into_bytes())
+    }
+
+    fn set_if_not_exists(&mut self, key: String, value: String) -> Result<bool> {
+        self.set_if_not_exists_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_if_exists(&mut self, key: String, value: String) -> Result<bool> {
+        self.set_if_exists_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    fn persist(&mut self, key: String) -> Result<()> {
+        self.persist_bytes(key.into_bytes())
+    }
+}
+
+/// Read-only view of an engine as of the time it was taken
+pub trait KvsSnapshot: Send {
+    /// get `value` of `key` together with its sequence number
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// get all keys in ascending order
+    fn keys_bytes(&self) -> Vec<Vec<u8>>;
+
+    /// get expiry timestamp of `key` in milliseconds since unix epoch
+    fn expires_at_bytes(&self, key: &[u8]) -> Option<u64>;
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned_bytes(key)?.map(|(value, _)| value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(match self.get_bytes(key.into_bytes())? {
+            Some(value) => Some(String::from_utf8(value)?),
+            None => None,
+        })
+    }
 }
@@ -22,10 +22,55 @@ pub enum KvStoreError {
     IOError(#[fail(cause)] std::io::Error),
     #[fail(display = "{}", _0)]
     SerdeError(#[fail(cause)] serde_json::error::Error),
+    #[fail(display = "{}", _0)]
+    CborError(#[fail(cause)] serde_cbor::error::Error),
+    #[fail(display = "{}", _0)]
+    Utf8Error(#[fail(cause)] std::string::FromUtf8Error),
     #[fail(display = "error from server: {}", reason)]
     RequestError { reason: String },
     #[fail(display = "{}", _0)]
     SledError(#[fail(cause)] sled::Error),
+    #[fail(display = "invalid dump: {}", reason)]
+    InvalidDump { reason: String },
+    #[fail(display = "namespace not found: {}", name)]
+    NamespaceNotFound { name: String },
+    #[fail(display = "default namespace can't be dropped")]
+    DropDefaultNamespace {},
+    #[fail(display = "permission denied")]
+    PermissionDenied {},
+    #[fail(display = "{}", _0)]
+    TlsError(#[fail(cause)] rustls::Error),
+    #[fail(display = "invalid tls config: {}", reason)]
+    InvalidTlsConfig { reason: String },
+    #[fail(display = "invalid encryption key: {}", reason)]
+    InvalidEncryptionKey { reason: String },
+    #[fail(display = "encryption key not found: {}", id)]
+    EncryptionKeyNotFound { id: String },
+    #[fail(display = "wrong encryption key: {}", id)]
+    WrongEncryptionKey { id: String },
+    #[fail(display = "failed to decrypt record")]
+    DecryptionFailed {},
+    #[fail(display = "failed to decompress value")]
+    DecompressionFailed {},
+    #[fail(display = "invalid record: {}", reason)]
+    InvalidRecord { reason: String },
+    #[fail(display = "blob file not found: {}", file)]
+    BlobFileNotFound { file: u64 },
+    #[fail(display = "changes after sequence number {} are no longer kept", seq)]
+    ReplicaBehind { seq: u64 },
+    #[fail(
+        display = "watcher fell behind, changes after sequence number {} were dropped",
+        seq
+    )]
+    WatcherBehind { seq: u64 },
+}
+
+impl KvStoreError {
+    pub(crate) fn key_not_found(key: &[u8]) -> Self {
+        KvStoreError::KeyNotFound {
+            key: String::from_utf8_lossy(key).into_owned(),
+        }
+    }
 }
 
 impl std::convert::From<std::io::Error> for KvStoreError {
@@ -45,3 +90,21 @@ impl std::convert::From<sled::Error> for KvStoreError {
         KvStoreError::SledError(err)
     }
 }
+
+impl std::convert::From<serde_cbor::error::Error> for KvStoreError {
+    fn from(err: serde_cbor::error::Error) -> Self {
+        KvStoreError::CborError(err)
+    }
+}
+
+impl std::convert::From<rustls::Error> for KvStoreError {
+    fn from(err: rustls::Error) -> Self {
+        KvStoreError::TlsError(err)
+    }
+}
+
+impl std::convert::From<std::string::FromUtf8Error> for KvStoreError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        KvStoreError::Utf8Error(err)
+    }
+}
@@ -1,18 +1,36 @@
 //! defines KvStore struct which implements a simple in-memory key-value storage
 
+pub mod auth;
+mod blob;
 pub mod client;
 mod command;
+pub mod compression;
+pub mod dump;
+pub mod encryption;
 mod engine;
 pub mod error;
 mod log;
+pub mod metrics;
+pub mod raft;
+pub mod replication;
 pub mod server;
+pub mod shard;
 mod sled_engine;
+mod stats;
 mod store;
+pub mod stream;
+pub mod tls;
+mod transaction;
+pub mod verify;
+mod watch;
 
 pub use command::{CommandRequest, CommandResponse};
-pub use engine::KvsEngine;
+pub use engine::{KvsEngine, KvsSnapshot, DEFAULT_NAMESPACE};
 pub use sled_engine::SledEngine;
-pub use store::KvStore;
+pub use stats::{ClusterStats, GenerationStats, NamespaceStats, ReplicationStats, Stats};
+pub use store::{KvStore, KvStoreOptions, RestorePoint};
+pub use transaction::Transaction;
+pub use watch::WatchEvent;
 
 use error::KvStoreError;
 
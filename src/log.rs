@@ -1,10 +1,365 @@
 //! defines logging
+//!
+//! A generation is a sequence of CBOR records. A generation encrypted with a
+//! key of a `Keyring` starts with `MAGIC` followed by a `Header`, and every record
+//! is a byte string of a CBOR `Command` sealed with that key. Plain generations
+//! have no header, so they read the same as before encryption existed.
+//!
+//! Blob files, see `blob`, start with the same header, followed by values as
+//! byte strings, sealed with the key of the header if any.
+//!
+//! Generations written before records were encoded in CBOR are streams of JSON
+//! records, which are rewritten as plain generations by `migrate_json` when the
+//! store is opened.
 
+use crate::compression::Compression;
+use crate::encryption::{Key, Keyring};
+use crate::error::KvStoreError;
+use crate::Result;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor::de::IoRead;
+use serde_cbor::StreamDeserializer;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// first bytes of a generation with a header, never the start of a CBOR `Command`
+const MAGIC: &[u8; 4] = b"KVS\x01";
+
+/// plaintext sealed into `Header::check`
+const CHECK: &[u8] = b"kvs";
 
 /// Command
+///
+/// Records are encoded in CBOR, keys and values are stored as byte strings.
+/// `seq` is the sequence number assigned to the write.
+/// `expires_at` is the expiry timestamp of the key in milliseconds since unix epoch.
+/// `timestamp` is the time of the write in milliseconds since unix epoch, 0 for records
+/// written before it was recorded.
+/// `ns` is the id of the namespace of the key, declared by a `Namespace` record.
+/// It is left out for the default namespace 0, so such records read the same as
+/// before namespaces existed.
+/// `compression` is the codec `value` is compressed with, left out if it's not.
+/// `blob` is the location of `value` if it's stored in a blob file, in which case
+/// `value` is empty.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        timestamp: u64,
+        #[serde(default, skip_serializing_if = "is_default_namespace")]
+        ns: u32,
+        #[serde(default, skip_serializing_if = "Compression::is_none")]
+        compression: Compression,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        blob: Option<BlobRef>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        timestamp: u64,
+        #[serde(default, skip_serializing_if = "is_default_namespace")]
+        ns: u32,
+    },
+    /// declare namespace `name` with id `ns`, rewritten as is by compaction
+    Namespace {
+        ns: u32,
+        name: String,
+        seq: u64,
+        timestamp: u64,
+    },
+    /// drop namespace `ns` with all its keys, after which the id may be reused
+    DropNamespace { ns: u32, seq: u64, timestamp: u64 },
+    /// highest sequence number assigned so far, written first by compaction as
+    /// the records of the latest writes may be dropped
+    Seq { seq: u64 },
+}
+
+fn is_default_namespace(ns: &u32) -> bool {
+    *ns == 0
+}
+
+/// record of generations written in JSON, records written before sequence
+/// numbers were introduced have `seq` 0
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(record: JsonCommand) -> Self {
+        match record {
+            JsonCommand::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+            } => Command::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                seq,
+                expires_at,
+                timestamp: 0,
+                ns: 0,
+                compression: Compression::None,
+                blob: None,
+            },
+            JsonCommand::Remove { key, seq } => Command::Remove {
+                key: key.into_bytes(),
+                seq,
+                timestamp: 0,
+                ns: 0,
+            },
+        }
+    }
+}
+
+/// rewrite generation `path` as plain CBOR records if it's written in JSON
+///
+/// A JSON generation starts with `{`, which neither `MAGIC` nor a CBOR `Command`
+/// does. A record torn at the end by a crash is dropped, as replay does.
+pub(crate) fn migrate_json(path: &Path) -> Result<()> {
+    let mut first = [0];
+    if File::open(path)?.read(&mut first)? == 0 || first[0] != b'{' {
+        return Ok(());
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let migrated = path.with_extension("migrating");
+    let mut writer = BufWriter::new(File::create(&migrated)?);
+    for record in serde_json::Deserializer::from_reader(reader).into_iter::<JsonCommand>() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        Codec::Plain.encode(&record.into(), &mut writer)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(migrated, path)?;
+    Ok(())
+}
+
+/// whether `error` decoding a record comes from the record being cut off at the
+/// end of a generation, as left by a crash while appending it
+pub(crate) fn is_torn(error: &KvStoreError) -> bool {
+    matches!(error, KvStoreError::CborError(e) if e.is_eof())
+}
+
+/// location of a value in a blob file, see `Command::Set`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    /// id of blob file `{file}.blob`
+    pub file: u64,
+    pub offset: u64,
+    /// length of the entry in the blob file
+    pub len: u64,
+    /// length of the value once decompressed
+    pub value_len: u64,
+}
+
+/// header of an encrypted generation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Header {
+    /// id of the key records are sealed with
+    pub key_id: String,
+    /// `CHECK` sealed with the key, so that a wrong key is detected before any record
+    #[serde(with = "serde_bytes")]
+    check: Vec<u8>,
+}
+
+impl Header {
+    /// read the header of a generation, leaving `reader` at the first record
+    ///
+    /// `None` is returned for a plain generation.
+    pub fn read(reader: &mut (impl Read + Seek)) -> Result<Option<Header>> {
+        let mut magic = [0; 4];
+        let mut len = 0;
+        while len < magic.len() {
+            match reader.read(&mut magic[len..])? {
+                0 => break,
+                x => len += x,
+            }
+        }
+        if &magic != MAGIC {
+            reader.seek(SeekFrom::Current(-(len as i64)))?;
+            return Ok(None);
+        }
+        Ok(Some(Header::deserialize(
+            &mut serde_cbor::Deserializer::from_reader(reader),
+        )?))
+    }
+}
+
+/// encoding of records of a generation, given by its header
+#[derive(Clone)]
+pub(crate) enum Codec {
+    Plain,
+    Sealed(Key),
+}
+
+impl Codec {
+    /// write the header of a new generation, encrypted with the active key of
+    /// `keyring` if given
+    pub fn create(writer: &mut impl Write, keyring: Option<&Keyring>) -> Result<Codec> {
+        let key = match keyring {
+            Some(keyring) => keyring.active(),
+            None => return Ok(Codec::Plain),
+        };
+        writer.write_all(MAGIC)?;
+        let header = Header {
+            key_id: key.id.clone(),
+            check: key.seal(CHECK),
+        };
+        serde_cbor::to_writer(writer, &header)?;
+        Ok(Codec::Sealed(key.clone()))
+    }
+
+    /// codec of a generation with `header`, checking the key in `keyring`
+    pub fn from_header(header: Option<&Header>, keyring: Option<&Keyring>) -> Result<Codec> {
+        let header = match header {
+            Some(header) => header,
+            None => return Ok(Codec::Plain),
+        };
+        let key = keyring.and_then(|x| x.get(&header.key_id)).ok_or_else(|| {
+            KvStoreError::EncryptionKeyNotFound {
+                id: header.key_id.clone(),
+            }
+        })?;
+        if key.open(&header.check).as_deref() != Some(CHECK) {
+            return Err(KvStoreError::WrongEncryptionKey {
+                id: header.key_id.clone(),
+            });
+        }
+        Ok(Codec::Sealed(key.clone()))
+    }
+
+    /// id of the key records are sealed with, `None` if they are plain
+    pub fn key_id(&self) -> Option<&str> {
+        match self {
+            Codec::Plain => None,
+            Codec::Sealed(key) => Some(&key.id),
+        }
+    }
+
+    /// read the header of a generation and check its key, leaving `reader` at
+    /// the first record
+    pub fn open(reader: &mut (impl Read + Seek), keyring: Option<&Keyring>) -> Result<Codec> {
+        Self::from_header(Header::read(reader)?.as_ref(), keyring)
+    }
+
+    pub fn encode(&self, record: &Command, writer: impl Write) -> Result<()> {
+        match self {
+            Codec::Plain => serde_cbor::to_writer(writer, record)?,
+            Codec::Sealed(key) => {
+                let sealed = key.seal(&serde_cbor::to_vec(record)?);
+                serde_cbor::to_writer(writer, &ByteBuf::from(sealed))?
+            }
+        }
+        Ok(())
+    }
+
+    /// write `value` as an entry of a blob file
+    pub fn encode_blob(&self, value: &[u8], writer: impl Write) -> Result<()> {
+        match self {
+            Codec::Plain => serde_cbor::to_writer(writer, &serde_bytes::Bytes::new(value))?,
+            Codec::Sealed(key) => serde_cbor::to_writer(writer, &ByteBuf::from(key.seal(value)))?,
+        }
+        Ok(())
+    }
+
+    /// decode the blob file entry at the position of `reader`
+    pub fn decode_blob(&self, reader: impl Read) -> Result<Vec<u8>> {
+        let entry = ByteBuf::deserialize(&mut serde_cbor::Deserializer::from_reader(reader))?;
+        match self {
+            Codec::Plain => Ok(entry.into_vec()),
+            Codec::Sealed(key) => key.open(&entry).ok_or(KvStoreError::DecryptionFailed {}),
+        }
+    }
+
+    /// decode the record at the position of `reader`
+    pub fn decode(&self, reader: impl Read) -> Result<Command> {
+        let mut de = serde_cbor::Deserializer::from_reader(reader);
+        match self {
+            Codec::Plain => Ok(Command::deserialize(&mut de)?),
+            Codec::Sealed(key) => Self::unseal(key, ByteBuf::deserialize(&mut de)?),
+        }
+    }
+
+    fn unseal(key: &Key, sealed: ByteBuf) -> Result<Command> {
+        let plaintext = key.open(&sealed).ok_or(KvStoreError::DecryptionFailed {})?;
+        Ok(serde_cbor::from_slice(&plaintext)?)
+    }
+
+    /// iterate records from the position of `reader`, usually right after the header
+    pub fn records<R: Read + Seek>(&self, mut reader: R) -> Result<Records<R>> {
+        let start = reader.stream_position()?;
+        let de = serde_cbor::Deserializer::from_reader(reader);
+        let items = match self {
+            Codec::Plain => Items::Plain(de.into_iter()),
+            Codec::Sealed(key) => Items::Sealed(de.into_iter(), key.clone()),
+        };
+        Ok(Records { start, items })
+    }
+}
+
+enum Items<R: Read> {
+    Plain(StreamDeserializer<'static, IoRead<R>, Command>),
+    Sealed(StreamDeserializer<'static, IoRead<R>, ByteBuf>, Key),
+}
+
+/// records of a generation, see `Codec::records`
+pub(crate) struct Records<R: Read> {
+    start: u64,
+    items: Items<R>,
+}
+
+impl<R: Read> Records<R> {
+    /// offset in file of the next record
+    pub fn offset(&self) -> u64 {
+        self.start
+            + match &self.items {
+                Items::Plain(de) => de.byte_offset(),
+                Items::Sealed(de, _) => de.byte_offset(),
+            } as u64
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.items {
+            Items::Plain(de) => Some(de.next()?.map_err(Into::into)),
+            Items::Sealed(de, key) => Some(
+                de.next()?
+                    .map_err(Into::into)
+                    .and_then(|x| Codec::unseal(key, x)),
+            ),
+        }
+    }
 }
@@ -0,0 +1,257 @@
+//! defines server metrics in Prometheus text exposition format
+
+use crate::{CommandResponse, Stats};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// upper bounds of request latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// latency histogram of one request type
+#[derive(Default)]
+struct Histogram {
+    /// number of observations in each bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|x| seconds <= *x) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// metrics collected by `KvsServer`
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    connections: AtomicI64,
+}
+
+impl Metrics {
+    /// record a request of type `command` which took `elapsed`
+    pub fn observe(&self, command: &'static str, elapsed: Duration, response: &CommandResponse) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+        let kind = match response {
+            CommandResponse::Error { .. } => "request",
+            CommandResponse::KeyNotFound {} => "key_not_found",
+            CommandResponse::ConditionFailed {} => "condition_failed",
+            CommandResponse::PermissionDenied {} => "permission_denied",
+            _ => return,
+        };
+        self.error(kind);
+    }
+
+    /// record an error of `kind`
+    pub fn error(&self, kind: &'static str) {
+        *self.errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// render all metrics, together with `stats` of the engine if available
+    pub fn render(&self, stats: Option<&Stats>) -> String {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap();
+        out.push_str("# HELP kvs_requests_total Number of requests handled.\n");
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        for (command, histogram) in requests.iter() {
+            writeln!(
+                out,
+                "kvs_requests_total{{command=\"{}\"}} {}",
+                command, histogram.count
+            )
+            .unwrap();
+        }
+        out.push_str("# HELP kvs_request_duration_seconds Latency of requests.\n");
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for (command, histogram) in requests.iter() {
+            let mut cumulative = 0;
+            for (le, cnt) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += cnt;
+                writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, le, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                command, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{command=\"{}\"}} {}",
+                command, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{command=\"{}\"}} {}",
+                command, histogram.count
+            )
+            .unwrap();
+        }
+        drop(requests);
+
+        out.push_str("# HELP kvs_errors_total Number of errors by kind.\n");
+        out.push_str("# TYPE kvs_errors_total counter\n");
+        for (kind, cnt) in self.errors.lock().unwrap().iter() {
+            writeln!(out, "kvs_errors_total{{kind=\"{}\"}} {}", kind, cnt).unwrap();
+        }
+
+        out.push_str("# HELP kvs_open_connections Number of open client connections.\n");
+        out.push_str("# TYPE kvs_open_connections gauge\n");
+        writeln!(
+            out,
+            "kvs_open_connections {}",
+            self.connections.load(Ordering::SeqCst)
+        )
+        .unwrap();
+
+        if let Some(stats) = stats {
+            let gauges = [
+                ("kvs_engine_keys", "Number of live keys.", stats.keys),
+                (
+                    "kvs_engine_total_bytes",
+                    "Bytes used on disk.",
+                    stats.total_bytes,
+                ),
+                (
+                    "kvs_engine_live_bytes",
+                    "Bytes of live records.",
+                    stats.live_bytes,
+                ),
+                (
+                    "kvs_engine_uncompressed_live_bytes",
+                    "Bytes of live records if their values were not compressed.",
+                    stats.uncompressed_live_bytes,
+                ),
+                (
+                    "kvs_engine_generations",
+                    "Number of generation files.",
+                    stats.generations.len() as u64,
+                ),
+                (
+                    "kvs_engine_blob_files",
+                    "Number of blob files.",
+                    stats.blob_files.len() as u64,
+                ),
+            ];
+            for (name, help, value) in gauges.iter() {
+                writeln!(out, "# HELP {} {}", name, help).unwrap();
+                writeln!(out, "# TYPE {} gauge", name).unwrap();
+                writeln!(out, "{} {}", name, value).unwrap();
+            }
+            out.push_str("# HELP kvs_namespace_keys Number of live keys by namespace.\n");
+            out.push_str("# TYPE kvs_namespace_keys gauge\n");
+            for ns in &stats.namespaces {
+                // debug formatting escapes quotes and backslashes as required
+                writeln!(
+                    out,
+                    "kvs_namespace_keys{{namespace={:?}}} {}",
+                    ns.name, ns.keys
+                )
+                .unwrap();
+            }
+            out.push_str("# HELP kvs_engine_compactions_total Number of compactions run.\n");
+            out.push_str("# TYPE kvs_engine_compactions_total counter\n");
+            writeln!(out, "kvs_engine_compactions_total {}", stats.compactions).unwrap();
+            out.push_str(
+                "# HELP kvs_engine_blob_compactions_total Number of compactions of blob files run.\n",
+            );
+            out.push_str("# TYPE kvs_engine_blob_compactions_total counter\n");
+            writeln!(
+                out,
+                "kvs_engine_blob_compactions_total {}",
+                stats.blob_compactions
+            )
+            .unwrap();
+            if let Some(ms) = stats.last_compaction_ms {
+                out.push_str(
+                    "# HELP kvs_engine_last_compaction_seconds Duration of last compaction.\n",
+                );
+                out.push_str("# TYPE kvs_engine_last_compaction_seconds gauge\n");
+                writeln!(
+                    out,
+                    "kvs_engine_last_compaction_seconds {}",
+                    ms as f64 / 1000.0
+                )
+                .unwrap();
+            }
+            if let Some(replication) = &stats.replication {
+                out.push_str(
+                    "# HELP kvs_replication_lag_seconds Time since latest state of primary applied.\n",
+                );
+                out.push_str("# TYPE kvs_replication_lag_seconds gauge\n");
+                writeln!(
+                    out,
+                    "kvs_replication_lag_seconds {}",
+                    replication.lag_ms as f64 / 1000.0
+                )
+                .unwrap();
+                out.push_str(
+                    "# HELP kvs_replication_connected Whether changes are streamed from primary.\n",
+                );
+                out.push_str("# TYPE kvs_replication_connected gauge\n");
+                writeln!(
+                    out,
+                    "kvs_replication_connected {}",
+                    replication.connected as u8
+                )
+                .unwrap();
+            }
+            if let Some(cluster) = &stats.cluster {
+                let gauges = [
+                    ("kvs_raft_term", "Current Raft term.", cluster.term),
+                    (
+                        "kvs_raft_commit_index",
+                        "Index of the latest committed entry.",
+                        cluster.commit_index,
+                    ),
+                    (
+                        "kvs_raft_applied_index",
+                        "Index of the latest applied entry.",
+                        cluster.applied_index,
+                    ),
+                    (
+                        "kvs_raft_leader",
+                        "Whether the node is the leader.",
+                        (cluster.leader == Some(cluster.id)) as u64,
+                    ),
+                ];
+                for (name, help, value) in gauges.iter() {
+                    writeln!(out, "# HELP {} {}", name, help).unwrap();
+                    writeln!(out, "# TYPE {} gauge", name).unwrap();
+                    writeln!(out, "{} {}", name, value).unwrap();
+                }
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,1200 @@
+//! defines a Raft cluster of servers in front of their engines
+//!
+//! Every node runs a thread driving the Raft state machine from its inbox, which
+//! receives messages from peers, requests from clients and timeouts. The leader
+//! appends writes and membership changes to the log and replicates it to
+//! followers, and an entry is applied to the engine of every node once it's
+//! stored on a majority. Followers forward requests to the leader. Reads are
+//! served by the leader after a round of heartbeats confirms it's still the
+//! leader, and the engine has applied everything committed when the read came
+//! in, so they are linearizable.
+//!
+//! Members are added or removed one at a time, and a membership takes effect as
+//! soon as it's appended to the log. The log isn't compacted, so a new node
+//! receives every entry from the start. Expiry of keys set with ttl is computed
+//! by each node when applying, and may differ slightly between nodes.
+//!
+//! Versions of keys are assigned by the engine of each node and differ between
+//! nodes, so requests conditional on versions are rejected, and versions
+//! returned by reads are only meaningful to the node serving them.
+//!
+//! `TcpTransport` authenticates peers with a token shared by the cluster. Without
+//! one, anyone reaching the Raft address of a node can drive it, so it must only
+//! be reachable by peers.
+
+use crate::auth::constant_time_eq;
+use crate::server::KvsServer;
+use crate::{ClusterStats, CommandRequest, CommandResponse, KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use slog::{error, info, o, Logger};
+use std::collections::hash_map::{self, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type NodeId = u64;
+
+/// interval of heartbeats sent by leader
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// minimum time without hearing from leader before a follower starts an
+/// election, the actual timeout is randomized up to twice of it
+pub const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// time a client waits for its request to be served by the cluster
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// maximum number of entries sent in one `AppendEntries`
+const MAX_ENTRIES: usize = 64;
+
+/// time to wait for a connection to a peer
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// maximum number of messages queued for a peer, more are dropped as Raft resends
+/// what is lost
+const PEER_QUEUE_CAPACITY: usize = 256;
+
+/// entry of the replicated log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub data: EntryData,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryData {
+    /// appended by a new leader, to commit entries of previous terms
+    Noop,
+    /// write request applied to the engine
+    Request(CommandRequest),
+    /// members of the cluster after a membership change
+    Config(BTreeMap<NodeId, String>),
+}
+
+/// message exchanged between nodes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        /// round of heartbeats, echoed in the response to confirm leadership for reads
+        heartbeat: u64,
+    },
+    AppendResponse {
+        term: u64,
+        success: bool,
+        /// index of the last entry matching the leader, or below which the
+        /// leader should retry if not `success`
+        match_index: u64,
+        heartbeat: u64,
+    },
+    /// request forwarded by a follower to the leader
+    Forward {
+        id: u64,
+        request: CommandRequest,
+    },
+    ForwardResponse {
+        id: u64,
+        response: Box<CommandResponse>,
+    },
+}
+
+impl Message {
+    fn term(&self) -> Option<u64> {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. } => Some(*term),
+            Message::Forward { .. } | Message::ForwardResponse { .. } => None,
+        }
+    }
+}
+
+/// delivery of messages between nodes
+///
+/// Messages may be lost, delayed or reordered.
+pub trait Transport: Send + Sync {
+    fn send(&self, from: NodeId, to: NodeId, message: Message);
+
+    /// called with the members whenever they change, e.g. to learn addresses
+    fn set_members(&self, _members: &BTreeMap<NodeId, String>) {}
+}
+
+/// configuration of a `RaftNode`
+pub struct RaftConfig {
+    pub id: NodeId,
+    /// initial members of the cluster with their addresses, which must be the
+    /// same on all of them, or empty to join an existing cluster
+    pub members: BTreeMap<NodeId, String>,
+    /// directory to persist the log in, kept in memory only if `None`
+    pub dir: Option<PathBuf>,
+}
+
+enum Input {
+    Message(NodeId, Message),
+    Request(CommandRequest, Sender<CommandResponse>),
+    /// stop the node, and notify once its storage is closed
+    Stop(Sender<()>),
+}
+
+/// where to send the response of a request
+enum Reply {
+    Local(Sender<CommandResponse>),
+    /// request `id` forwarded by follower `from`
+    Remote {
+        from: NodeId,
+        id: u64,
+    },
+}
+
+/// term, vote and progress which must survive restarts
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<NodeId>,
+    applied_index: u64,
+}
+
+const STATE_KEY: &str = "state";
+const LOG_TREE: &str = "log";
+
+/// log and hard state persisted in a sled database
+struct Storage {
+    db: sled::Db,
+    log: sled::Tree,
+}
+
+impl Storage {
+    fn open(dir: PathBuf) -> Result<Self> {
+        let db = sled::open(dir)?;
+        let log = db.open_tree(LOG_TREE)?;
+        Ok(Self { db, log })
+    }
+
+    fn load(&self) -> Result<(HardState, Vec<Entry>)> {
+        let state = match self.db.get(STATE_KEY)? {
+            Some(state) => serde_cbor::from_slice(&state)?,
+            None => HardState::default(),
+        };
+        let entries = self
+            .log
+            .iter()
+            .values()
+            .map(|x| Ok(serde_cbor::from_slice(&x?)?))
+            .collect::<Result<_>>()?;
+        Ok((state, entries))
+    }
+
+    fn save_state(&self, state: &HardState) -> Result<()> {
+        self.db.insert(STATE_KEY, serde_cbor::to_vec(state)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn append(&self, index: u64, entry: &Entry) -> Result<()> {
+        self.log
+            .insert(index.to_be_bytes(), serde_cbor::to_vec(entry)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// remove entries from `index` on
+    fn truncate(&self, index: u64) -> Result<()> {
+        for key in self.log.range(index.to_be_bytes()..).keys() {
+            self.log.remove(key?)?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// state of a leader
+struct Leadership {
+    /// index of the next entry to send to each peer
+    next_index: HashMap<NodeId, u64>,
+    /// index of the latest entry known to be stored on each peer
+    match_index: HashMap<NodeId, u64>,
+    /// latest round of heartbeats
+    heartbeat: u64,
+    /// latest round of heartbeats answered by each peer
+    acked: HashMap<NodeId, u64>,
+    /// index of the first entry of the term
+    term_start: u64,
+}
+
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<NodeId> },
+    Leader(Leadership),
+}
+
+/// read waiting to be served by leader
+struct PendingRead {
+    /// commit index when the read came in
+    index: u64,
+    /// round of heartbeats confirming leadership
+    heartbeat: u64,
+    request: CommandRequest,
+    reply: Reply,
+}
+
+/// random election timeout between `ELECTION_TIMEOUT` and twice of it
+fn election_timeout() -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    ELECTION_TIMEOUT + Duration::from_millis(random % ELECTION_TIMEOUT.as_millis() as u64)
+}
+
+fn error_response(reason: &str) -> CommandResponse {
+    CommandResponse::Error {
+        reason: reason.to_owned(),
+    }
+}
+
+/// state machine of a node, owned by its thread
+struct Node {
+    id: NodeId,
+    kvs_engine: Arc<Mutex<Box<dyn KvsEngine>>>,
+    transport: Arc<dyn Transport>,
+    storage: Option<Storage>,
+    status: Arc<Mutex<ClusterStats>>,
+    log: Logger,
+
+    term: u64,
+    voted_for: Option<NodeId>,
+    /// the entry at index `i` is `entries[i - 1]`
+    entries: Vec<Entry>,
+    commit_index: u64,
+    applied_index: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    /// when the node last heard from the leader
+    heard_at: Option<Instant>,
+    /// election timeout, or time of the next heartbeat on leader
+    deadline: Instant,
+    initial_members: BTreeMap<NodeId, String>,
+    members: BTreeMap<NodeId, String>,
+    /// index of the entry setting `members`, 0 for initial members
+    members_index: u64,
+
+    /// writes by log index, waiting to be applied
+    pending: HashMap<u64, Reply>,
+    reads: Vec<PendingRead>,
+    /// requests forwarded to leader by id
+    forwarded: HashMap<u64, (Instant, Reply)>,
+    next_forward_id: u64,
+}
+
+impl Node {
+    fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.entries[index as usize - 1].term,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members
+            .keys()
+            .copied()
+            .filter(|x| *x != self.id)
+            .collect()
+    }
+
+    fn send(&self, to: NodeId, message: Message) {
+        self.transport.send(self.id, to, message);
+    }
+
+    fn save_state(&self) -> Result<()> {
+        match &self.storage {
+            Some(storage) => storage.save_state(&HardState {
+                term: self.term,
+                voted_for: self.voted_for,
+                applied_index: self.applied_index,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// set members to the latest in the log
+    fn reload_members(&mut self) {
+        let config = self
+            .entries
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(idx, x)| match &x.data {
+                EntryData::Config(members) => Some((idx as u64 + 1, members.clone())),
+                _ => None,
+            });
+        let (index, members) = config.unwrap_or_else(|| (0, self.initial_members.clone()));
+        self.members_index = index;
+        self.members = members;
+        self.transport.set_members(&self.members);
+    }
+
+    fn append_entry(&mut self, entry: Entry) -> Result<u64> {
+        let index = self.last_index() + 1;
+        if let Some(storage) = &self.storage {
+            storage.append(index, &entry)?;
+        }
+        if let EntryData::Config(members) = &entry.data {
+            self.members = members.clone();
+            self.members_index = index;
+            self.transport.set_members(&self.members);
+        }
+        self.entries.push(entry);
+        Ok(index)
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        if let Some(storage) = &self.storage {
+            storage.truncate(index)?;
+        }
+        self.entries.truncate(index as usize - 1);
+        if self.members_index >= index {
+            self.reload_members();
+        }
+        Ok(())
+    }
+
+    fn reply(&self, reply: Reply, response: CommandResponse) {
+        match reply {
+            Reply::Local(sender) => {
+                sender.send(response).ok();
+            }
+            Reply::Remote { from, id } => {
+                let response = Box::new(response);
+                self.send(from, Message::ForwardResponse { id, response })
+            }
+        }
+    }
+
+    fn run(mut self, inbox: Receiver<Input>) -> Result<()> {
+        self.apply()?;
+        loop {
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            match inbox.recv_timeout(timeout) {
+                Ok(Input::Message(from, message)) => self.step(from, message)?,
+                Ok(Input::Request(request, sender)) => {
+                    self.request(request, Reply::Local(sender))?
+                }
+                Ok(Input::Stop(stopped)) => {
+                    drop(self);
+                    stopped.send(()).ok();
+                    return Ok(());
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if Instant::now() >= self.deadline {
+                self.tick()?;
+            }
+            self.apply()?;
+            self.update_status();
+        }
+    }
+
+    fn update_status(&self) {
+        let role = match self.role {
+            Role::Follower => "follower",
+            Role::Candidate { .. } => "candidate",
+            Role::Leader(_) => "leader",
+        };
+        *self.status.lock().unwrap() = ClusterStats {
+            id: self.id,
+            role: role.to_owned(),
+            term: self.term,
+            leader: self.leader,
+            commit_index: self.commit_index,
+            applied_index: self.applied_index,
+            members: self.members.clone(),
+        };
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        let now = Instant::now();
+        self.forwarded
+            .retain(|_, (sent_at, _)| now.duration_since(*sent_at) < REQUEST_TIMEOUT);
+        if let Role::Leader(_) = self.role {
+            self.broadcast_append();
+            self.deadline = now + HEARTBEAT_INTERVAL;
+        } else if self.members.contains_key(&self.id) {
+            self.campaign()?;
+        } else {
+            // not a member yet, or removed
+            self.deadline = now + election_timeout();
+        }
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.save_state()?;
+        self.set_leader(None);
+        info!(self.log, "starting election"; "term" => self.term);
+        let mut votes = HashSet::new();
+        votes.insert(self.id);
+        self.role = Role::Candidate { votes };
+        self.deadline = Instant::now() + election_timeout();
+        let message = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.term_at(self.last_index()),
+        };
+        for peer in self.peers() {
+            self.send(peer, message.clone());
+        }
+        self.check_votes()
+    }
+
+    fn check_votes(&mut self) -> Result<()> {
+        let won = match &self.role {
+            Role::Candidate { votes } => {
+                self.members.keys().filter(|x| votes.contains(x)).count() >= self.quorum()
+            }
+            _ => false,
+        };
+        if won {
+            self.become_leader()?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!(self.log, "elected as leader"; "term" => self.term);
+        self.set_leader(Some(self.id));
+        self.role = Role::Leader(Leadership {
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            heartbeat: 0,
+            acked: HashMap::new(),
+            term_start: self.last_index() + 1,
+        });
+        self.append_entry(Entry {
+            term: self.term,
+            data: EntryData::Noop,
+        })?;
+        self.broadcast_append();
+        self.advance_commit();
+        self.deadline = Instant::now() + HEARTBEAT_INTERVAL;
+        Ok(())
+    }
+
+    /// step down to follower of `leader` in `term`
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term != self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_state()?;
+        }
+        if let Role::Leader(_) = self.role {
+            info!(self.log, "stepping down"; "term" => self.term);
+            // entries might still be committed by the next leader
+            for (_, reply) in self.pending.drain().collect::<Vec<_>>() {
+                self.reply(reply, error_response("leader changed, outcome unknown"));
+            }
+            for read in std::mem::take(&mut self.reads) {
+                self.reply(read.reply, error_response("leader changed"));
+            }
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.role = Role::Follower;
+            self.deadline = Instant::now() + election_timeout();
+        }
+        self.set_leader(leader);
+        Ok(())
+    }
+
+    /// change the known leader, failing requests forwarded to the old one
+    fn set_leader(&mut self, leader: Option<NodeId>) {
+        if self.leader == leader {
+            return;
+        }
+        self.leader = leader;
+        for (_, (_, reply)) in self.forwarded.drain().collect::<Vec<_>>() {
+            self.reply(reply, error_response("leader changed, outcome unknown"));
+        }
+    }
+
+    /// whether a leader is known to be alive, in which case votes are refused
+    /// so that removed or partitioned nodes can't disrupt the cluster
+    fn leader_alive(&self) -> bool {
+        match self.role {
+            Role::Leader(_) => true,
+            _ => {
+                self.leader.is_some()
+                    && matches!(self.heard_at, Some(x) if x.elapsed() < ELECTION_TIMEOUT)
+            }
+        }
+    }
+
+    fn step(&mut self, from: NodeId, message: Message) -> Result<()> {
+        if let Some(term) = message.term() {
+            if let Message::RequestVote { .. } = message {
+                if self.leader_alive() {
+                    return Ok(());
+                }
+            }
+            if term > self.term {
+                self.become_follower(term, None)?;
+            }
+        }
+        match message {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (self.term_at(self.last_index()), self.last_index());
+                let granted = term == self.term
+                    && (self.voted_for.is_none() || self.voted_for == Some(from))
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.save_state()?;
+                    self.deadline = Instant::now() + election_timeout();
+                }
+                self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term,
+                        granted,
+                    },
+                );
+            }
+            Message::Vote { term, granted } => {
+                if let Role::Candidate { votes } = &mut self.role {
+                    if term == self.term && granted {
+                        votes.insert(from);
+                    }
+                }
+                self.check_votes()?;
+            }
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                heartbeat,
+            } => self.append_entries(
+                from,
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                heartbeat,
+            )?,
+            Message::AppendResponse {
+                term,
+                success,
+                match_index,
+                heartbeat,
+            } => {
+                if term == self.term {
+                    self.append_response(from, success, match_index, heartbeat);
+                }
+            }
+            Message::Forward { id, request } => {
+                self.request(request, Reply::Remote { from, id })?
+            }
+            Message::ForwardResponse { id, response } => {
+                if let Some((_, reply)) = self.forwarded.remove(&id) {
+                    self.reply(reply, *response);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn append_entries(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        heartbeat: u64,
+    ) -> Result<()> {
+        let mut response = Message::AppendResponse {
+            term: self.term,
+            success: false,
+            match_index: 0,
+            heartbeat,
+        };
+        if term < self.term {
+            self.send(from, response);
+            return Ok(());
+        }
+        self.become_follower(term, Some(from))?;
+        self.heard_at = Some(Instant::now());
+        self.deadline = Instant::now() + election_timeout();
+
+        if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+            if let Message::AppendResponse { match_index, .. } = &mut response {
+                *match_index = (prev_log_index - 1).min(self.last_index());
+            }
+            self.send(from, response);
+            return Ok(());
+        }
+        let mut index = prev_log_index;
+        for entry in entries {
+            index += 1;
+            if index <= self.last_index() {
+                if self.term_at(index) == entry.term {
+                    continue;
+                }
+                self.truncate(index)?;
+            }
+            self.append_entry(entry)?;
+        }
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(index);
+        }
+        if let Message::AppendResponse {
+            success,
+            match_index,
+            ..
+        } = &mut response
+        {
+            *success = true;
+            *match_index = index;
+        }
+        self.send(from, response);
+        Ok(())
+    }
+
+    fn append_response(&mut self, from: NodeId, success: bool, match_index: u64, heartbeat: u64) {
+        let last_index = self.last_index();
+        let leadership = match &mut self.role {
+            Role::Leader(leadership) => leadership,
+            _ => return,
+        };
+        let acked = leadership.acked.entry(from).or_insert(0);
+        *acked = heartbeat.max(*acked);
+        let matched = leadership.match_index.entry(from).or_insert(0);
+        if success {
+            *matched = match_index.max(*matched);
+        }
+        let next = if success {
+            *matched + 1
+        } else {
+            (match_index + 1).max(*matched + 1)
+        };
+        leadership.next_index.insert(from, next);
+        if success {
+            self.advance_commit();
+        }
+        if !success || next <= last_index {
+            self.send_append(from);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let last_index = self.last_index();
+        let (next, heartbeat) = match &mut self.role {
+            Role::Leader(leadership) => (
+                *leadership.next_index.entry(peer).or_insert(last_index + 1),
+                leadership.heartbeat,
+            ),
+            _ => return,
+        };
+        let prev_log_index = next - 1;
+        let entries = self.entries[prev_log_index as usize..]
+            .iter()
+            .take(MAX_ENTRIES)
+            .cloned()
+            .collect();
+        self.send(
+            peer,
+            Message::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index),
+                entries,
+                leader_commit: self.commit_index,
+                heartbeat,
+            },
+        );
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers() {
+            self.send_append(peer);
+        }
+    }
+
+    /// commit the latest entry of the term stored on a majority
+    fn advance_commit(&mut self) {
+        let leadership = match &self.role {
+            Role::Leader(leadership) => leadership,
+            _ => return,
+        };
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            // entries of previous terms are committed along with this term's
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let stored = self
+                .members
+                .keys()
+                .filter(|x| {
+                    **x == self.id
+                        || matches!(leadership.match_index.get(x), Some(x) if *x >= index)
+                })
+                .count();
+            if stored >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    /// whether a majority answered heartbeats of round `heartbeat`
+    fn confirmed(&self, heartbeat: u64) -> bool {
+        let leadership = match &self.role {
+            Role::Leader(leadership) => leadership,
+            _ => return false,
+        };
+        let acked = self
+            .members
+            .keys()
+            .filter(|x| {
+                **x == self.id || matches!(leadership.acked.get(x), Some(x) if *x >= heartbeat)
+            })
+            .count();
+        acked >= self.quorum()
+    }
+
+    fn request(&mut self, request: CommandRequest, reply: Reply) -> Result<()> {
+        let leadership = match &mut self.role {
+            Role::Leader(leadership) => leadership,
+            _ => {
+                self.forward(request, reply);
+                return Ok(());
+            }
+        };
+        let term_start = leadership.term_start;
+        match request {
+            CommandRequest::AddNode { .. } | CommandRequest::RemoveNode { .. } => {
+                if self.members_index > self.commit_index || self.commit_index < term_start {
+                    self.reply(reply, error_response("membership change in progress"));
+                    return Ok(());
+                }
+                let mut members = self.members.clone();
+                match request {
+                    CommandRequest::AddNode { id, addr } => {
+                        members.insert(id, addr);
+                    }
+                    CommandRequest::RemoveNode { id } => {
+                        if members.remove(&id).is_none() || members.is_empty() {
+                            self.reply(reply, error_response("can't remove node"));
+                            return Ok(());
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                info!(self.log, "changing members"; "members" => format!("{:?}", members));
+                let index = self.append_entry(Entry {
+                    term: self.term,
+                    data: EntryData::Config(members),
+                })?;
+                self.pending.insert(index, reply);
+            }
+            request if request.is_write() => {
+                let index = self.append_entry(Entry {
+                    term: self.term,
+                    data: EntryData::Request(request),
+                })?;
+                self.pending.insert(index, reply);
+            }
+            request => {
+                leadership.heartbeat += 1;
+                self.reads.push(PendingRead {
+                    index: self.commit_index.max(term_start),
+                    heartbeat: leadership.heartbeat,
+                    request,
+                    reply,
+                });
+            }
+        }
+        self.broadcast_append();
+        self.advance_commit();
+        Ok(())
+    }
+
+    fn forward(&mut self, request: CommandRequest, reply: Reply) {
+        match (self.leader, &reply) {
+            (Some(leader), Reply::Local(_)) => {
+                let id = self.next_forward_id;
+                self.next_forward_id += 1;
+                self.forwarded.insert(id, (Instant::now(), reply));
+                self.send(leader, Message::Forward { id, request });
+            }
+            // forwarded by a follower with an outdated leader
+            (Some(_), Reply::Remote { .. }) => self.reply(reply, error_response("not the leader")),
+            (None, _) => self.reply(reply, error_response("no leader elected")),
+        }
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        while self.applied_index < self.commit_index {
+            let index = self.applied_index + 1;
+            let reply = self.pending.remove(&index);
+            let response = match self.entries[index as usize - 1].data.clone() {
+                EntryData::Noop => None,
+                EntryData::Request(request) => {
+                    let mut kvs_engine = self.kvs_engine.lock().unwrap();
+                    Some(KvsServer::handle(
+                        kvs_engine.as_mut(),
+                        &mut None,
+                        request,
+                        &self.log,
+                    ))
+                }
+                EntryData::Config(members) => {
+                    if !members.contains_key(&self.id) && self.leader == Some(self.id) {
+                        info!(self.log, "removed from cluster");
+                        self.become_follower(self.term, None)?;
+                    }
+                    Some(CommandResponse::Success {})
+                }
+            };
+            self.applied_index = index;
+            self.save_state()?;
+            if let (Some(reply), Some(response)) = (reply, response) {
+                self.reply(reply, response);
+            }
+        }
+
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|x| {
+                x.index <= self.applied_index && self.confirmed(x.heartbeat)
+            });
+        self.reads = waiting;
+        for read in ready {
+            let response = {
+                let mut kvs_engine = self.kvs_engine.lock().unwrap();
+                KvsServer::handle(kvs_engine.as_mut(), &mut None, read.request, &self.log)
+            };
+            self.reply(read.reply, response);
+        }
+        Ok(())
+    }
+}
+
+/// Handle to a running Raft node
+#[derive(Clone)]
+pub struct RaftNode {
+    id: NodeId,
+    inbox: Sender<Input>,
+    status: Arc<Mutex<ClusterStats>>,
+}
+
+impl RaftNode {
+    /// start a node in front of `kvs_engine` in background
+    ///
+    /// Messages from peers must be passed to `receive`, e.g. by `TcpTransport::listen`.
+    pub fn start(
+        config: RaftConfig,
+        kvs_engine: Arc<Mutex<Box<dyn KvsEngine>>>,
+        transport: Arc<dyn Transport>,
+        log: &Logger,
+    ) -> Result<Self> {
+        let storage = config.dir.map(Storage::open).transpose()?;
+        let (state, entries) = match &storage {
+            Some(storage) => storage.load()?,
+            None => (HardState::default(), vec![]),
+        };
+        let status = Arc::new(Mutex::new(ClusterStats {
+            id: config.id,
+            role: "follower".to_owned(),
+            term: state.term,
+            leader: None,
+            commit_index: state.applied_index,
+            applied_index: state.applied_index,
+            members: BTreeMap::new(),
+        }));
+        let log = log.new(o!("node" => config.id));
+        let mut node = Node {
+            id: config.id,
+            kvs_engine,
+            transport,
+            storage,
+            status: status.clone(),
+            log: log.clone(),
+            term: state.term,
+            voted_for: state.voted_for,
+            entries,
+            // entries applied before are known to be committed
+            commit_index: state.applied_index,
+            applied_index: state.applied_index,
+            role: Role::Follower,
+            leader: None,
+            heard_at: None,
+            deadline: Instant::now() + election_timeout(),
+            initial_members: config.members,
+            members: BTreeMap::new(),
+            members_index: 0,
+            pending: HashMap::new(),
+            reads: vec![],
+            forwarded: HashMap::new(),
+            next_forward_id: 0,
+        };
+        node.reload_members();
+        node.update_status();
+
+        let (inbox, receiver) = channel();
+        thread::spawn(move || {
+            if let Err(e) = node.run(receiver) {
+                error!(log, "raft node failed"; "error" => format!("{:?}", e));
+            }
+        });
+        Ok(Self {
+            id: config.id,
+            inbox,
+            status,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// handle `message` from peer `from`
+    pub fn receive(&self, from: NodeId, message: Message) {
+        self.inbox.send(Input::Message(from, message)).ok();
+    }
+
+    /// serve a write, read or membership change through the cluster
+    ///
+    /// Failures, including timeouts, are reported as `CommandResponse::Error`,
+    /// after which a write may or may not have been applied.
+    pub fn request(&self, request: CommandRequest) -> CommandResponse {
+        if let CommandRequest::CompareVersionAndSwap { .. } = request.namespaced().1 {
+            return error_response("versions are not supported in cluster mode");
+        }
+        let (sender, receiver) = channel();
+        if self.inbox.send(Input::Request(request, sender)).is_err() {
+            return error_response("raft node stopped");
+        }
+        receiver
+            .recv_timeout(REQUEST_TIMEOUT)
+            .unwrap_or_else(|_| error_response("request timed out"))
+    }
+
+    pub fn stats(&self) -> ClusterStats {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// stop the node, which no longer handles messages or requests, and wait
+    /// until it's stopped
+    pub fn stop(&self) {
+        let (sender, receiver) = channel();
+        if self.inbox.send(Input::Stop(sender)).is_ok() {
+            receiver.recv().ok();
+        }
+    }
+}
+
+/// Transport between nodes in one process, e.g. for tests
+#[derive(Default)]
+pub struct MemoryTransport {
+    nodes: Mutex<HashMap<NodeId, RaftNode>>,
+    isolated: Mutex<HashSet<NodeId>>,
+}
+
+impl MemoryTransport {
+    /// deliver messages to `node`, replacing any node with the same id
+    pub fn register(&self, node: &RaftNode) {
+        self.nodes.lock().unwrap().insert(node.id, node.clone());
+    }
+
+    /// drop all messages from and to node `id`, as if it's partitioned away
+    pub fn isolate(&self, id: NodeId) {
+        self.isolated.lock().unwrap().insert(id);
+    }
+
+    pub fn heal(&self, id: NodeId) {
+        self.isolated.lock().unwrap().remove(&id);
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, from: NodeId, to: NodeId, message: Message) {
+        {
+            let isolated = self.isolated.lock().unwrap();
+            if isolated.contains(&from) || isolated.contains(&to) {
+                return;
+            }
+        }
+        if let Some(node) = self.nodes.lock().unwrap().get(&to) {
+            node.receive(from, message);
+        }
+    }
+}
+
+/// Transport over TCP, where each message is a CBOR-encoded `(from, message)`
+///
+/// Every peer gets a connection from a background thread, which is reopened
+/// after errors. Messages sent while a peer is unreachable are dropped. If the
+/// cluster has a token, every connection starts with it as a CBOR string, and
+/// connections starting with another one are dropped.
+pub struct TcpTransport {
+    addrs: Mutex<HashMap<NodeId, String>>,
+    senders: Mutex<HashMap<NodeId, SyncSender<(NodeId, Message)>>>,
+    token: Option<String>,
+}
+
+impl TcpTransport {
+    /// transport to peers at `addrs`, which are also learned from members later,
+    /// authenticating to them with `token`
+    pub fn new(addrs: BTreeMap<NodeId, String>, token: Option<String>) -> Self {
+        Self {
+            addrs: Mutex::new(addrs.into_iter().collect()),
+            senders: Default::default(),
+            token,
+        }
+    }
+
+    /// pass messages from peers connecting to `listener` with `token` to `node`
+    /// in background
+    pub fn listen(listener: TcpListener, node: RaftNode, token: Option<String>, log: &Logger) {
+        let log = log.clone();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let connection = match connection {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!(log, "failed to accept peer"; "error" => format!("{:?}", e));
+                        continue;
+                    }
+                };
+                let node = node.clone();
+                let token = token.clone();
+                let log = log.clone();
+                thread::spawn(move || {
+                    let peer = connection.peer_addr().ok();
+                    let mut de = serde_cbor::Deserializer::from_reader(connection);
+                    if let Some(token) = token {
+                        let received = String::deserialize(&mut de).unwrap_or_default();
+                        if !constant_time_eq(received.as_bytes(), token.as_bytes()) {
+                            error!(log, "peer failed to authenticate"; "addr" => format!("{:?}", peer));
+                            return;
+                        }
+                    }
+                    for message in de.into_iter::<(NodeId, Message)>() {
+                        match message {
+                            Ok((from, message)) => node.receive(from, message),
+                            Err(_) => break,
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// write messages from `receiver` to peer at `addr`
+    ///
+    /// `addr` is resolved only until it succeeds once. While the peer can't be
+    /// connected to, messages queued meanwhile are dropped, as they are stale once
+    /// it's back.
+    fn connect(addr: String, token: Option<String>, receiver: Receiver<(NodeId, Message)>) {
+        let mut resolved = None;
+        let mut writer = None;
+        for message in receiver.iter() {
+            if resolved.is_none() {
+                resolved = addr.to_socket_addrs().ok().and_then(|mut x| x.next());
+            }
+            if writer.is_none() {
+                writer = resolved
+                    .and_then(|x| TcpStream::connect_timeout(&x, CONNECT_TIMEOUT).ok())
+                    .map(|x| {
+                        x.set_nodelay(true).ok();
+                        BufWriter::new(x)
+                    });
+                if let (Some(stream), Some(token)) = (&mut writer, &token) {
+                    if serde_cbor::to_writer(&mut *stream, token).is_err() {
+                        writer = None;
+                    }
+                }
+                if writer.is_none() {
+                    receiver.try_iter().for_each(drop);
+                    continue;
+                }
+            }
+            if let Some(stream) = &mut writer {
+                if serde_cbor::to_writer(&mut *stream, &message).is_err() || stream.flush().is_err()
+                {
+                    writer = None;
+                }
+            }
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, from: NodeId, to: NodeId, message: Message) {
+        let mut senders = self.senders.lock().unwrap();
+        let sender = match senders.entry(to) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                let addr = match self.addrs.lock().unwrap().get(&to) {
+                    Some(addr) => addr.clone(),
+                    None => return,
+                };
+                let (sender, receiver) = sync_channel(PEER_QUEUE_CAPACITY);
+                let token = self.token.clone();
+                thread::spawn(move || Self::connect(addr, token, receiver));
+                entry.insert(sender)
+            }
+        };
+        // drop the message if the peer's queue is full
+        sender.try_send((from, message)).ok();
+    }
+
+    fn set_members(&self, members: &BTreeMap<NodeId, String>) {
+        let mut addrs = self.addrs.lock().unwrap();
+        let mut senders = self.senders.lock().unwrap();
+        for (id, addr) in members {
+            if addrs.get(id) != Some(addr) {
+                addrs.insert(*id, addr.clone());
+                // reconnect to the new address
+                senders.remove(id);
+            }
+        }
+    }
+}
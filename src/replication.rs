@@ -0,0 +1,527 @@
+//! defines asynchronous primary-replica replication
+//!
+//! A replica sends `Replicate` to the primary. The primary writes a checkpoint,
+//! sends its files as `FileChunk`s and its sequence number as a heartbeat
+//! followed by `Success`, and then streams every
+//! change applied after the checkpoint as `Replicated`, with heartbeats in between
+//! while idle.
+//!
+//! The primary keeps the latest changes in a `Backlog` of at most `BACKLOG_BYTES`,
+//! which every replica is streamed from at its own pace, and a replica falling
+//! further behind is disconnected. A replica reconnects whenever its stream
+//! breaks, and resumes after the latest change applied, whose sequence number it
+//! also saves in `REPLICA_FILE` so that it resumes after a restart as well.
+//! Resuming fails once the primary no longer keeps all changes after it, e.g.
+//! after the primary restarted and was written to, and the replica must then
+//! bootstrap into an empty directory again.
+
+use crate::client::{unexpected_response, ClientOptions, KvsClient};
+use crate::engine::unix_millis;
+use crate::error::KvStoreError;
+use crate::{CommandRequest, CommandResponse, KvsEngine, ReplicationStats, Result, WatchEvent};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// interval of heartbeats sent by primary while no change is applied
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// interval between attempts of a replica to reconnect to its primary
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// file in the data directory of a replica holding the sequence number to resume
+/// after
+pub const REPLICA_FILE: &str = ".replica";
+
+/// maximum size of a `FileChunk`
+const CHUNK_SIZE: u64 = 1 << 20;
+
+/// maximum bytes of keys and values of the changes kept in a `Backlog`
+const BACKLOG_BYTES: usize = 64 << 20;
+
+/// maximum number of changes pending between the engine and its `Backlog`
+const BACKLOG_CAPACITY: usize = 4096;
+
+/// maximum number of changes taken from a `Backlog` at once
+const BATCH_LEN: usize = 256;
+
+/// number of checkpoints written for replicas, to name their directories
+static CHECKPOINT_CNT: AtomicU64 = AtomicU64::new(0);
+
+fn send(writer: &mut impl Write, response: &CommandResponse) -> Result<()> {
+    serde_cbor::to_writer(&mut *writer, response)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// send all files under `dir` with paths relative to `root`
+fn send_files(root: &Path, dir: &Path, writer: &mut impl Write) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            send_files(root, &path, writer)?;
+            continue;
+        }
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let mut file = File::open(&path)?;
+        loop {
+            let mut data = vec![];
+            (&mut file).take(CHUNK_SIZE).read_to_end(&mut data)?;
+            let len = data.len() as u64;
+            send(
+                writer,
+                &CommandResponse::FileChunk {
+                    path: name.clone(),
+                    data,
+                },
+            )?;
+            if len < CHUNK_SIZE {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// bytes of keys and values of `event`, counted against `BACKLOG_BYTES`
+fn event_size(event: &WatchEvent) -> usize {
+    let value = match event {
+        WatchEvent::Set { value, .. } => value.len(),
+        _ => 0,
+    };
+    event.namespace().len() + event.key().len() + value
+}
+
+/// Latest changes applied on a primary, which replicas are streamed from
+#[derive(Default)]
+pub(crate) struct Backlog {
+    state: Mutex<BacklogState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct BacklogState {
+    /// whether changes are received from the engine
+    running: bool,
+    /// changes after sequence number `start` in order
+    events: VecDeque<WatchEvent>,
+    bytes: usize,
+    start: u64,
+}
+
+impl BacklogState {
+    /// whether all changes after sequence number `seq` are kept
+    fn keeps(&self, seq: u64) -> bool {
+        self.running && self.start <= seq
+    }
+}
+
+impl Backlog {
+    /// start keeping changes of `kvs_engine` if not yet, and get a sequence number
+    /// at least that of every change applied so far
+    ///
+    /// `kvs_engine` must be held until the sequence number is used, so that no
+    /// change is applied meanwhile.
+    fn open(backlog: &Arc<Backlog>, kvs_engine: &mut dyn KvsEngine) -> Result<u64> {
+        let mut state = backlog.state.lock().unwrap();
+        if !state.running {
+            let receiver = kvs_engine.watch_all(BACKLOG_CAPACITY)?;
+            *state = BacklogState {
+                running: true,
+                start: kvs_engine.seq()?,
+                ..Default::default()
+            };
+            let backlog = backlog.clone();
+            thread::spawn(move || backlog.fill(receiver));
+        }
+        kvs_engine.seq()
+    }
+
+    /// keep changes from `receiver`, dropping the oldest beyond `BACKLOG_BYTES`
+    fn fill(&self, receiver: Receiver<WatchEvent>) {
+        for event in receiver {
+            let mut state = self.state.lock().unwrap();
+            state.bytes += event_size(&event);
+            state.events.push_back(event);
+            while state.bytes > BACKLOG_BYTES {
+                let event = state.events.pop_front().unwrap();
+                state.bytes -= event_size(&event);
+                state.start = event.seq();
+            }
+            self.changed.notify_all();
+        }
+        // dropped by the engine for falling behind, so changes may be missing
+        *self.state.lock().unwrap() = BacklogState::default();
+        self.changed.notify_all();
+    }
+
+    /// wait up to `timeout` for changes after sequence number `seq`
+    ///
+    /// `ReplicaBehind` is returned once some of them are no longer kept.
+    fn next(&self, seq: u64, timeout: Duration) -> Result<Vec<WatchEvent>> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |x| {
+                x.keeps(seq) && x.events.back().is_none_or(|x| x.seq() <= seq)
+            })
+            .unwrap();
+        if !state.keeps(seq) {
+            return Err(KvStoreError::ReplicaBehind { seq });
+        }
+        let first = state.events.partition_point(|x| x.seq() <= seq);
+        Ok(state
+            .events
+            .range(first..)
+            .take(BATCH_LEN)
+            .cloned()
+            .collect())
+    }
+}
+
+/// write a checkpoint of `kvs_engine` and send its files and sequence number,
+/// returning the sequence number changes are streamed after
+fn send_checkpoint(
+    kvs_engine: &Mutex<Box<dyn KvsEngine>>,
+    backlog: &Arc<Backlog>,
+    writer: &mut impl Write,
+) -> Result<u64> {
+    let dir = std::env::temp_dir().join(format!(
+        "kvs-replica-{}-{}",
+        std::process::id(),
+        CHECKPOINT_CNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    // the sequence number is taken with the engine still held, so that no change
+    // is missed
+    let seq = {
+        let mut kvs_engine = kvs_engine.lock().unwrap();
+        kvs_engine
+            .checkpoint(&dir)
+            .and_then(|_| Backlog::open(backlog, kvs_engine.as_mut()))
+    };
+    let result = seq.and_then(|seq| {
+        send_files(&dir, &dir, writer)?;
+        send(
+            writer,
+            &CommandResponse::Replicated {
+                event: None,
+                timestamp: unix_millis(),
+                seq,
+            },
+        )?;
+        Ok(seq)
+    });
+    std::fs::remove_dir_all(&dir).ok();
+    result
+}
+
+/// check that all changes after sequence number `since` of a resuming replica
+/// are kept
+fn check_resume(
+    kvs_engine: &Mutex<Box<dyn KvsEngine>>,
+    backlog: &Arc<Backlog>,
+    since: u64,
+) -> Result<u64> {
+    let seq = Backlog::open(backlog, kvs_engine.lock().unwrap().as_mut())?;
+    if since > seq || !backlog.state.lock().unwrap().keeps(since) {
+        return Err(KvStoreError::ReplicaBehind { seq: since });
+    }
+    Ok(since)
+}
+
+/// serve a replica on primary: send a checkpoint of `kvs_engine` unless the
+/// replica resumes after sequence number `since`, and then all changes after it
+/// from `backlog` until the replica goes away or falls behind
+pub(crate) fn serve_replica(
+    kvs_engine: &Mutex<Box<dyn KvsEngine>>,
+    backlog: &Arc<Backlog>,
+    since: Option<u64>,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let result = match since {
+        Some(since) => check_resume(kvs_engine, backlog, since),
+        None => send_checkpoint(kvs_engine, backlog, writer),
+    };
+    let mut seq = match result {
+        Ok(seq) => seq,
+        Err(e) => {
+            let reason = format!("{:?}", e);
+            send(writer, &CommandResponse::Error { reason })?;
+            return Err(e);
+        }
+    };
+    send(writer, &CommandResponse::Success {})?;
+
+    loop {
+        let events = match backlog.next(seq, HEARTBEAT_INTERVAL) {
+            Ok(events) => events,
+            Err(e) => {
+                let reason = format!("{:?}", e);
+                send(writer, &CommandResponse::Error { reason })?;
+                return Err(e);
+            }
+        };
+        let timestamp = unix_millis();
+        if events.is_empty() {
+            let event = None;
+            send(
+                writer,
+                &CommandResponse::Replicated {
+                    event,
+                    timestamp,
+                    seq,
+                },
+            )?;
+        }
+        for event in events {
+            seq = event.seq();
+            let event = Some(event);
+            send(
+                writer,
+                &CommandResponse::Replicated {
+                    event,
+                    timestamp,
+                    seq,
+                },
+            )?;
+        }
+    }
+}
+
+/// progress of a replica
+struct Progress {
+    primary: String,
+    connected: bool,
+    applied_seq: u64,
+    /// time on primary of the latest state applied
+    synced_at: u64,
+}
+
+/// Shared handle to the state of a replica
+#[derive(Clone)]
+pub struct ReplicaStatus {
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl ReplicaStatus {
+    pub fn stats(&self) -> ReplicationStats {
+        let progress = self.progress.lock().unwrap();
+        ReplicationStats {
+            primary: progress.primary.clone(),
+            connected: progress.connected,
+            applied_seq: progress.applied_seq,
+            lag_ms: unix_millis().saturating_sub(progress.synced_at),
+        }
+    }
+}
+
+type Responses = Box<dyn Iterator<Item = Result<CommandResponse>> + Send>;
+
+/// Stream of changes from a primary, see `Replication::bootstrap`
+pub struct Replication {
+    addr: String,
+    options: ClientOptions,
+    /// data directory of the replica, holding `REPLICA_FILE`
+    dir: PathBuf,
+    responses: Responses,
+    progress: Arc<Mutex<Progress>>,
+    /// sequence number last saved in `REPLICA_FILE`, and when
+    saved: (u64, Instant),
+}
+
+impl Replication {
+    /// copy a checkpoint of the primary at `addr`, connected to with `options`,
+    /// into empty directory `dest`
+    ///
+    /// Changes after the checkpoint are applied by `follow` to the engine
+    /// opened on `dest`. The sequence number of the checkpoint is saved in
+    /// `REPLICA_FILE`, so that the replica resumes after it from now on.
+    pub fn bootstrap(addr: &str, options: &ClientOptions, dest: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dest)?;
+        if std::fs::read_dir(dest)?.next().is_some() {
+            return Err(KvStoreError::RequestError {
+                reason: "data directory of replica must be empty".to_owned(),
+            });
+        }
+        let (responses, seq) = Self::connect(addr, options, None, dest)?;
+        Self::write_seq(dest, seq)?;
+        Ok(Self::new(addr, options, dest, responses, seq))
+    }
+
+    /// resume replicating from the primary at `addr` into `dir`, after the
+    /// sequence number saved in `REPLICA_FILE` by a previous `follow`
+    pub fn resume(addr: &str, options: &ClientOptions, dir: &Path) -> Result<Self> {
+        let seq = std::fs::read_to_string(dir.join(REPLICA_FILE))?
+            .trim()
+            .parse()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid replica file"))?;
+        let (responses, seq) = Self::connect(addr, options, Some(seq), dir)?;
+        Ok(Self::new(addr, options, dir, responses, seq))
+    }
+
+    fn new(
+        addr: &str,
+        options: &ClientOptions,
+        dir: &Path,
+        responses: Responses,
+        seq: u64,
+    ) -> Self {
+        Self {
+            addr: addr.to_owned(),
+            options: options.clone(),
+            dir: dir.to_path_buf(),
+            responses,
+            progress: Arc::new(Mutex::new(Progress {
+                primary: addr.to_owned(),
+                connected: true,
+                applied_seq: seq,
+                synced_at: unix_millis(),
+            })),
+            saved: (seq, Instant::now()),
+        }
+    }
+
+    /// request changes after sequence number `since` from the primary, or a
+    /// checkpoint first, copied into `dest`, if `None`, returning them with the
+    /// sequence number they follow
+    fn connect(
+        addr: &str,
+        options: &ClientOptions,
+        since: Option<u64>,
+        dest: &Path,
+    ) -> Result<(Responses, u64)> {
+        let mut responses = KvsClient::connect_with_options(addr, options)?
+            .stream(&CommandRequest::Replicate { since })?;
+        let mut seq = since;
+        loop {
+            match responses.next().transpose()? {
+                Some(CommandResponse::FileChunk { path, data }) if since.is_none() => {
+                    let path = Self::checkpoint_path(dest, &path)?;
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)?
+                        .write_all(&data)?;
+                }
+                Some(CommandResponse::Replicated {
+                    event: None,
+                    seq: x,
+                    ..
+                }) if since.is_none() => seq = Some(x),
+                Some(CommandResponse::Success {}) => break,
+                Some(CommandResponse::PermissionDenied {}) => {
+                    return Err(KvStoreError::PermissionDenied {})
+                }
+                Some(CommandResponse::Error { reason }) => {
+                    return Err(KvStoreError::RequestError { reason })
+                }
+                response => return Err(unexpected_response(response)),
+            }
+        }
+        let seq = seq.ok_or_else(|| KvStoreError::RequestError {
+            reason: "checkpoint without sequence number".to_owned(),
+        })?;
+        Ok((Box::new(responses), seq))
+    }
+
+    /// path of checkpoint file `name` in `dest`, which must stay within `dest`
+    fn checkpoint_path(dest: &Path, name: &str) -> Result<PathBuf> {
+        let name = Path::new(name);
+        if name
+            .components()
+            .any(|x| !matches!(x, Component::Normal(_)))
+        {
+            return Err(KvStoreError::RequestError {
+                reason: format!("invalid checkpoint file {}", name.display()),
+            });
+        }
+        Ok(dest.join(name))
+    }
+
+    pub fn status(&self) -> ReplicaStatus {
+        ReplicaStatus {
+            progress: self.progress.clone(),
+        }
+    }
+
+    /// apply changes from primary to `kvs_engine`, reconnecting whenever the
+    /// stream breaks, until the primary can't be resumed from or a change can't
+    /// be applied
+    pub fn follow(mut self, kvs_engine: &Mutex<Box<dyn KvsEngine>>) -> Result<()> {
+        loop {
+            let result = self.apply_all(kvs_engine);
+            self.progress.lock().unwrap().connected = false;
+            result?;
+            self.responses = self.reconnect()?;
+            self.progress.lock().unwrap().connected = true;
+        }
+    }
+
+    /// apply changes until the stream breaks, failing only if one can't be applied
+    fn apply_all(&mut self, kvs_engine: &Mutex<Box<dyn KvsEngine>>) -> Result<()> {
+        while let Some(Ok(CommandResponse::Replicated {
+            event,
+            timestamp,
+            seq,
+        })) = self.responses.next()
+        {
+            if let Some(event) = event {
+                kvs_engine.lock().unwrap().apply_event(event)?;
+            }
+            let mut progress = self.progress.lock().unwrap();
+            progress.applied_seq = seq;
+            progress.synced_at = timestamp;
+            drop(progress);
+            self.save(seq)?;
+        }
+        Ok(())
+    }
+
+    /// save `seq` in `REPLICA_FILE`, at most once per `HEARTBEAT_INTERVAL`
+    ///
+    /// A saved sequence number may be behind the changes applied, which are then
+    /// applied again in the same order on resume.
+    fn save(&mut self, seq: u64) -> Result<()> {
+        if seq == self.saved.0 || self.saved.1.elapsed() < HEARTBEAT_INTERVAL {
+            return Ok(());
+        }
+        Self::write_seq(&self.dir, seq)?;
+        self.saved = (seq, Instant::now());
+        Ok(())
+    }
+
+    /// write `seq` into `REPLICA_FILE` in `dir`, replacing it at once
+    fn write_seq(dir: &Path, seq: u64) -> Result<()> {
+        let path = dir.join(REPLICA_FILE);
+        let saving = path.with_extension("saving");
+        std::fs::write(&saving, seq.to_string())?;
+        std::fs::rename(saving, path)?;
+        Ok(())
+    }
+
+    /// connect to the primary again once it's reachable, resuming after the
+    /// latest change applied
+    fn reconnect(&self) -> Result<Responses> {
+        loop {
+            thread::sleep(RECONNECT_INTERVAL);
+            let since = self.progress.lock().unwrap().applied_seq;
+            match Self::connect(&self.addr, &self.options, Some(since), &self.dir) {
+                Err(KvStoreError::IOError(_)) | Err(KvStoreError::CborError(_)) => continue,
+                result => return result.map(|(responses, _)| responses),
+            }
+        }
+    }
+}
@@ -1,67 +1,674 @@
-use crate::{CommandRequest, CommandResponse, KvStoreError, KvsEngine, Result};
-use slog::{info, Logger};
-use std::io::{BufRead, BufReader, BufWriter};
-use std::net::TcpListener;
+use crate::auth::{AuthConfig, TokenConfig};
+use crate::metrics::Metrics;
+use crate::raft::RaftNode;
+use crate::replication::{self, Backlog, ReplicaStatus, Replication};
+use crate::stream::{Connection, Listener, Stream};
+use crate::tls::TlsAcceptor;
+use crate::{
+    CommandRequest, CommandResponse, KvStoreError, KvsEngine, Result, Transaction, WatchEvent,
+    DEFAULT_NAMESPACE,
+};
+use serde_bytes::ByteBuf;
+use slog::{error, info, o, Logger};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// interval between two runs of the expired keys sweeper
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// maximum number of changes buffered for a watch client before it's disconnected
+const WATCH_CAPACITY: usize = 4096;
+
+/// lossy representation of a binary key or value for logging
+fn printable(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// transaction open on a connection, with the namespace it was begun in
+type OpenTransaction = (String, Transaction);
 
 pub struct KvsServer {
-    listener: TcpListener,
-    kvs_engine: Box<dyn KvsEngine>,
+    listener: Listener,
+    kvs_engine: Arc<Mutex<Box<dyn KvsEngine>>>,
+    metrics: Arc<Metrics>,
+    /// status of replication if the server is a replica, which rejects writes
+    replica: Option<ReplicaStatus>,
+    /// changes kept for replicas of the server, see `replication`
+    backlog: Arc<Backlog>,
+    /// node of a Raft cluster serving writes and reads, see `raft`
+    raft: Option<RaftNode>,
+    /// tokens accepted if clients must authenticate, see `auth`
+    auth: Option<Arc<AuthConfig>>,
+    /// wraps client connections in TLS if set, see `tls`
+    tls: Option<TlsAcceptor>,
 }
 
 impl KvsServer {
-    pub fn new(listener: TcpListener, kvs_engine: Box<dyn KvsEngine>) -> Self {
+    /// serve `kvs_engine` to clients connecting to `listener`, a `TcpListener`,
+    /// `UnixListener` or `Listener`
+    pub fn new(listener: impl Into<Listener>, kvs_engine: Box<dyn KvsEngine>) -> Self {
         Self {
-            listener,
-            kvs_engine,
+            listener: listener.into(),
+            kvs_engine: Arc::new(Mutex::new(kvs_engine)),
+            metrics: Default::default(),
+            replica: None,
+            backlog: Default::default(),
+            raft: None,
+            auth: None,
+            tls: None,
         }
     }
+
+    /// engine of the server, e.g. to start a `RaftNode` in front of
+    pub fn engine(&self) -> Arc<Mutex<Box<dyn KvsEngine>>> {
+        self.kvs_engine.clone()
+    }
+
+    /// serve writes, reads and membership changes through `node` of a cluster
+    pub fn join_cluster(&mut self, node: RaftNode) {
+        self.raft = Some(node);
+    }
+
+    /// require clients to authenticate with a token of `config` before any request
+    pub fn require_auth(&mut self, config: AuthConfig) {
+        self.auth = Some(Arc::new(config));
+    }
+
+    /// serve clients over TLS accepted by `acceptor`
+    pub fn use_tls(&mut self, acceptor: TlsAcceptor) {
+        self.tls = Some(acceptor);
+    }
+
+    /// apply changes of `replication` in background, and reject writes from clients
+    pub fn replicate(&mut self, replication: Replication, log: &Logger) {
+        self.replica = Some(replication.status());
+        let kvs_engine = self.kvs_engine.clone();
+        let log = log.clone();
+        thread::spawn(move || {
+            if let Err(e) = replication.follow(&kvs_engine) {
+                error!(log, "replication failed"; "error" => format!("{:?}", e));
+            }
+        });
+    }
+
+    /// serve metrics over HTTP on `listener` in background
+    ///
+    /// Every request gets the metrics in Prometheus text format, regardless of path.
+    pub fn serve_metrics(&self, listener: TcpListener, log: &Logger) {
+        let kvs_engine = self.kvs_engine.clone();
+        let metrics = self.metrics.clone();
+        let replica = self.replica.clone();
+        let raft = self.raft.clone();
+        let log = log.clone();
+        thread::spawn(move || {
+            for connection in listener.incoming() {
+                let result = connection
+                    .map_err(Into::into)
+                    .and_then(|x| Self::serve_scrape(&kvs_engine, &metrics, &replica, &raft, x));
+                if let Err(e) = result {
+                    error!(log, "metrics scrape failed"; "error" => format!("{:?}", e));
+                }
+            }
+        });
+    }
+
+    /// answer one HTTP request on `connection` with current metrics
+    fn serve_scrape(
+        kvs_engine: &Mutex<Box<dyn KvsEngine>>,
+        metrics: &Metrics,
+        replica: &Option<ReplicaStatus>,
+        raft: &Option<RaftNode>,
+        connection: TcpStream,
+    ) -> Result<()> {
+        // skip request line and headers
+        let mut reader = BufReader::new(&connection);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+            line.clear();
+        }
+        let mut stats = kvs_engine.lock().unwrap().stats().ok();
+        if let Some(stats) = &mut stats {
+            stats.replication = replica.as_ref().map(ReplicaStatus::stats);
+            stats.cluster = raft.as_ref().map(RaftNode::stats);
+        }
+        let body = metrics.render(stats.as_ref());
+        let mut writer = BufWriter::new(&connection);
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+
     pub fn serve(&mut self, log: &Logger) -> Result<()> {
-        for connection in self.listener.incoming() {
-            let mut connection = connection?;
-            info!(log, "new connection"; "peer" => connection.peer_addr()?);
-            let mut reader = BufReader::new(&mut connection);
-            let mut line = String::new();
-            reader.read_line(&mut line)?;
-            drop(reader);
-            let response = match serde_json::from_str(line.as_str())? {
-                CommandRequest::Get { key } => {
-                    info!(log, "client"; "command" => "get" ,"key" => &key);
-                    match self.kvs_engine.get(key) {
-                        Ok(value) => CommandResponse::Value { value },
-                        Err(e) => CommandResponse::Error {
-                            reason: format!("{:?}", e),
-                        },
+        self.spawn_sweeper(log.clone());
+        loop {
+            let (connection, peer) = self.listener.accept()?;
+            info!(log, "new connection"; "peer" => &peer);
+            let connection = match &self.tls {
+                Some(tls) => tls.accept(connection)?,
+                None => connection,
+            };
+            let kvs_engine = self.kvs_engine.clone();
+            let metrics = self.metrics.clone();
+            let replica = self.replica.clone();
+            let backlog = self.backlog.clone();
+            let raft = self.raft.clone();
+            let auth = self.auth.clone();
+            let log = log.new(o!("peer" => peer));
+            thread::spawn(move || {
+                metrics.connection_opened();
+                let result = Self::serve_connection(
+                    kvs_engine, &metrics, replica, &backlog, raft, auth, connection, &log,
+                );
+                if let Err(e) = result {
+                    metrics.error("connection");
+                    error!(log, "connection failed"; "error" => format!("{:?}", e));
+                }
+                metrics.connection_closed();
+            });
+        }
+    }
+
+    /// serve requests on `connection` until it's closed by client
+    #[allow(clippy::too_many_arguments)]
+    fn serve_connection(
+        kvs_engine: Arc<Mutex<Box<dyn KvsEngine>>>,
+        metrics: &Metrics,
+        replica: Option<ReplicaStatus>,
+        backlog: &Arc<Backlog>,
+        raft: Option<RaftNode>,
+        auth: Option<Arc<AuthConfig>>,
+        connection: Box<dyn Stream>,
+        log: &Logger,
+    ) -> Result<()> {
+        let connection = Connection::new(connection);
+        let requests =
+            serde_cbor::Deserializer::from_reader(&connection).into_iter::<CommandRequest>();
+        let mut writer = BufWriter::new(&connection);
+        let mut transaction = None;
+        let mut principal: Option<TokenConfig> = None;
+        for request in requests {
+            // a request wrapped in the default namespace is served as the request
+            // itself, so that requests changing the connection are caught below
+            let request = match request? {
+                CommandRequest::InNamespace { namespace, request }
+                    if namespace == DEFAULT_NAMESPACE =>
+                {
+                    *request
+                }
+                request => request,
+            };
+            let command = request.name();
+            let start = Instant::now();
+            if let CommandRequest::InNamespace { request: inner, .. } = &request {
+                if let CommandRequest::InNamespace { .. }
+                | CommandRequest::Replicate { .. }
+                | CommandRequest::Auth { .. } = **inner
+                {
+                    let response = CommandResponse::Error {
+                        reason: format!("{} requests can't be in a namespace", inner.name()),
+                    };
+                    metrics.observe(command, start.elapsed(), &response);
+                    serde_cbor::to_writer(&mut writer, &response)?;
+                    writer.flush()?;
+                    continue;
+                }
+            }
+            if let CommandRequest::Auth { token } = &request {
+                let response = match auth.as_ref().map(|x| x.authenticate(token)) {
+                    Some(Some(config)) => {
+                        info!(log, "client"; "command" => "auth", "name" => &config.name);
+                        principal = Some(config.clone());
+                        CommandResponse::Success {}
                     }
+                    Some(None) => {
+                        info!(log, "client"; "command" => "auth", "error" => "unknown token");
+                        principal = None;
+                        CommandResponse::PermissionDenied {}
+                    }
+                    // any token is accepted if authentication is not required
+                    None => CommandResponse::Success {},
+                };
+                metrics.observe(command, start.elapsed(), &response);
+                serde_cbor::to_writer(&mut writer, &response)?;
+                writer.flush()?;
+                continue;
+            }
+            if auth.is_some() && !matches!(&principal, Some(x) if x.allows(&request)) {
+                info!(log, "client"; "command" => command, "error" => "permission denied");
+                let response = CommandResponse::PermissionDenied {};
+                metrics.observe(command, start.elapsed(), &response);
+                serde_cbor::to_writer(&mut writer, &response)?;
+                writer.flush()?;
+                continue;
+            }
+            let (namespace, inner) = request.namespaced();
+            if let (CommandRequest::Watch { prefix }, None) = (inner, &transaction) {
+                info!(log, "client"; "command" => "watch", "prefix" => printable(prefix), "namespace" => namespace);
+                let mut kvs_engine = kvs_engine.lock().unwrap();
+                let receiver = Self::watch(kvs_engine.as_mut(), namespace, prefix.clone());
+                drop(kvs_engine);
+                let response = match &receiver {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => CommandResponse::Error {
+                        reason: format!("{:?}", e),
+                    },
+                };
+                metrics.observe(command, start.elapsed(), &response);
+                serde_cbor::to_writer(&mut writer, &response)?;
+                writer.flush()?;
+                if let Ok(receiver) = receiver {
+                    Self::serve_watch(receiver, &mut writer);
+                }
+                return Ok(());
+            }
+            if let (CommandRequest::Replicate { since }, None) = (&request, &transaction) {
+                info!(log, "client"; "command" => "replicate", "since" => since);
+                metrics.observe(command, start.elapsed(), &CommandResponse::Success {});
+                return replication::serve_replica(&kvs_engine, backlog, *since, &mut writer);
+            }
+            let mut response = if replica.is_some() && request.is_write() {
+                CommandResponse::Error {
+                    reason: "writes are not allowed on replica".to_owned(),
                 }
-                CommandRequest::Set { key, value } => {
-                    info!(log, "client"; "command" => "set", "key" => &key, "value" => &value);
-                    match self.kvs_engine.set(key, value) {
-                        Ok(_) => CommandResponse::Success {},
-                        Err(e) => CommandResponse::Error {
-                            reason: format!("{:?}", e),
-                        },
+            } else if let Some(raft) = &raft {
+                Self::handle_in_cluster(raft, &kvs_engine, request, log)
+            } else {
+                let mut kvs_engine = kvs_engine.lock().unwrap();
+                Self::handle(kvs_engine.as_mut(), &mut transaction, request, log)
+            };
+            if let CommandResponse::Stats { stats } = &mut response {
+                stats.replication = replica.as_ref().map(ReplicaStatus::stats);
+                stats.cluster = raft.as_ref().map(RaftNode::stats);
+            }
+            metrics.observe(command, start.elapsed(), &response);
+            serde_cbor::to_writer(&mut writer, &response)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// watch keys starting with `prefix` in `namespace`
+    fn watch(
+        kvs_engine: &mut dyn KvsEngine,
+        namespace: &str,
+        prefix: Vec<u8>,
+    ) -> Result<Receiver<WatchEvent>> {
+        kvs_engine.use_namespace(namespace)?;
+        let receiver = kvs_engine.watch(prefix, WATCH_CAPACITY);
+        kvs_engine.use_namespace(DEFAULT_NAMESPACE)?;
+        receiver
+    }
+
+    /// serve writes, reads and membership changes through `raft`, and other
+    /// requests by the local engine
+    fn handle_in_cluster(
+        raft: &RaftNode,
+        kvs_engine: &Mutex<Box<dyn KvsEngine>>,
+        request: CommandRequest,
+        log: &Logger,
+    ) -> CommandResponse {
+        match request.namespaced().1 {
+            CommandRequest::Begin {} | CommandRequest::Commit {} | CommandRequest::Abort {} => {
+                return CommandResponse::Error {
+                    reason: "transactions are not supported in cluster mode".to_owned(),
+                }
+            }
+            inner @ CommandRequest::AddNode { .. } | inner @ CommandRequest::RemoveNode { .. } => {
+                info!(log, "client"; "command" => inner.name());
+                return raft.request(inner.clone());
+            }
+            _ => {}
+        }
+        match request {
+            request if request.is_write() || request.is_read() => raft.request(request),
+            request => {
+                let mut kvs_engine = kvs_engine.lock().unwrap();
+                Self::handle(kvs_engine.as_mut(), &mut None, request, log)
+            }
+        }
+    }
+
+    /// write every event from `receiver` to `writer`, until the client goes away
+    /// or falls behind, which is sent an error then
+    fn serve_watch(receiver: Receiver<WatchEvent>, writer: &mut impl Write) {
+        let mut seq = 0;
+        for event in receiver {
+            seq = event.seq();
+            let response = CommandResponse::Event { event };
+            if serde_cbor::to_writer(&mut *writer, &response).is_err() || writer.flush().is_err() {
+                return;
+            }
+        }
+        let reason = format!("{:?}", KvStoreError::WatcherBehind { seq });
+        if serde_cbor::to_writer(&mut *writer, &CommandResponse::Error { reason }).is_ok() {
+            writer.flush().ok();
+        }
+    }
+
+    /// periodically drop expired keys in background
+    fn spawn_sweeper(&self, log: Logger) {
+        let kvs_engine = self.kvs_engine.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            match kvs_engine.lock().unwrap().purge_expired() {
+                Ok(0) => {}
+                Ok(cnt) => info!(log, "expired keys purged"; "count" => cnt),
+                Err(e) => {
+                    error!(log, "failed to purge expired keys"; "error" => format!("{:?}", e))
+                }
+            }
+        });
+    }
+
+    /// serve `request` in the namespace it's wrapped in, or the default one
+    pub(crate) fn handle(
+        kvs_engine: &mut dyn KvsEngine,
+        transaction: &mut Option<OpenTransaction>,
+        request: CommandRequest,
+        log: &Logger,
+    ) -> CommandResponse {
+        let (namespace, request) = match request {
+            CommandRequest::InNamespace { namespace, request } => (namespace, *request),
+            request => {
+                return Self::handle_in_namespace(
+                    kvs_engine,
+                    transaction,
+                    DEFAULT_NAMESPACE,
+                    request,
+                    log,
+                )
+            }
+        };
+        if let Err(e) = kvs_engine.use_namespace(&namespace) {
+            return Self::error_response(e);
+        }
+        let response = Self::handle_in_namespace(kvs_engine, transaction, &namespace, request, log);
+        // the engine is shared by all connections, so it's always left in the
+        // default namespace
+        match kvs_engine.use_namespace(DEFAULT_NAMESPACE) {
+            Ok(()) => response,
+            Err(e) => Self::error_response(e),
+        }
+    }
+
+    /// serve `request` by `kvs_engine`, which is in `namespace`
+    fn handle_in_namespace(
+        kvs_engine: &mut dyn KvsEngine,
+        transaction: &mut Option<OpenTransaction>,
+        namespace: &str,
+        request: CommandRequest,
+        log: &Logger,
+    ) -> CommandResponse {
+        let request = match transaction {
+            Some((begun_in, _)) if begun_in != namespace => {
+                return CommandResponse::Error {
+                    reason: "transaction was begun in another namespace".to_owned(),
+                }
+            }
+            Some((_, transaction)) => match request {
+                CommandRequest::Get { .. }
+                | CommandRequest::Set { ttl: None, .. }
+                | CommandRequest::Remove { .. } => {
+                    return Self::handle_in_transaction(kvs_engine, transaction, request, log)
+                }
+                CommandRequest::Begin {} | CommandRequest::Commit {} | CommandRequest::Abort {} => {
+                    request
+                }
+                _ => {
+                    return CommandResponse::Error {
+                        reason: "request not allowed in transaction".to_owned(),
                     }
                 }
-                CommandRequest::Remove { key } => {
-                    info!(log, "client"; "command" => "rm", "key" => &key);
-                    match self.kvs_engine.remove(key) {
-                        Ok(_) => CommandResponse::Success {},
-                        Err(e) => {
-                            if let KvStoreError::KeyNotFound { .. } = e {
-                                CommandResponse::KeyNotFound {}
-                            } else {
-                                CommandResponse::Error {
-                                    reason: format!("{:?}", e),
-                                }
-                            }
-                        }
+            },
+            None => request,
+        };
+        match request {
+            CommandRequest::Get { key } => {
+                info!(log, "client"; "command" => "get" ,"key" => printable(&key));
+                match kvs_engine.get_versioned_bytes(key) {
+                    Ok(value) => {
+                        let (value, version) = match value {
+                            Some((value, version)) => (Some(value), Some(version)),
+                            None => (None, None),
+                        };
+                        CommandResponse::Value { value, version }
                     }
+                    Err(e) => CommandResponse::Error {
+                        reason: format!("{:?}", e),
+                    },
                 }
-            };
-            let mut writer = BufWriter::new(connection);
-            serde_json::to_writer(&mut writer, &response)?;
+            }
+            CommandRequest::Set { key, value, ttl } => {
+                info!(log, "client"; "command" => "set", "key" => printable(&key), "value" => printable(&value));
+                let result = match ttl {
+                    Some(ttl) => {
+                        kvs_engine.set_with_ttl_bytes(key, value, Duration::from_millis(ttl))
+                    }
+                    None => kvs_engine.set_bytes(key, value),
+                };
+                match result {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => CommandResponse::Error {
+                        reason: format!("{:?}", e),
+                    },
+                }
+            }
+            CommandRequest::Remove { key } => {
+                info!(log, "client"; "command" => "rm", "key" => printable(&key));
+                match kvs_engine.remove_bytes(key) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::CompareAndSwap {
+                key,
+                expected,
+                value,
+            } => {
+                info!(log, "client"; "command" => "cas", "key" => printable(&key), "value" => printable(&value));
+                Self::conditional_response(kvs_engine.set_if_bytes(key, expected, value))
+            }
+            CommandRequest::CompareVersionAndSwap {
+                key,
+                expected,
+                value,
+            } => {
+                info!(log, "client"; "command" => "cas", "key" => printable(&key), "value" => printable(&value));
+                Self::conditional_response(kvs_engine.set_if_version_bytes(key, expected, value))
+            }
+            CommandRequest::SetIfNotExists { key, value } => {
+                info!(log, "client"; "command" => "setnx", "key" => printable(&key), "value" => printable(&value));
+                Self::conditional_response(kvs_engine.set_if_not_exists_bytes(key, value))
+            }
+            CommandRequest::SetIfExists { key, value } => {
+                info!(log, "client"; "command" => "setxx", "key" => printable(&key), "value" => printable(&value));
+                Self::conditional_response(kvs_engine.set_if_exists_bytes(key, value))
+            }
+            CommandRequest::Ttl { key } => {
+                info!(log, "client"; "command" => "ttl", "key" => printable(&key));
+                match kvs_engine.ttl_bytes(key) {
+                    Ok(ttl) => CommandResponse::Ttl {
+                        ttl: ttl.map(|x| x.as_millis() as u64),
+                    },
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::Persist { key } => {
+                info!(log, "client"; "command" => "persist", "key" => printable(&key));
+                match kvs_engine.persist_bytes(key) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::MGet { keys } => {
+                info!(log, "client"; "command" => "mget", "keys" => keys.len());
+                let keys = keys.into_iter().map(ByteBuf::into_vec).collect();
+                match kvs_engine.get_many_bytes(keys) {
+                    Ok(values) => CommandResponse::Values {
+                        values: values.into_iter().map(|x| x.map(ByteBuf::from)).collect(),
+                    },
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::MSet { pairs } => {
+                info!(log, "client"; "command" => "mset", "keys" => pairs.len());
+                let pairs = pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.into_vec(), value.into_vec()))
+                    .collect();
+                match kvs_engine.set_many_bytes(pairs) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::Scan { start, end, limit } => {
+                info!(log, "client"; "command" => "scan", "start" => printable(&start));
+                match kvs_engine.scan_bytes(start, end, limit) {
+                    Ok(pairs) => CommandResponse::Pairs {
+                        pairs: pairs
+                            .into_iter()
+                            .map(|(key, value)| (ByteBuf::from(key), ByteBuf::from(value)))
+                            .collect(),
+                    },
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::Checkpoint { path } => {
+                info!(log, "client"; "command" => "checkpoint", "path" => &path);
+                match kvs_engine.checkpoint(Path::new(&path)) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            // served by `serve_connection`, as they change the connection
+            CommandRequest::Watch { .. } => CommandResponse::Error {
+                reason: "watch requests can't be served here".to_owned(),
+            },
+            CommandRequest::Replicate { .. } | CommandRequest::Auth { .. }
+                if namespace == DEFAULT_NAMESPACE =>
+            {
+                CommandResponse::Error {
+                    reason: format!("{} requests can't be served here", request.name()),
+                }
+            }
+            CommandRequest::Replicate { .. }
+            | CommandRequest::Auth { .. }
+            | CommandRequest::InNamespace { .. } => CommandResponse::Error {
+                reason: format!("{} requests can't be in a namespace", request.name()),
+            },
+            CommandRequest::DropNamespace { namespace } => {
+                info!(log, "client"; "command" => "drop_namespace", "namespace" => &namespace);
+                match kvs_engine.drop_namespace(&namespace) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::AddNode { .. } | CommandRequest::RemoveNode { .. } => {
+                CommandResponse::Error {
+                    reason: "server is not in cluster mode".to_owned(),
+                }
+            }
+            CommandRequest::Stats {} => {
+                info!(log, "client"; "command" => "stats");
+                match kvs_engine.stats() {
+                    Ok(stats) => CommandResponse::Stats { stats },
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::Begin {} => {
+                info!(log, "client"; "command" => "begin");
+                if transaction.is_some() {
+                    return CommandResponse::Error {
+                        reason: "transaction already started".to_owned(),
+                    };
+                }
+                *transaction = Some((namespace.to_owned(), Transaction::begin()));
+                CommandResponse::Success {}
+            }
+            CommandRequest::Commit {} => {
+                info!(log, "client"; "command" => "commit");
+                match transaction.take() {
+                    Some((_, transaction)) => {
+                        Self::conditional_response(transaction.commit(kvs_engine))
+                    }
+                    None => CommandResponse::Error {
+                        reason: "no transaction started".to_owned(),
+                    },
+                }
+            }
+            CommandRequest::Abort {} => {
+                info!(log, "client"; "command" => "abort");
+                match transaction.take() {
+                    Some(_) => CommandResponse::Success {},
+                    None => CommandResponse::Error {
+                        reason: "no transaction started".to_owned(),
+                    },
+                }
+            }
         }
+    }
 
-        Ok(())
+    /// handle reads and writes within an open transaction
+    fn handle_in_transaction(
+        kvs_engine: &mut dyn KvsEngine,
+        transaction: &mut Transaction,
+        request: CommandRequest,
+        log: &Logger,
+    ) -> CommandResponse {
+        match request {
+            CommandRequest::Get { key } => {
+                info!(log, "client"; "command" => "txn get", "key" => printable(&key));
+                match transaction.get_bytes(kvs_engine, key) {
+                    Ok(value) => CommandResponse::Value {
+                        value,
+                        version: None,
+                    },
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            CommandRequest::Set { key, value, .. } => {
+                info!(log, "client"; "command" => "txn set", "key" => printable(&key), "value" => printable(&value));
+                transaction.set_bytes(key, value);
+                CommandResponse::Success {}
+            }
+            CommandRequest::Remove { key } => {
+                info!(log, "client"; "command" => "txn rm", "key" => printable(&key));
+                match transaction.remove_bytes(kvs_engine, key) {
+                    Ok(_) => CommandResponse::Success {},
+                    Err(e) => Self::error_response(e),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn error_response(e: KvStoreError) -> CommandResponse {
+        if let KvStoreError::KeyNotFound { .. } = e {
+            CommandResponse::KeyNotFound {}
+        } else {
+            CommandResponse::Error {
+                reason: format!("{:?}", e),
+            }
+        }
+    }
+
+    fn conditional_response(result: Result<bool>) -> CommandResponse {
+        match result {
+            Ok(true) => CommandResponse::Success {},
+            Ok(false) => CommandResponse::ConditionFailed {},
+            Err(e) => CommandResponse::Error {
+                reason: format!("{:?}", e),
+            },
+        }
     }
 }
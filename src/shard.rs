@@ -0,0 +1,320 @@
+//! defines client-side sharding of keys over several kvs-servers
+//!
+//! Keys are mapped to shards by a consistent-hash ring, where every shard owns
+//! a number of virtual nodes proportional to its weight. Adding a shard only
+//! moves keys to the new shard, about its share of all keys, which are copied
+//! over by `ShardedClient::rebalance`.
+
+use crate::client::{unexpected_response, ClientOptions, KvsClient};
+use crate::error::KvStoreError;
+use crate::{CommandRequest, CommandResponse, Result, DEFAULT_NAMESPACE};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// default number of virtual nodes per unit of weight
+pub const DEFAULT_VNODES: u32 = 128;
+
+/// number of pairs read at a time while rebalancing
+const REBALANCE_BATCH: u64 = 1024;
+
+/// 64-bit FNV-1a finished by the mixer of splitmix64, stable across processes
+/// so that every client maps keys the same way
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Consistent-hash ring of shards, identified by their addresses
+#[derive(Clone)]
+pub struct HashRing {
+    /// virtual nodes per unit of weight
+    vnodes: u32,
+    weights: BTreeMap<String, u32>,
+    /// shard owning keys hashed up to each point
+    points: BTreeMap<u64, String>,
+}
+
+impl Default for HashRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_VNODES)
+    }
+}
+
+impl HashRing {
+    pub fn new(vnodes: u32) -> Self {
+        Self {
+            vnodes,
+            weights: BTreeMap::new(),
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// add shard `addr` with `weight` virtual nodes per unit, or change its weight
+    pub fn add(&mut self, addr: &str, weight: u32) {
+        self.remove(addr);
+        for i in 0..weight * self.vnodes {
+            self.points
+                .insert(hash(format!("{}#{}", addr, i).as_bytes()), addr.to_owned());
+        }
+        self.weights.insert(addr.to_owned(), weight);
+    }
+
+    pub fn remove(&mut self, addr: &str) {
+        if self.weights.remove(addr).is_some() {
+            self.points.retain(|_, x| x != addr);
+        }
+    }
+
+    /// address of the shard owning `key`, `None` if the ring is empty
+    pub fn shard(&self, key: &[u8]) -> Option<&str> {
+        self.points
+            .range(hash(key)..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| addr.as_str())
+    }
+
+    /// addresses of all shards with their weights
+    pub fn shards(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.weights.iter().map(|(addr, x)| (addr.as_str(), *x))
+    }
+}
+
+/// Client sending requests to the shards of a `HashRing`
+///
+/// Single-key requests go to the shard owning the key. `MGet`, `MSet` and
+/// `Scan` are split over all shards, so they aren't atomic. Requests in a
+/// namespace are routed by their keys alike. Connections are opened on first use.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<String, KvsClient>,
+    /// options of connections to every shard
+    options: ClientOptions,
+}
+
+impl ShardedClient {
+    pub fn new(ring: HashRing) -> Self {
+        Self::with_options(ring, ClientOptions::default())
+    }
+
+    pub fn with_options(ring: HashRing, options: ClientOptions) -> Self {
+        Self {
+            ring,
+            clients: HashMap::new(),
+            options,
+        }
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// send `request` in `namespace` to shard `addr` and wait for the response
+    fn request_shard(
+        &mut self,
+        addr: &str,
+        namespace: &str,
+        request: &CommandRequest,
+    ) -> Result<CommandResponse> {
+        if !self.clients.contains_key(addr) {
+            let client = KvsClient::connect_with_options(addr, &self.options)?;
+            self.clients.insert(addr.to_owned(), client);
+        }
+        let request = request.clone().in_namespace(namespace);
+        let result = self.clients.get_mut(addr).unwrap().request(&request);
+        if result.is_err() {
+            // reconnect on next request
+            self.clients.remove(addr);
+        }
+        result
+    }
+
+    fn owner(&self, key: &[u8]) -> Result<String> {
+        self.ring
+            .shard(key)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| KvStoreError::RequestError {
+                reason: "no shard".to_owned(),
+            })
+    }
+
+    /// send `request` to the shards owning its keys and wait for the response
+    pub fn request(&mut self, request: &CommandRequest) -> Result<CommandResponse> {
+        let (namespace, request) = request.namespaced();
+        if let Some(key) = request.key() {
+            let addr = self.owner(key)?;
+            return self.request_shard(&addr, namespace, request);
+        }
+        match request {
+            CommandRequest::MGet { keys } => {
+                let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+                for (idx, key) in keys.iter().enumerate() {
+                    groups.entry(self.owner(key)?).or_default().push(idx);
+                }
+                let mut values = vec![None; keys.len()];
+                for (addr, idxs) in groups {
+                    let request = CommandRequest::MGet {
+                        keys: idxs.iter().map(|x| keys[*x].clone()).collect(),
+                    };
+                    match self.request_shard(&addr, namespace, &request)? {
+                        CommandResponse::Values { values: part } if part.len() == idxs.len() => {
+                            for (idx, value) in idxs.into_iter().zip(part) {
+                                values[idx] = value;
+                            }
+                        }
+                        response @ CommandResponse::Values { .. } => {
+                            return Err(unexpected_response(Some(response)))
+                        }
+                        response => return Ok(response),
+                    }
+                }
+                Ok(CommandResponse::Values { values })
+            }
+            CommandRequest::MSet { pairs } => {
+                let mut groups: BTreeMap<String, Vec<(ByteBuf, ByteBuf)>> = BTreeMap::new();
+                for pair in pairs {
+                    groups
+                        .entry(self.owner(&pair.0)?)
+                        .or_default()
+                        .push(pair.clone());
+                }
+                for (addr, pairs) in groups {
+                    let request = CommandRequest::MSet { pairs };
+                    match self.request_shard(&addr, namespace, &request)? {
+                        CommandResponse::Success {} => {}
+                        response => return Ok(response),
+                    }
+                }
+                Ok(CommandResponse::Success {})
+            }
+            CommandRequest::Scan { limit, .. } => {
+                let addrs = self.ring.weights.keys().cloned().collect::<Vec<_>>();
+                let mut pairs = vec![];
+                for addr in addrs {
+                    match self.request_shard(&addr, namespace, request)? {
+                        CommandResponse::Pairs { pairs: part } => pairs.extend(part),
+                        response => return Ok(response),
+                    }
+                }
+                pairs.sort();
+                if let Some(limit) = limit {
+                    pairs.truncate(*limit as usize);
+                }
+                Ok(CommandResponse::Pairs { pairs })
+            }
+            request => Err(KvStoreError::RequestError {
+                reason: format!("{} requests can't be sharded", request.name()),
+            }),
+        }
+    }
+
+    /// move keys of all namespaces stored on a shard other than their owner, e.g.
+    /// after a shard is added, and return the number of keys moved
+    ///
+    /// Each key is copied to its owner unless the owner has it already, in which
+    /// case the copy there is newer, and then removed from the old shard. Keys
+    /// with ttl are copied with the remaining ttl and always overwrite.
+    pub fn rebalance(&mut self) -> Result<u64> {
+        let addrs = self.ring.weights.keys().cloned().collect::<Vec<_>>();
+        let mut namespaces = BTreeSet::new();
+        namespaces.insert(DEFAULT_NAMESPACE.to_owned());
+        for addr in addrs.iter() {
+            match self.request_shard(addr, DEFAULT_NAMESPACE, &CommandRequest::Stats {})? {
+                CommandResponse::Stats { stats } => {
+                    namespaces.extend(stats.namespaces.into_iter().map(|x| x.name))
+                }
+                response => return Err(Self::request_error(response)),
+            }
+        }
+        let mut moved = 0;
+        for namespace in namespaces.iter() {
+            for addr in addrs.iter() {
+                moved += self.rebalance_shard(addr, namespace)?;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// move keys of `namespace` on shard `addr` not owned by it
+    fn rebalance_shard(&mut self, addr: &str, namespace: &str) -> Result<u64> {
+        let mut moved = 0;
+        let mut start = vec![];
+        loop {
+            let request = CommandRequest::Scan {
+                start,
+                end: None,
+                limit: Some(REBALANCE_BATCH),
+            };
+            let pairs = match self.request_shard(addr, namespace, &request)? {
+                CommandResponse::Pairs { pairs } => pairs,
+                response => return Err(Self::request_error(response)),
+            };
+            for (key, value) in &pairs {
+                let owner = self.owner(key)?;
+                if owner != addr {
+                    self.move_key(addr, &owner, namespace, key.to_vec(), value.to_vec())?;
+                    moved += 1;
+                }
+            }
+            match pairs.last() {
+                Some((key, _)) if pairs.len() as u64 == REBALANCE_BATCH => {
+                    // smallest key after the last one
+                    start = key.to_vec();
+                    start.push(0);
+                }
+                _ => break,
+            }
+        }
+        Ok(moved)
+    }
+
+    fn move_key(
+        &mut self,
+        from: &str,
+        to: &str,
+        namespace: &str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        let request = CommandRequest::Ttl { key: key.clone() };
+        let ttl = match self.request_shard(from, namespace, &request)? {
+            CommandResponse::Ttl { ttl } => ttl,
+            // removed since scanned
+            CommandResponse::KeyNotFound {} => return Ok(()),
+            response => return Err(Self::request_error(response)),
+        };
+        let request = match ttl {
+            Some(ttl) => CommandRequest::Set {
+                key: key.clone(),
+                value,
+                ttl: Some(ttl),
+            },
+            None => CommandRequest::SetIfNotExists {
+                key: key.clone(),
+                value,
+            },
+        };
+        match self.request_shard(to, namespace, &request)? {
+            CommandResponse::Success {} | CommandResponse::ConditionFailed {} => {}
+            response => return Err(Self::request_error(response)),
+        }
+        match self.request_shard(from, namespace, &CommandRequest::Remove { key })? {
+            CommandResponse::Success {} | CommandResponse::KeyNotFound {} => Ok(()),
+            response => Err(Self::request_error(response)),
+        }
+    }
+
+    fn request_error(response: CommandResponse) -> KvStoreError {
+        match response {
+            CommandResponse::Error { reason } => KvStoreError::RequestError { reason },
+            CommandResponse::PermissionDenied {} => KvStoreError::PermissionDenied {},
+            response => unexpected_response(Some(response)),
+        }
+    }
+}
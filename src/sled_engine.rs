@@ -1,39 +1,666 @@
+use crate::engine::{create_checkpoint_dir, unix_millis};
 use crate::error::KvStoreError;
-use crate::KvsEngine;
+use crate::watch::Watchers;
 use crate::Result;
-use std::path::PathBuf;
+use crate::{KvsEngine, KvsSnapshot, NamespaceStats, Stats, WatchEvent, DEFAULT_NAMESPACE};
+use sled::{abort, TransactionError};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// first byte of the header prefixed to every value, followed by sequence number
+/// and expiry timestamp
+const HEADER_MARKER: u8 = 1;
+
+/// length of the header prefixed to every value
+const HEADER_LEN: usize = 17;
+
+/// tree holding metadata of the engine, apart from the default tree of records
+const META_TREE: &[u8] = b"__kvs_meta";
+
+/// prefix of the names of trees holding records of namespaces other than the
+/// default one, which uses the default tree
+const NAMESPACE_PREFIX: &str = "__kvs_ns:";
+
+/// key of the number added to every id generated by sled as sequence number
+const SEQ_BASE_KEY: &[u8] = b"seq_base";
+
+/// key of the format of values, missing in databases of plain values written
+/// before headers existed
+const FORMAT_KEY: &[u8] = b"format";
+
+/// value stored in sled together with its metadata
+struct Record {
+    value: Vec<u8>,
+    seq: u64,
+    expires_at: Option<u64>,
+}
+
+impl Record {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
 
 pub struct SledEngine {
     engine: sled::Db,
+    /// tree of the namespace in use, `None` until the namespace is created by a
+    /// write
+    tree: Option<sled::Tree>,
+    namespace: String,
+    seq_base: u64,
+    reads: u64,
+    writes: u64,
+    watchers: Watchers,
 }
 
 impl SledEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
         let engine = sled::open(path.into())?;
-        Ok(Self { engine })
+        let meta = engine.open_tree(META_TREE)?;
+        let seq_base = meta
+            .get(SEQ_BASE_KEY)?
+            .map_or(0, |x| u64::from_be_bytes(x.as_ref().try_into().unwrap()));
+        let store = Self {
+            tree: Some((*engine).clone()),
+            engine,
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            seq_base,
+            reads: 0,
+            writes: 0,
+            watchers: Default::default(),
+        };
+        if meta.get(FORMAT_KEY)?.is_none() {
+            store.migrate_plain_values()?;
+            meta.insert(FORMAT_KEY, &[HEADER_MARKER])?;
+            store.engine.flush()?;
+        }
+        Ok(store)
+    }
+
+    /// prefix every value of a database written before headers existed, which
+    /// only has the default tree, with a header of a new sequence number
+    fn migrate_plain_values(&self) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for item in self.engine.iter() {
+            let (key, value) = item?;
+            batch.insert(key, Self::encode_with_seq(self.next_seq()?, &value, None));
+        }
+        self.engine.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// prefix `value` with a newly generated sequence number and expiry timestamp,
+    /// 0 as expiry timestamp means the key never expires
+    fn encode(&self, value: &[u8], expires_at: Option<u64>) -> Result<Vec<u8>> {
+        Ok(Self::encode_with_seq(self.next_seq()?, value, expires_at))
+    }
+
+    fn encode_with_seq(seq: u64, value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+        let mut encoded = vec![HEADER_MARKER];
+        encoded.extend_from_slice(&seq.to_be_bytes());
+        encoded.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    fn next_seq(&self) -> Result<u64> {
+        Ok(self.seq_base + self.engine.generate_id()?)
+    }
+
+    /// tree holding records of namespace `name`, created if it doesn't exist
+    fn open_namespace(&self, name: &str) -> Result<sled::Tree> {
+        if name == DEFAULT_NAMESPACE {
+            Ok((*self.engine).clone())
+        } else {
+            Ok(self
+                .engine
+                .open_tree(format!("{}{}", NAMESPACE_PREFIX, name))?)
+        }
+    }
+
+    /// tree holding records of namespace `name`, if it exists
+    fn find_namespace(&self, name: &str) -> Result<Option<sled::Tree>> {
+        let tree_name = format!("{}{}", NAMESPACE_PREFIX, name);
+        let exists = name == DEFAULT_NAMESPACE
+            || self
+                .engine
+                .tree_names()
+                .iter()
+                .any(|x| x.as_ref() == tree_name.as_bytes());
+        exists.then(|| self.open_namespace(name)).transpose()
+    }
+
+    /// tree of the namespace in use, which is created by the first write to it
+    fn write_tree(&mut self) -> Result<sled::Tree> {
+        if self.tree.is_none() {
+            self.tree = Some(self.open_namespace(&self.namespace)?);
+        }
+        Ok(self.tree.clone().unwrap())
+    }
+
+    /// all namespaces with their trees
+    fn namespaces(&self) -> Result<Vec<(String, sled::Tree)>> {
+        let mut namespaces = vec![(DEFAULT_NAMESPACE.to_owned(), (*self.engine).clone())];
+        for name in self.engine.tree_names() {
+            if let Some(name) = std::str::from_utf8(&name)
+                .ok()
+                .and_then(|x| x.strip_prefix(NAMESPACE_PREFIX))
+            {
+                namespaces.push((name.to_owned(), self.open_namespace(name)?));
+            }
+        }
+        Ok(namespaces)
+    }
+
+    /// drop the tree of namespace `name`, using the default namespace from now on
+    /// if it's in use, returns whether the namespace existed
+    fn drop_tree(&mut self, name: &str) -> Result<bool> {
+        if self.namespace == name {
+            self.tree = Some((*self.engine).clone());
+            self.namespace = DEFAULT_NAMESPACE.to_owned();
+        }
+        Ok(self
+            .engine
+            .drop_tree(format!("{}{}", NAMESPACE_PREFIX, name).as_bytes())?)
+    }
+
+    /// notify watchers of `key` being set to `encoded`
+    fn notify_set(&mut self, key: &[u8], encoded: &[u8]) -> Result<()> {
+        if self.watchers.watching(&self.namespace, key) {
+            let record = Self::decode(encoded)?;
+            self.watchers.notify(WatchEvent::Set {
+                key: key.to_vec(),
+                value: record.value,
+                seq: record.seq,
+                expires_at: record.expires_at,
+                namespace: self.namespace.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// notify watchers of `key` being removed
+    ///
+    /// sled doesn't keep removed records, so a sequence number is only generated
+    /// for the event.
+    fn notify_remove(&mut self, key: &[u8]) -> Result<()> {
+        if self.watchers.watching(&self.namespace, key) {
+            let seq = self.next_seq()?;
+            self.watchers.notify(WatchEvent::Remove {
+                key: key.to_vec(),
+                seq,
+                namespace: self.namespace.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// split a stored record into value and metadata, failing if it has no header
+    fn decode(encoded: &[u8]) -> Result<Record> {
+        if encoded.len() < HEADER_LEN || encoded[0] != HEADER_MARKER {
+            return Err(KvStoreError::InvalidRecord {
+                reason: "value without header".to_owned(),
+            });
+        }
+        let (header, value) = encoded.split_at(HEADER_LEN);
+        let seq = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let expires_at = u64::from_be_bytes(header[9..].try_into().unwrap());
+        Ok(Record {
+            value: value.to_vec(),
+            seq,
+            expires_at: if expires_at == 0 {
+                None
+            } else {
+                Some(expires_at)
+            },
+        })
+    }
+
+    /// get the record of `key`, removing it if it has expired
+    fn live_record(&self, key: &[u8]) -> Result<Option<Record>> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return Ok(None),
+        };
+        let encoded = match tree.get(key)? {
+            Some(encoded) => encoded,
+            None => return Ok(None),
+        };
+        let record = Self::decode(&encoded)?;
+        if record.is_expired(unix_millis()) {
+            // the key may have been overwritten in the meantime
+            let _ = tree.compare_and_swap(key, Some(encoded), None as Option<&[u8]>)?;
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let encoded = self.encode(&value, expires_at)?;
+        self.writes += 1;
+        self.write_tree()?
+            .insert(key.as_slice(), encoded.as_slice())?;
+        self.engine.flush()?;
+        self.notify_set(&key, &encoded)
+    }
+
+    /// swap the value of `key` to `value` if `check` accepts the current record
+    fn swap_if(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        check: impl Fn(Option<&Record>) -> bool,
+    ) -> Result<bool> {
+        if self.tree.is_none() && !check(None) {
+            return Ok(false);
+        }
+        let tree = self.write_tree()?;
+        loop {
+            let current = tree.get(&key)?;
+            let record = current
+                .as_ref()
+                .map(|x| Self::decode(x))
+                .transpose()?
+                .filter(|x| !x.is_expired(unix_millis()));
+            if !check(record.as_ref()) {
+                return Ok(false);
+            }
+            let encoded = self.encode(&value, None)?;
+            if tree
+                .compare_and_swap(&key, current, Some(encoded.as_slice()))?
+                .is_ok()
+            {
+                self.writes += 1;
+                self.engine.flush()?;
+                self.notify_set(&key, &encoded)?;
+                return Ok(true);
+            }
+        }
     }
 }
 
 impl KvsEngine for SledEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.engine.insert(key.as_str(), value.as_str())?;
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(key, value, None)
+    }
+
+    fn get_many_bytes(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        self.reads += keys.len() as u64;
+        keys.into_iter()
+            .map(|key| Ok(self.live_record(&key)?.map(|record| record.value)))
+            .collect()
+    }
+
+    fn set_many_bytes(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        let mut watched = vec![];
+        self.writes += pairs.len() as u64;
+        for (key, value) in pairs {
+            let encoded = self.encode(&value, None)?;
+            if self.watchers.watching(&self.namespace, &key) {
+                watched.push((key.clone(), encoded.clone()));
+            }
+            batch.insert(key, encoded);
+        }
+        self.write_tree()?.apply_batch(batch)?;
         self.engine.flush()?;
+        for (key, encoded) in watched {
+            self.notify_set(&key, &encoded)?;
+        }
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.engine
-            .get(key.as_str())
-            .and_then(|x| Ok(x.map(|x| std::str::from_utf8(&*x).unwrap().to_string())))
-            .map_err(|x| x.into())
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        self.reads += 1;
+        Ok(self
+            .live_record(&key)?
+            .map(|record| (record.value, record.seq)))
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.engine.contains_key(key.as_str())? {
-            return Err(KvStoreError::KeyNotFound { key });
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.live_record(&key)?.is_none() {
+            return Err(KvStoreError::key_not_found(&key));
         }
-        self.engine.remove(key.as_str())?;
+        self.writes += 1;
+        self.write_tree()?.remove(key.as_slice())?;
         self.engine.flush()?;
+        self.notify_remove(&key)?;
         Ok(())
     }
+
+    fn set_if_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        self.swap_if(key, value, |current| {
+            current.map(|record| &record.value) == expected.as_ref()
+        })
+    }
+
+    fn set_if_version_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<u64>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        self.swap_if(key, value, |current| {
+            current.map(|record| record.seq) == expected
+        })
+    }
+
+    fn set_if_exists_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.swap_if(key, value, |current| current.is_some())
+    }
+
+    fn set_with_ttl_bytes(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = unix_millis() + ttl.as_millis() as u64;
+        self.write(key, value, Some(expires_at))
+    }
+
+    fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let record = self
+            .live_record(&key)?
+            .ok_or_else(|| KvStoreError::key_not_found(&key))?;
+        let now = unix_millis();
+        Ok(record
+            .expires_at
+            .map(|x| Duration::from_millis(x.saturating_sub(now))))
+    }
+
+    fn persist_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let record = self
+            .live_record(&key)?
+            .ok_or_else(|| KvStoreError::key_not_found(&key))?;
+        if record.expires_at.is_some() {
+            let seq = record.seq;
+            self.swap_if(key, record.value, |current| {
+                current.map(|x| x.seq) == Some(seq)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn purge_expired(&mut self) -> Result<usize> {
+        let now = unix_millis();
+        let mut cnt = 0;
+        for (_, tree) in self.namespaces()? {
+            for item in tree.iter() {
+                let (key, encoded) = item?;
+                if Self::decode(&encoded)?.is_expired(now)
+                    && tree
+                        .compare_and_swap(key, Some(encoded), None as Option<&[u8]>)?
+                        .is_ok()
+                {
+                    cnt += 1;
+                }
+            }
+        }
+        if cnt > 0 {
+            self.engine.flush()?;
+        }
+        Ok(cnt)
+    }
+    /// check versions and apply writes in a sled transaction
+    fn commit_bytes(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<bool> {
+        // sequence numbers are generated outside, as the closure may be retried
+        let writes = writes
+            .into_iter()
+            .map(|(key, value)| Ok((key, value.map(|x| self.encode(&x, None)).transpose()?)))
+            .collect::<Result<Vec<_>>>()?;
+        if self.tree.is_none() && writes.is_empty() {
+            return Ok(reads.iter().all(|(_, expected)| expected.is_none()));
+        }
+        let now = unix_millis();
+        let result = self.write_tree()?.transaction(|tx| {
+            for (key, expected) in &reads {
+                let record = match tx.get(key)?.map(|x| Self::decode(&x)).transpose() {
+                    Ok(record) => record,
+                    Err(e) => return abort(Some(e)),
+                };
+                let version = record.filter(|x| !x.is_expired(now)).map(|x| x.seq);
+                if version != *expected {
+                    return abort(None);
+                }
+            }
+            for (key, value) in &writes {
+                match value {
+                    Some(value) => tx.insert(key.as_slice(), value.as_slice())?,
+                    None => tx.remove(key.as_slice())?,
+                };
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.writes += writes.len() as u64;
+                self.engine.flush()?;
+                for (key, value) in &writes {
+                    match value {
+                        Some(encoded) => self.notify_set(key, encoded)?,
+                        None => self.notify_remove(key)?,
+                    }
+                }
+                Ok(true)
+            }
+            Err(TransactionError::Abort(None)) => Ok(false),
+            Err(TransactionError::Abort(Some(e))) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    /// copy all records of all namespaces into a new sled database at `dest`
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        let checkpoint = sled::open(dest)?;
+        for (name, tree) in self.namespaces()? {
+            let target = if name == DEFAULT_NAMESPACE {
+                (*checkpoint).clone()
+            } else {
+                checkpoint.open_tree(format!("{}{}", NAMESPACE_PREFIX, name))?
+            };
+            for item in tree.iter() {
+                let (key, encoded) = item?;
+                target.insert(key, encoded)?;
+            }
+        }
+        // id generator of sled is not copied, so sequence numbers of the checkpoint
+        // continue from a base above all existing ones
+        let seq_base = self.seq_base + self.engine.generate_id()?;
+        let meta = checkpoint.open_tree(META_TREE)?;
+        meta.insert(SEQ_BASE_KEY, &seq_base.to_be_bytes())?;
+        meta.insert(FORMAT_KEY, &[HEADER_MARKER])?;
+        checkpoint.flush()?;
+        std::fs::write(dest.join(".config"), "sled")?;
+        Ok(())
+    }
+
+    /// count live records, sled compacts on its own so there are no generations
+    fn stats(&mut self) -> Result<Stats> {
+        let now = unix_millis();
+        let mut namespaces = vec![];
+        for (name, tree) in self.namespaces()? {
+            let mut stats = NamespaceStats {
+                name,
+                keys: 0,
+                live_bytes: 0,
+            };
+            for item in tree.iter() {
+                let (key, encoded) = item?;
+                if !Self::decode(&encoded)?.is_expired(now) {
+                    stats.keys += 1;
+                    stats.live_bytes += (key.len() + encoded.len()) as u64;
+                }
+            }
+            namespaces.push(stats);
+        }
+        namespaces.sort_by(|x, y| x.name.cmp(&y.name));
+        let live_bytes = namespaces.iter().map(|x| x.live_bytes).sum();
+        Ok(Stats {
+            keys: namespaces.iter().map(|x| x.keys).sum(),
+            total_bytes: self.engine.size_on_disk()?,
+            live_bytes,
+            uncompressed_live_bytes: live_bytes,
+            reads: self.reads,
+            writes: self.writes,
+            namespaces,
+            ..Default::default()
+        })
+    }
+
+    fn watch(&mut self, prefix: Vec<u8>, capacity: usize) -> Result<Receiver<WatchEvent>> {
+        Ok(self.watchers.add(Some(&self.namespace), prefix, capacity))
+    }
+
+    fn watch_all(&mut self, capacity: usize) -> Result<Receiver<WatchEvent>> {
+        Ok(self.watchers.add(None, vec![], capacity))
+    }
+
+    /// reserve a sequence number, as sled can't tell the latest one generated
+    ///
+    /// Sequence numbers generated by sled skip ahead after a restart, so they
+    /// can't tell whether any write happened in between.
+    fn seq(&mut self) -> Result<u64> {
+        self.next_seq()
+    }
+
+    /// write or remove the record with the sequence number of `event`
+    fn apply_event(&mut self, event: WatchEvent) -> Result<()> {
+        let seq = event.seq();
+        let notify = event.clone();
+        match event {
+            WatchEvent::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+                namespace,
+            } => {
+                self.open_namespace(&namespace)?
+                    .insert(key, Self::encode_with_seq(seq, &value, expires_at))?;
+            }
+            WatchEvent::Remove { key, namespace, .. } => {
+                self.open_namespace(&namespace)?.remove(key)?;
+            }
+            WatchEvent::DropNamespace { namespace, .. } => {
+                self.drop_tree(&namespace)?;
+            }
+        }
+        // sequence numbers generated after promotion must stay above replicated ones
+        if seq >= self.seq_base {
+            self.seq_base = seq + 1;
+            self.engine
+                .open_tree(META_TREE)?
+                .insert(SEQ_BASE_KEY, &self.seq_base.to_be_bytes())?;
+        }
+        self.writes += 1;
+        self.engine.flush()?;
+        self.watchers.notify(notify);
+        Ok(())
+    }
+
+    /// switch to namespace `name`, which is only created by the first write to it
+    fn use_namespace(&mut self, name: &str) -> Result<()> {
+        self.tree = self.find_namespace(name)?;
+        self.namespace = name.to_owned();
+        Ok(())
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvStoreError::DropDefaultNamespace {});
+        }
+        if !self.drop_tree(name)? {
+            return Err(KvStoreError::NamespaceNotFound {
+                name: name.to_owned(),
+            });
+        }
+        self.writes += 1;
+        self.engine.flush()?;
+        let seq = self.next_seq()?;
+        self.watchers.notify(WatchEvent::DropNamespace {
+            namespace: name.to_owned(),
+            seq,
+        });
+        Ok(())
+    }
+
+    /// read pairs in range lazily from the tree, which is consistent as the engine
+    /// is held exclusively meanwhile
+    fn scan_bytes(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<u64>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return Ok(vec![]),
+        };
+        let range = match end {
+            Some(end) if end <= start => return Ok(vec![]),
+            Some(end) => tree.range(start..end),
+            None => tree.range(start..),
+        };
+        let now = unix_millis();
+        let mut pairs = vec![];
+        for item in range {
+            if matches!(limit, Some(limit) if pairs.len() as u64 >= limit) {
+                break;
+            }
+            let (key, encoded) = item?;
+            let record = Self::decode(&encoded)?;
+            if !record.is_expired(now) {
+                pairs.push((key.to_vec(), record.value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// copy all live records
+    ///
+    /// sled 0.31 doesn't expose read snapshots, and iterating the tree is only
+    /// consistent because the engine is held exclusively while copying.
+    fn snapshot(&mut self) -> Result<Box<dyn KvsSnapshot>> {
+        let now = unix_millis();
+        let mut records = BTreeMap::new();
+        for item in self.tree.iter().flat_map(|x| x.iter()) {
+            let (key, encoded) = item?;
+            let record = Self::decode(&encoded)?;
+            if !record.is_expired(now) {
+                records.insert(key.to_vec(), record);
+            }
+        }
+        Ok(Box::new(SledSnapshot { records }))
+    }
+}
+
+/// Read-only view of a SledEngine, see `KvsEngine::snapshot`
+pub struct SledSnapshot {
+    records: BTreeMap<Vec<u8>, Record>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .records
+            .get(&key)
+            .map(|record| (record.value.clone(), record.seq)))
+    }
+
+    fn keys_bytes(&self) -> Vec<Vec<u8>> {
+        self.records.keys().cloned().collect()
+    }
+
+    fn expires_at_bytes(&self, key: &[u8]) -> Option<u64> {
+        self.records.get(key)?.expires_at
+    }
 }
@@ -0,0 +1,189 @@
+//! defines statistics reported by engines
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// statistics of an engine, see `KvsEngine::stats`
+///
+/// Counters start from zero when the engine is opened.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Stats {
+    /// number of live keys
+    pub keys: u64,
+    /// bytes used on disk
+    pub total_bytes: u64,
+    /// bytes of records that are the latest of a live key
+    pub live_bytes: u64,
+    /// bytes of the same records if their values were not compressed
+    #[serde(default)]
+    pub uncompressed_live_bytes: u64,
+    /// generations in ascending order, empty if the engine has none
+    #[serde(default)]
+    pub generations: Vec<GenerationStats>,
+    /// blob files in ascending order of ids, see `KvStoreOptions::blob_threshold`
+    #[serde(default)]
+    pub blob_files: Vec<GenerationStats>,
+    /// number of compactions run
+    #[serde(default)]
+    pub compactions: u64,
+    /// number of compactions of blob files run
+    #[serde(default)]
+    pub blob_compactions: u64,
+    /// duration of last compaction in milliseconds
+    #[serde(default)]
+    pub last_compaction_ms: Option<u64>,
+    /// number of keys read
+    #[serde(default)]
+    pub reads: u64,
+    /// number of keys written or removed
+    #[serde(default)]
+    pub writes: u64,
+    /// namespaces in ascending order of names, including the default one
+    #[serde(default)]
+    pub namespaces: Vec<NamespaceStats>,
+    /// state of replication if the server is a replica
+    #[serde(default)]
+    pub replication: Option<ReplicationStats>,
+    /// state of the Raft node if the server is in a cluster
+    #[serde(default)]
+    pub cluster: Option<ClusterStats>,
+}
+
+/// state of a replica, see `replication`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationStats {
+    /// address of the primary
+    pub primary: String,
+    /// whether changes are still streamed from the primary
+    pub connected: bool,
+    /// sequence number of the latest change applied
+    pub applied_seq: u64,
+    /// milliseconds since the latest state of the primary known to be applied
+    ///
+    /// The primary sends a heartbeat every `HEARTBEAT_INTERVAL` while idle, so
+    /// lag of an up-to-date replica is below that interval.
+    pub lag_ms: u64,
+}
+
+/// state of a node in a Raft cluster, see `raft`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterStats {
+    pub id: u64,
+    /// one of `leader`, `candidate` and `follower`
+    pub role: String,
+    pub term: u64,
+    /// id of the leader known to the node
+    pub leader: Option<u64>,
+    /// index of the latest entry known to be stored on a majority
+    pub commit_index: u64,
+    /// index of the latest entry applied to the engine
+    pub applied_index: u64,
+    /// members of the cluster with addresses of their Raft transport
+    pub members: BTreeMap<u64, String>,
+}
+
+/// statistics of one namespace, see `KvsEngine::use_namespace`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NamespaceStats {
+    pub name: String,
+    pub keys: u64,
+    pub live_bytes: u64,
+}
+
+/// statistics of one generation or blob file of a KvStore
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenerationStats {
+    pub generation: u64,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+}
+
+impl Stats {
+    /// uncompressed bytes of live records per byte on disk, 1 if nothing is compressed
+    pub fn compression_ratio(&self) -> f64 {
+        if self.live_bytes == 0 || self.uncompressed_live_bytes == 0 {
+            1.0
+        } else {
+            self.uncompressed_live_bytes as f64 / self.live_bytes as f64
+        }
+    }
+}
+
+impl GenerationStats {
+    /// fraction of bytes not belonging to a live key, from 0 to 1
+    pub fn dead_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.0
+        } else {
+            (self.total_bytes - self.live_bytes) as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "total bytes: {}", self.total_bytes)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "compression ratio: {:.2}", self.compression_ratio())?;
+        // the default namespace alone is already covered by the totals
+        if self.namespaces.len() > 1 {
+            for ns in &self.namespaces {
+                writeln!(
+                    f,
+                    "namespace {:?}: {} keys, {} live bytes",
+                    ns.name, ns.keys, ns.live_bytes
+                )?;
+            }
+        }
+        for g in &self.generations {
+            writeln!(
+                f,
+                "generation {}: {} bytes, {:.1}% dead",
+                g.generation,
+                g.total_bytes,
+                g.dead_ratio() * 100.0
+            )?;
+        }
+        for b in &self.blob_files {
+            writeln!(
+                f,
+                "blob file {}: {} bytes, {:.1}% dead",
+                b.generation,
+                b.total_bytes,
+                b.dead_ratio() * 100.0
+            )?;
+        }
+        writeln!(f, "compactions: {}", self.compactions)?;
+        if self.blob_compactions > 0 {
+            writeln!(f, "blob compactions: {}", self.blob_compactions)?;
+        }
+        if let Some(ms) = self.last_compaction_ms {
+            writeln!(f, "last compaction: {} ms", ms)?;
+        }
+        writeln!(f, "reads: {}", self.reads)?;
+        write!(f, "writes: {}", self.writes)?;
+        if let Some(replication) = &self.replication {
+            writeln!(f)?;
+            writeln!(f, "replica of: {}", replication.primary)?;
+            writeln!(f, "connected: {}", replication.connected)?;
+            writeln!(f, "applied seq: {}", replication.applied_seq)?;
+            write!(f, "replication lag: {} ms", replication.lag_ms)?;
+        }
+        if let Some(cluster) = &self.cluster {
+            writeln!(f)?;
+            writeln!(f, "node: {} ({})", cluster.id, cluster.role)?;
+            writeln!(f, "term: {}", cluster.term)?;
+            if let Some(leader) = cluster.leader {
+                writeln!(f, "leader: {}", leader)?;
+            }
+            writeln!(f, "commit index: {}", cluster.commit_index)?;
+            write!(f, "applied index: {}", cluster.applied_index)?;
+            for (id, addr) in &cluster.members {
+                write!(f, "\nmember {}: {}", id, addr)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,246 @@
+use crate::blob::{all_blob_files, blob_path, BlobFiles};
+use crate::compression::Compression;
+use crate::encryption::Keyring;
+use crate::engine::{create_checkpoint_dir, unix_millis};
 use crate::error::KvStoreError;
-use crate::log::Command;
-use crate::{KvsEngine, Result};
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::log::{is_torn, migrate_json, BlobRef, Codec, Command};
+use crate::watch::Watchers;
+use crate::{
+    GenerationStats, KvsEngine, KvsSnapshot, NamespaceStats, Result, Stats, WatchEvent,
+    DEFAULT_NAMESPACE,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// KvStore struct stores key-value information
 pub struct KvStore {
     path: PathBuf,
+    options: KvStoreOptions,
     writer: SequentialWriter<File>,
-    keydir: HashMap<String, (u64, u64)>,
+    /// keydirs of namespaces by id, 0 being the default namespace
+    keydirs: HashMap<u32, KeyDir>,
+    /// declarations of namespaces by id, including the default namespace
+    namespaces: HashMap<u32, NamespaceEntry>,
+    /// name of the namespace in use, created on the first write to it
+    namespace: String,
+    /// id of the namespace in use, `None` until it's created
+    ns: Option<u32>,
     files: HashMap<u64, File>,
+    /// codecs of all generations, including the active one
+    codecs: HashMap<u64, Codec>,
+    /// blob files including the active one, see `blob`
+    blobs: BlobFiles,
+    /// id and writer of the active blob file, created on the first value written
+    blob_writer: Option<(u64, SequentialWriter<File>)>,
+    /// id of the next blob file
+    blob_cnt: u64,
+    /// bytes of blob files
+    blob_bytes: u64,
+    /// bytes of blob files not belonging to a live key
+    blob_garbage: u64,
     generation_cnt: u64,
+    seq: u64,
     compaction_cnt: u64,
     compaction_in_progress: bool,
+    pins: Arc<Mutex<Pins>>,
+    compactions: u64,
+    blob_compactions: u64,
+    last_compaction: Option<Duration>,
+    reads: u64,
+    writes: u64,
+    watchers: Watchers,
+}
+
+/// Options of KvStore
+#[derive(Clone)]
+pub struct KvStoreOptions {
+    /// move generations dropped by compaction into this directory instead of
+    /// deleting them, so that earlier states can be restored with `KvStore::restore`
+    pub archive_dir: Option<PathBuf>,
+    /// encrypt new generations with the active key of this keyring, which must
+    /// also hold the keys of all encrypted generations, see `encryption`
+    pub keyring: Option<Keyring>,
+    /// codec to compress values with, see `compression`
+    pub compression: Compression,
+    /// values shorter than this are never compressed
+    pub compression_threshold: usize,
+    /// store values of at least this many bytes once compressed in blob files,
+    /// see `blob`
+    pub blob_threshold: Option<usize>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            archive_dir: None,
+            keyring: None,
+            compression: Compression::None,
+            compression_threshold: 256,
+            blob_threshold: None,
+        }
+    }
+}
+
+/// point in history to restore a store to
+#[derive(Clone, Copy, Debug)]
+pub enum RestorePoint {
+    Latest,
+    /// state after the write with this sequence number
+    Seq(u64),
+    /// state as of this time in milliseconds since unix epoch
+    Time(u64),
+}
+
+impl RestorePoint {
+    fn includes(&self, seq: u64, timestamp: u64) -> bool {
+        match *self {
+            RestorePoint::Latest => true,
+            RestorePoint::Seq(until) => seq <= until,
+            RestorePoint::Time(until) => timestamp <= until,
+        }
+    }
+}
+
+/// location and version of the latest record of a key
+#[derive(Clone, Copy)]
+struct KeyDirEntry {
+    generation: u64,
+    offset: u64,
+    /// length of the record in bytes
+    len: u64,
+    /// length of the record if its value were not compressed
+    uncompressed_len: u64,
+    seq: u64,
+    expires_at: Option<u64>,
+    /// location of the value if it's in a blob file
+    blob: Option<BlobRef>,
+}
+
+impl KeyDirEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|x| x <= now)
+    }
+}
+
+type KeyDir = HashMap<Vec<u8>, KeyDirEntry>;
+
+/// length of a record of `len` bytes if its `value` compressed with `compression`,
+/// or stored in `blob`, were inline and not compressed
+fn uncompressed_len(
+    len: u64,
+    value: &[u8],
+    compression: Compression,
+    blob: Option<BlobRef>,
+) -> u64 {
+    match blob {
+        Some(blob) => len + blob.value_len,
+        None => len - value.len() as u64 + compression.uncompressed_len(value) as u64,
+    }
+}
+
+/// declaration of a namespace, see `Command::Namespace`
+struct NamespaceEntry {
+    name: String,
+    seq: u64,
+    timestamp: u64,
+}
+
+/// generations and blob files pinned by snapshots, by path
+///
+/// Compaction doesn't remove a pinned generation, but renames it to `N.obsolete`
+/// so that it won't be replayed, and likewise a blob file to `N.blob.obsolete`.
+/// The file is removed after the last snapshot pinning it is dropped.
+#[derive(Default)]
+struct Pins {
+    refs: HashMap<PathBuf, usize>,
+    obsolete: HashMap<PathBuf, PathBuf>,
+}
+
+impl Pins {
+    fn pin(&mut self, path: PathBuf) {
+        *self.refs.entry(path).or_insert(0) += 1;
+    }
+
+    fn unpin(&mut self, path: PathBuf) {
+        let refs = self.refs.entry(path.clone()).or_insert(1);
+        *refs -= 1;
+        if *refs == 0 {
+            self.refs.remove(&path);
+            if let Some(obsolete) = self.obsolete.remove(&path) {
+                std::fs::remove_file(obsolete).ok();
+            }
+        }
+    }
+
+    /// remove file `path` dropped by compaction, or rename it to `obsolete` if
+    /// it's pinned
+    fn remove_file(&mut self, path: PathBuf, obsolete: PathBuf) -> Result<()> {
+        if self.refs.contains_key(&path) {
+            std::fs::rename(&path, &obsolete)?;
+            self.obsolete.insert(path, obsolete);
+        } else {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read-only view of a KvStore, see `KvsEngine::snapshot`
+pub struct KvStoreSnapshot {
+    keydir: KeyDir,
+    files: HashMap<u64, File>,
+    codecs: HashMap<u64, Codec>,
+    blobs: BlobFiles,
+    /// paths of generations and blob files pinned
+    pinned: Vec<PathBuf>,
+    pins: Arc<Mutex<Pins>>,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let entry = match self.keydir.get(&key) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let file = self
+            .files
+            .get_mut(&entry.generation)
+            .ok_or(KvStoreError::InvalidFileHandler {})?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let value = KvStore::decode_value(
+            &self.codecs[&entry.generation],
+            &mut self.blobs,
+            BufReader::new(&mut *file),
+        )?;
+        Ok(Some((value, entry.seq)))
+    }
+
+    fn keys_bytes(&self) -> Vec<Vec<u8>> {
+        let mut keys = self.keydir.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    fn expires_at_bytes(&self, key: &[u8]) -> Option<u64> {
+        self.keydir.get(key)?.expires_at
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    /// unpin generations and blob files, and remove those already compacted
+    fn drop(&mut self) {
+        self.files.clear();
+        self.blobs = BlobFiles::default();
+        let mut pins = self.pins.lock().unwrap();
+        for path in self.pinned.drain(..) {
+            pins.unpin(path);
+        }
+    }
 }
 
 struct SequentialWriter<T: std::io::Write> {
@@ -58,10 +283,10 @@ impl<T: std::io::Write> std::io::Write for SequentialWriter<T> {
 }
 
 impl KvStore {
-    fn all_generations(path: &PathBuf) -> Result<Vec<u64>> {
+    pub(crate) fn all_generations(path: &PathBuf) -> Result<Vec<u64>> {
         let mut ids = std::fs::read_dir(&path)?
             .flat_map(|f| -> Result<_> { Ok(f?.path()) })
-            .filter(|f| f.is_file() && f.extension().map_or(false, |x| x == "db"))
+            .filter(|f| f.is_file() && f.extension().is_some_and(|x| x == "db"))
             .flat_map(|f| {
                 f.file_name()
                     .and_then(|x| x.to_str())
@@ -74,60 +299,330 @@ impl KvStore {
         Ok(ids)
     }
 
+    pub(crate) fn generation_path(path: &Path, generation: u64) -> PathBuf {
+        path.join(format!("{}.db", generation))
+    }
+
+    /// hard-link immutable file `src` to `dst`, or copy it if links are not supported
+    fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+        if std::fs::hard_link(src, dst).is_err() {
+            std::fs::copy(src, dst)?;
+        }
+        Ok(())
+    }
+
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path, KvStoreOptions::default())
+    }
+
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
         let path = path.into();
+        if let Some(archive_dir) = &options.archive_dir {
+            std::fs::create_dir_all(archive_dir)?;
+        }
         let generation_cnt: u64;
         let mut files: HashMap<u64, File> = Default::default();
-        let mut keydir: HashMap<String, (u64, u64)> = Default::default();
+        let mut codecs = HashMap::new();
+        let mut keydirs: HashMap<u32, KeyDir> = Default::default();
+        let mut namespaces = HashMap::new();
+        namespaces.insert(
+            0,
+            NamespaceEntry {
+                name: DEFAULT_NAMESPACE.to_owned(),
+                seq: 0,
+                timestamp: 0,
+            },
+        );
+        let mut seq = 0;
+        let mut blob_cnt = 0;
+        let now = unix_millis();
         if path.exists() {
+            // remove files left by snapshots alive during last compaction
+            for f in std::fs::read_dir(&path)? {
+                let f = f?.path();
+                if f.extension() == Some("obsolete".as_ref()) {
+                    std::fs::remove_file(f)?;
+                }
+            }
             let generations = Self::all_generations(&path)?;
             generation_cnt = generations.last().map_or(0, |x| *x) + 1;
             for generation in generations {
                 let mut path = path.clone();
                 path.push(format!("{}.db", generation));
+                migrate_json(&path)?;
                 let mut reader = BufReader::new(File::open(path)?);
-                let mut de =
-                    serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
+                let codec = Codec::open(&mut reader, options.keyring.as_ref())?;
+                let mut records = codec.records(&mut reader)?;
                 loop {
-                    let offset = de.byte_offset();
-                    match de.next() {
+                    let offset = records.offset();
+                    let next = records.next();
+                    let len = records.offset() - offset;
+                    match next {
                         Some(result) => match result {
                             Ok(cmd) => match cmd {
-                                Command::Set { key, .. } => {
-                                    keydir.insert(key, (generation, offset as u64));
+                                Command::Set {
+                                    key,
+                                    value,
+                                    seq: cmd_seq,
+                                    expires_at,
+                                    ns,
+                                    compression,
+                                    blob,
+                                    ..
+                                } => {
+                                    seq = seq.max(cmd_seq);
+                                    if let Some(blob) = blob {
+                                        blob_cnt = blob_cnt.max(blob.file + 1);
+                                    }
+                                    let entry = KeyDirEntry {
+                                        generation,
+                                        offset,
+                                        len,
+                                        uncompressed_len: uncompressed_len(
+                                            len,
+                                            &value,
+                                            compression,
+                                            blob,
+                                        ),
+                                        seq: cmd_seq,
+                                        expires_at,
+                                        blob,
+                                    };
+                                    let keydir = keydirs.entry(ns).or_default();
+                                    if entry.is_expired(now) {
+                                        keydir.remove(&key);
+                                    } else {
+                                        keydir.insert(key, entry);
+                                    }
+                                }
+                                Command::Remove {
+                                    key,
+                                    seq: cmd_seq,
+                                    ns,
+                                    ..
+                                } => {
+                                    seq = seq.max(cmd_seq);
+                                    if let Some(keydir) = keydirs.get_mut(&ns) {
+                                        keydir.remove(&key);
+                                    }
+                                }
+                                Command::Namespace {
+                                    ns,
+                                    name,
+                                    seq: cmd_seq,
+                                    timestamp,
+                                } => {
+                                    seq = seq.max(cmd_seq);
+                                    let entry = NamespaceEntry {
+                                        name,
+                                        seq: cmd_seq,
+                                        timestamp,
+                                    };
+                                    namespaces.insert(ns, entry);
                                 }
-                                Command::Remove { key } => {
-                                    keydir.remove(&key);
+                                Command::DropNamespace {
+                                    ns, seq: cmd_seq, ..
+                                } => {
+                                    seq = seq.max(cmd_seq);
+                                    namespaces.remove(&ns);
+                                    keydirs.remove(&ns);
                                 }
+                                Command::Seq { seq: cmd_seq } => seq = seq.max(cmd_seq),
                             },
-                            Err(_x) => break,
+                            Err(e) if is_torn(&e) => break,
+                            Err(e) => return Err(e),
                         },
                         None => break,
                     };
                 }
                 files.insert(generation, reader.into_inner());
+                codecs.insert(generation, codec);
             }
         } else {
             std::fs::create_dir_all(&path)?;
             generation_cnt = 0;
         }
 
+        // ids of blob files are never reused, see `blob`
+        let mut blobs = BlobFiles::default();
+        for id in all_blob_files(&path)? {
+            blobs.open(&path, id, options.keyring.as_ref())?;
+            blob_cnt = blob_cnt.max(id + 1);
+        }
+        if let Some(archive_dir) = &options.archive_dir {
+            if let Some(id) = all_blob_files(archive_dir)?.last() {
+                blob_cnt = blob_cnt.max(id + 1);
+            }
+        }
+
         let mut new_generation_path = path.clone();
         new_generation_path.push(format!("{}.db", generation_cnt));
         let file = std::fs::OpenOptions::new()
             .create(true)
-            .write(true)
+            .append(true)
             .read(true)
             .open(new_generation_path)?;
-        Ok(Self {
+        let mut writer = SequentialWriter::new(BufWriter::new(file), 0);
+        codecs.insert(
+            generation_cnt,
+            Codec::create(&mut writer, options.keyring.as_ref())?,
+        );
+        writer.flush()?;
+        let mut store = Self {
             path,
-            writer: SequentialWriter::new(BufWriter::new(file), 0),
-            keydir,
+            options,
+            writer,
+            keydirs,
+            namespaces,
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            ns: Some(0),
             files,
+            codecs,
+            blobs,
+            blob_writer: None,
+            blob_cnt,
+            blob_bytes: 0,
+            blob_garbage: 0,
             generation_cnt,
+            seq,
             compaction_cnt: 0,
             compaction_in_progress: false,
-        })
+            pins: Default::default(),
+            compactions: 0,
+            blob_compactions: 0,
+            last_compaction: None,
+            reads: 0,
+            writes: 0,
+            watchers: Default::default(),
+        };
+        store.count_blobs()?;
+        Ok(store)
+    }
+
+    /// rebuild the store in `path` as of `until` into empty directory `dest`
+    ///
+    /// Generations in `archive_dir` and `path` are replayed in order. Records written
+    /// before timestamps were recorded are treated as written at time 0. Values in
+    /// blob files dropped by compaction without `archive_dir` are lost, which fails
+    /// the restore if they are part of the restored state.
+    pub fn restore(
+        path: &Path,
+        archive_dir: Option<&Path>,
+        dest: &Path,
+        until: RestorePoint,
+    ) -> Result<()> {
+        let options = KvStoreOptions {
+            archive_dir: archive_dir.map(Into::into),
+            ..Default::default()
+        };
+        Self::restore_with_options(path, &options, dest, until)
+    }
+
+    /// rebuild the store in `path` as of `until` into empty directory `dest`,
+    /// reading archived generations from and encrypting with `options`
+    ///
+    /// The restored store is not archived.
+    pub fn restore_with_options(
+        path: &Path,
+        options: &KvStoreOptions,
+        dest: &Path,
+        until: RestorePoint,
+    ) -> Result<()> {
+        let keyring = options.keyring.as_ref();
+        let mut generations = BTreeMap::new();
+        let mut blobs = BlobFiles::default();
+        for dir in options.archive_dir.as_deref().into_iter().chain(Some(path)) {
+            let dir = dir.to_path_buf();
+            for generation in Self::all_generations(&dir)? {
+                generations.insert(generation, Self::generation_path(&dir, generation));
+            }
+            for id in all_blob_files(&dir)? {
+                if !blobs.contains(id) {
+                    blobs.open(&dir, id, keyring)?;
+                }
+            }
+        }
+
+        create_checkpoint_dir(dest)?;
+        let dest_options = KvStoreOptions {
+            archive_dir: None,
+            ..options.clone()
+        };
+        let mut store = Self::open_with_options(dest, dest_options)?;
+        // sequence number of the latest record applied of each key, as generations
+        // written by compaction contain copies of records already replayed
+        let mut applied = HashMap::new();
+        // keys whose latest record applied refers to a missing blob file
+        let mut missing = HashMap::new();
+        for path in generations.values() {
+            let mut reader = BufReader::new(File::open(path)?);
+            let codec = Codec::open(&mut reader, keyring)?;
+            for record in codec.records(reader)? {
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) if is_torn(&e) => break,
+                    Err(e) => return Err(e),
+                };
+                let (key, seq, timestamp) = match &record {
+                    Command::Set {
+                        key,
+                        seq,
+                        timestamp,
+                        ns,
+                        ..
+                    }
+                    | Command::Remove {
+                        key,
+                        seq,
+                        timestamp,
+                        ns,
+                    } => (Some((*ns, key.clone())), *seq, *timestamp),
+                    Command::Namespace { seq, timestamp, .. }
+                    | Command::DropNamespace { seq, timestamp, .. } => (None, *seq, *timestamp),
+                    // the restored store writes its own on compaction
+                    Command::Seq { .. } => continue,
+                };
+                if !until.includes(seq, timestamp) {
+                    continue;
+                }
+                if let Some(key) = key {
+                    if applied.get(&key) > Some(&seq) {
+                        continue;
+                    }
+                    missing.remove(&key);
+                    applied.insert(key, seq);
+                }
+                let record = match record {
+                    Command::Set {
+                        key,
+                        seq,
+                        timestamp,
+                        ns,
+                        blob: Some(blob),
+                        ..
+                    } if !blobs.contains(blob.file) => {
+                        // fine as long as a later record supersedes it
+                        missing.insert((ns, key.clone()), blob.file);
+                        Command::Remove {
+                            key,
+                            seq,
+                            timestamp,
+                            ns,
+                        }
+                    }
+                    record => blobs.inline(record)?,
+                };
+                store.seq = store.seq.max(seq);
+                store.append(record)?;
+            }
+        }
+        if let Some(file) = missing.values().next() {
+            return Err(KvStoreError::BlobFileNotFound { file: *file });
+        }
+        store.writer.flush()?;
+        store.compaction()?;
+        std::fs::write(dest.join(".config"), "kvs")?;
+        Ok(())
     }
 
     fn get_file(&mut self, fileno: u64) -> Result<&mut File> {
@@ -141,13 +636,391 @@ impl KvStore {
         }
     }
 
-    /// try compact log
+    /// keydir of the namespace in use, if it exists
+    fn keydir(&self) -> Option<&KeyDir> {
+        self.keydirs.get(&self.ns?)
+    }
+
+    /// get keydir entry of `key` in the namespace in use, dropping it if it has expired
+    fn live_entry(&mut self, key: &[u8]) -> Option<KeyDirEntry> {
+        self.live_entry_in(self.ns?, key)
+    }
+
+    /// id of the namespace in use, which is created by the first write to it
+    fn write_ns(&mut self) -> Result<u32> {
+        match self.ns {
+            Some(ns) => Ok(ns),
+            None => self.get_or_create_namespace(&self.namespace.clone()),
+        }
+    }
+
+    /// get keydir entry of `key` in namespace `ns`, dropping it if it has expired
+    fn live_entry_in(&mut self, ns: u32, key: &[u8]) -> Option<KeyDirEntry> {
+        let keydir = self.keydirs.get_mut(&ns)?;
+        let entry = *keydir.get(key)?;
+        if entry.is_expired(unix_millis()) {
+            keydir.remove(key);
+            self.drop_blob(Some(entry), None);
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// id of namespace `name`, if it exists
+    fn namespace_id(&self, name: &str) -> Option<u32> {
+        self.namespaces
+            .iter()
+            .find(|(_, x)| x.name == name)
+            .map(|(ns, _)| *ns)
+    }
+
+    /// read the record `entry` points to
+    fn read_record(&mut self, entry: KeyDirEntry) -> Result<Command> {
+        let mut file = self.get_file(entry.generation)?.try_clone()?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        self.codecs[&entry.generation].decode(BufReader::new(file))
+    }
+
+    /// read value of the record `entry` points to
+    fn read_value(&mut self, entry: KeyDirEntry) -> Result<Vec<u8>> {
+        let mut file = self.get_file(entry.generation)?.try_clone()?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        Self::decode_value(
+            &self.codecs[&entry.generation],
+            &mut self.blobs,
+            BufReader::new(file),
+        )
+    }
+
+    /// decode value of the `Set` record at the position of `reader`, reading it
+    /// from `blobs` if it's in a blob file
+    fn decode_value(codec: &Codec, blobs: &mut BlobFiles, reader: impl Read) -> Result<Vec<u8>> {
+        blobs.value(codec.decode(reader)?)
+    }
+
+    /// write `record` into current generation and update keydir
+    ///
+    /// The value of a `Set` record is moved into the active blob file first if
+    /// it's long enough, see `KvStoreOptions::blob_threshold`.
+    fn append(&mut self, record: Command) -> Result<()> {
+        let record = self.spill(record)?;
+        let offset = self.writer.bytes_written();
+        self.codecs[&self.generation_cnt].encode(&record, &mut self.writer)?;
+        match record {
+            Command::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+                ns,
+                compression,
+                blob,
+                ..
+            } => {
+                let len = self.writer.bytes_written() - offset;
+                let entry = KeyDirEntry {
+                    generation: self.generation_cnt,
+                    offset,
+                    len,
+                    uncompressed_len: uncompressed_len(len, &value, compression, blob),
+                    seq,
+                    expires_at,
+                    blob,
+                };
+                let old = self.keydirs.entry(ns).or_default().insert(key, entry);
+                self.drop_blob(old, blob);
+            }
+            Command::Remove { key, ns, .. } => {
+                let old = self.keydirs.get_mut(&ns).and_then(|x| x.remove(&key));
+                self.drop_blob(old, None);
+            }
+            Command::Namespace {
+                ns,
+                name,
+                seq,
+                timestamp,
+            } => {
+                if name == self.namespace {
+                    self.ns = Some(ns);
+                }
+                let entry = NamespaceEntry {
+                    name,
+                    seq,
+                    timestamp,
+                };
+                self.namespaces.insert(ns, entry);
+            }
+            Command::DropNamespace { ns, .. } => {
+                if self.ns == Some(ns) {
+                    self.namespace = DEFAULT_NAMESPACE.to_owned();
+                    self.ns = Some(0);
+                }
+                self.namespaces.remove(&ns);
+                for entry in self.keydirs.remove(&ns).unwrap_or_default().into_values() {
+                    self.drop_blob(Some(entry), None);
+                }
+            }
+            Command::Seq { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// move the value of a `Set` record into the active blob file if it's at least
+    /// `blob_threshold` bytes
+    fn spill(&mut self, record: Command) -> Result<Command> {
+        match record {
+            Command::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+                timestamp,
+                ns,
+                compression,
+                blob: None,
+            } if self
+                .options
+                .blob_threshold
+                .is_some_and(|x| value.len() >= x) =>
+            {
+                let value_len = compression.uncompressed_len(&value) as u64;
+                let blob = self.write_blob(&value, value_len)?;
+                Ok(Command::Set {
+                    key,
+                    value: vec![],
+                    seq,
+                    expires_at,
+                    timestamp,
+                    ns,
+                    compression,
+                    blob: Some(blob),
+                })
+            }
+            record => Ok(record),
+        }
+    }
+
+    /// append `value` to the active blob file, creating one if there is none
+    ///
+    /// The blob file is flushed right away, so that the value is on disk before
+    /// any record referring to it.
+    fn write_blob(&mut self, value: &[u8], value_len: u64) -> Result<BlobRef> {
+        if self.blob_writer.is_none() {
+            let path = blob_path(&self.path, self.blob_cnt);
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            let mut writer = SequentialWriter::new(BufWriter::new(file), 0);
+            let codec = Codec::create(&mut writer, self.options.keyring.as_ref())?;
+            writer.flush()?;
+            self.blob_bytes += writer.bytes_written();
+            self.blobs.insert(self.blob_cnt, File::open(&path)?, codec);
+            self.blob_writer = Some((self.blob_cnt, writer));
+            self.blob_cnt += 1;
+        }
+        let (file, writer) = self.blob_writer.as_mut().unwrap();
+        let offset = writer.bytes_written();
+        self.blobs.codec(*file).encode_blob(value, &mut *writer)?;
+        writer.flush()?;
+        let len = writer.bytes_written() - offset;
+        self.blob_bytes += len;
+        Ok(BlobRef {
+            file: *file,
+            offset,
+            len,
+            value_len,
+        })
+    }
+
+    /// count the value `old` refers to as garbage if it's in a blob file, unless
+    /// the new entry refers to the same `blob`, as when compaction copies records
+    fn drop_blob(&mut self, old: Option<KeyDirEntry>, blob: Option<BlobRef>) {
+        if let Some(old) = old.and_then(|x| x.blob) {
+            if Some(old) != blob {
+                self.blob_garbage += old.len;
+            }
+        }
+    }
+
+    /// bytes of live values by blob file
+    fn blob_live_bytes(&self) -> HashMap<u64, u64> {
+        let now = unix_millis();
+        let mut live = HashMap::new();
+        let entries = self.keydirs.values().flat_map(|x| x.values());
+        for entry in entries.filter(|x| !x.is_expired(now)) {
+            if let Some(blob) = entry.blob {
+                *live.entry(blob.file).or_insert(0) += blob.len;
+            }
+        }
+        live
+    }
+
+    /// recount bytes of blob files and of those no keydir entry refers to
+    ///
+    /// Values of expired keys still in keydirs are counted as garbage once their
+    /// entries are dropped, see `drop_blob`.
+    fn count_blobs(&mut self) -> Result<()> {
+        self.blob_bytes = self.blobs.sizes()?.values().sum();
+        let referenced: u64 = self
+            .keydirs
+            .values()
+            .flat_map(|x| x.values())
+            .filter_map(|x| x.blob)
+            .map(|x| x.len)
+            .sum();
+        self.blob_garbage = self.blob_bytes.saturating_sub(referenced);
+        Ok(())
+    }
+
+    /// write a `Set` record of namespace `ns` with `seq` into current generation
+    /// and update keydir
+    fn append_set(
+        &mut self,
+        ns: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        seq: u64,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        self.writes += 1;
+        let namespace = &self.namespaces[&ns].name;
+        if self.watchers.watching(namespace, &key) {
+            self.watchers.notify(WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+                seq,
+                expires_at,
+                namespace: namespace.clone(),
+            });
+        }
+        let (value, compression) = self.compress(value);
+        self.append(Command::Set {
+            key,
+            value,
+            seq,
+            expires_at,
+            timestamp: unix_millis(),
+            ns,
+            compression,
+            blob: None,
+        })
+    }
+
+    /// compress `value` as configured, if it's long enough and gets smaller
+    fn compress(&self, value: Vec<u8>) -> (Vec<u8>, Compression) {
+        let compression = self.options.compression;
+        if value.len() < self.options.compression_threshold {
+            return (value, Compression::None);
+        }
+        match compression.compress(&value) {
+            Some(compressed) => (compressed, compression),
+            None => (value, Compression::None),
+        }
+    }
+
+    /// write a `Remove` record of namespace `ns` with `seq` into current generation
+    /// and update keydir
+    fn append_remove(&mut self, ns: u32, key: Vec<u8>, seq: u64) -> Result<()> {
+        self.writes += 1;
+        let namespace = &self.namespaces[&ns].name;
+        if self.watchers.watching(namespace, &key) {
+            self.watchers.notify(WatchEvent::Remove {
+                key: key.clone(),
+                seq,
+                namespace: namespace.clone(),
+            });
+        }
+        self.append(Command::Remove {
+            key,
+            seq,
+            timestamp: unix_millis(),
+            ns,
+        })
+    }
+
+    /// write a `DropNamespace` record of namespace `ns` with `seq` and drop its keydir
+    fn append_drop_namespace(&mut self, ns: u32, seq: u64) -> Result<()> {
+        self.writes += 1;
+        let namespace = self.namespaces[&ns].name.clone();
+        self.append(Command::DropNamespace {
+            ns,
+            seq,
+            timestamp: unix_millis(),
+        })?;
+        self.watchers
+            .notify(WatchEvent::DropNamespace { namespace, seq });
+        Ok(())
+    }
+
+    /// id of namespace `name`, declaring it with a new id if it doesn't exist
+    fn get_or_create_namespace(&mut self, name: &str) -> Result<u32> {
+        if let Some(ns) = self.namespace_id(name) {
+            return Ok(ns);
+        }
+        // ids of dropped namespaces may be reused, as their records are dropped
+        // on replay before the new declaration
+        let ns = self.namespaces.keys().max().map_or(0, |x| x + 1);
+        self.seq += 1;
+        self.append(Command::Namespace {
+            ns,
+            name: name.to_owned(),
+            seq: self.seq,
+            timestamp: unix_millis(),
+        })?;
+        self.writer.flush()?;
+        Ok(ns)
+    }
+
+    /// set `key` to `value` with a new sequence number
+    fn write(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let ns = self.write_ns()?;
+        let do_compaction = self.keydir().is_some_and(|x| x.contains_key(&key));
+        self.seq += 1;
+        self.append_set(ns, key, value, self.seq, expires_at)?;
+
+        if do_compaction {
+            self.try_compaction()?
+        };
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    /// seal active generation, and write to a new generation from now on
+    fn seal(&mut self) -> Result<()> {
+        let mut new_generation_path = self.path.clone();
+        self.generation_cnt += 1;
+        new_generation_path.push(format!("{}.db", self.generation_cnt));
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(new_generation_path)?;
+
+        let mut new_writer = SequentialWriter::new(BufWriter::new(file), 0);
+        let codec = Codec::create(&mut new_writer, self.options.keyring.as_ref())?;
+        self.codecs.insert(self.generation_cnt, codec);
+        let previous_writer = std::mem::replace(&mut self.writer, new_writer);
+        let file = previous_writer
+            .into_inner()
+            .into_inner()
+            .map_err(|_| KvStoreError::IntoInner {})?;
+        self.files.insert(self.generation_cnt - 1, file);
+        Ok(())
+    }
+
+    /// try compact log, and blob files once more than half of their bytes are garbage
     fn try_compaction(&mut self) -> Result<()> {
         self.compaction_cnt += 1;
         if self.compaction_cnt >= 5000 {
             self.compaction_cnt = 0;
             self.compaction()?;
         }
+        if self.blob_garbage * 2 > self.blob_bytes {
+            self.blob_compaction()?;
+        }
         Ok(())
     }
 
@@ -159,6 +1032,7 @@ impl KvStore {
             return Ok(());
         }
         self.compaction_in_progress = true;
+        let start = Instant::now();
 
         // phase 1: write all logs into next generation
         // phase 2: remove all files before current generation
@@ -167,79 +1041,155 @@ impl KvStore {
         // cache all generations
         let generations = Self::all_generations(&self.path)?;
 
-        // open new generation
-        let mut new_generation_path = self.path.clone();
-        self.generation_cnt += 1;
-        new_generation_path.push(format!("{}.db", self.generation_cnt));
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(new_generation_path)?;
+        self.seal()?;
 
-        let new_writer = SequentialWriter::new(BufWriter::new(file), 0);
-        let previous_writer = std::mem::replace(&mut self.writer, new_writer);
-        let file = previous_writer
-            .into_inner()
-            .into_inner()
-            .map_err(|_| KvStoreError::IntoInner {})?;
-        self.files.insert(self.generation_cnt - 1, file);
+        // removes and expired records are dropped, so sequence numbers would
+        // otherwise go back on replay
+        self.append(Command::Seq { seq: self.seq })?;
+
+        // declare namespaces before any of their records
+        let declarations = self
+            .namespaces
+            .iter()
+            .filter(|(ns, _)| **ns != 0)
+            .map(|(ns, x)| Command::Namespace {
+                ns: *ns,
+                name: x.name.clone(),
+                seq: x.seq,
+                timestamp: x.timestamp,
+            })
+            .collect::<Vec<_>>();
+        for record in declarations {
+            self.append(record)?;
+        }
 
         // get all keys
-        let keys: Vec<String> = self.keydir.keys().cloned().collect();
+        let keys: Vec<(u32, Vec<u8>)> = self
+            .keydirs
+            .iter()
+            .flat_map(|(ns, keydir)| keydir.keys().map(move |key| (*ns, key.clone())))
+            .collect();
 
-        // write to new log
-        for key in keys.into_iter() {
-            let value = self
-                .get(key.clone())?
-                .ok_or(KvStoreError::KeyNotFound { key: key.clone() })?;
-            self.set(key, value)?;
+        // write to new log, keeping every record as is and dropping expired ones
+        for (ns, key) in keys.into_iter() {
+            if let Some(entry) = self.live_entry_in(ns, &key) {
+                let record = self.read_record(entry)?;
+                self.append(record)?;
+            }
         }
+        self.writer.flush()?;
 
-        // remove all files before current generation
+        // remove all files before current generation, unless pinned by snapshots
+        let mut pins = self.pins.lock().unwrap();
         for g_cnt in generations {
             self.files.remove(&g_cnt);
-            let mut path = self.path.clone();
-            path.push(format!("{}.db", g_cnt));
-            std::fs::remove_file(path)?;
+            self.codecs.remove(&g_cnt);
+            let path = Self::generation_path(&self.path, g_cnt);
+            if let Some(archive_dir) = &self.options.archive_dir {
+                Self::link_or_copy(&path, &Self::generation_path(archive_dir, g_cnt))?;
+            }
+            let obsolete = path.with_extension("obsolete");
+            pins.remove_file(path, obsolete)?;
         }
+        drop(pins);
         self.compaction_in_progress = false;
+        self.compactions += 1;
+        self.last_compaction = Some(start.elapsed());
+
+        // blob files aren't rewritten above, so reseal those of older keys
+        if self.blobs.sizes()?.keys().any(|id| self.blob_is_stale(*id)) {
+            self.blob_compaction()?;
+        }
 
         Ok(())
     }
+
+    /// whether blob file `id` is sealed with another key than the active one
+    fn blob_is_stale(&self, id: u64) -> bool {
+        let active = self
+            .options
+            .keyring
+            .as_ref()
+            .map(|x| x.active().id.as_str());
+        self.blobs.codec(id).key_id() != active
+    }
+
+    /// compact blob files more than half of whose bytes are garbage, or which
+    /// are sealed with another key than the active one
+    ///
+    /// Live values in these files are copied into a new blob file, each with a
+    /// new `Set` record of the same sequence number, which supersedes the record
+    /// referring to the old copy on replay.
+    fn blob_compaction(&mut self) -> Result<()> {
+        let live = self.blob_live_bytes();
+        let files = self
+            .blobs
+            .sizes()?
+            .into_iter()
+            .filter(|(id, total)| {
+                live.get(id).cloned().unwrap_or(0) * 2 < *total || self.blob_is_stale(*id)
+            })
+            .map(|(id, _)| id)
+            .collect::<HashSet<_>>();
+        // live values are copied into the active blob file, unless it's compacted
+        if matches!(&self.blob_writer, Some((id, _)) if files.contains(id)) {
+            self.blob_writer = None;
+        }
+
+        let now = unix_millis();
+        let entries = self
+            .keydirs
+            .values()
+            .flat_map(|x| x.values())
+            .filter(|x| !x.is_expired(now))
+            .filter(|x| x.blob.is_some_and(|blob| files.contains(&blob.file)))
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in entries {
+            let record = self.read_record(entry)?;
+            let record = self.blobs.inline(record)?;
+            self.append(record)?;
+        }
+        self.writer.flush()?;
+
+        let mut pins = self.pins.lock().unwrap();
+        for id in files {
+            self.blobs.remove(id);
+            let path = blob_path(&self.path, id);
+            if let Some(archive_dir) = &self.options.archive_dir {
+                Self::link_or_copy(&path, &blob_path(archive_dir, id))?;
+            }
+            let obsolete = path.with_extension("blob.obsolete");
+            pins.remove_file(path, obsolete)?;
+        }
+        drop(pins);
+        self.blob_compactions += 1;
+        self.count_blobs()
+    }
 }
 
 impl KvsEngine for KvStore {
-    /// get `value` of the corresponding `key`
+    /// get `value` of the corresponding `key` together with its sequence number
     ///
     /// If the `key` hasn't been stored in memory, `None` will be returned
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if !self.keydir.contains_key(&key) {
-            return Ok(None);
-        }
-        let (fileno, offset) = self.keydir.get(&key).unwrap();
-        let fileno = *fileno;
-        let offset = *offset;
-        let mut file = self.get_file(fileno)?.try_clone()?;
-        file.seek(SeekFrom::Start(offset))?;
-        let cmd = Command::deserialize(&mut serde_json::Deserializer::from_reader(
-            BufReader::new(file),
-        ))?;
-        match cmd {
-            Command::Set { value, .. } => Ok(Some(value)),
-            Command::Remove { .. } => panic!("invalid record"),
+    fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        self.reads += 1;
+        match self.live_entry(&key) {
+            Some(entry) => Ok(Some((self.read_value(entry)?, entry.seq))),
+            None => Ok(None),
         }
     }
 
     /// remove key-value pair with `key`
     ///
-    /// If the key doesn't exist in memory, this function will panic
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.keydir.contains_key(&key) {
-            return Err(KvStoreError::KeyNotFound { key });
+    /// If the key doesn't exist in memory, `KeyNotFound` will be returned
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.live_entry(&key).is_none() {
+            return Err(KvStoreError::key_not_found(&key));
         }
-        self.keydir.remove(&key);
-        serde_json::to_writer(&mut self.writer, &Command::Remove { key })?;
+        let ns = self.write_ns()?;
+        self.seq += 1;
+        self.append_remove(ns, key, self.seq)?;
 
         self.try_compaction()?;
 
@@ -247,22 +1197,415 @@ impl KvsEngine for KvStore {
 
         Ok(())
     }
+
     /// set the corresponding `key` to `value`
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let offset = self.writer.bytes_written();
-        let do_compaction = self.keydir.contains_key(&key);
-        self.keydir
-            .insert(key.clone(), (self.generation_cnt, offset));
-        serde_json::to_writer(&mut self.writer, &Command::Set { key, value })?;
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(key, value, None)
+    }
 
-        if do_compaction {
-            self.try_compaction()?
+    /// get values of `keys`
+    ///
+    /// Records are read in the order of their position in generations,
+    /// so that every generation is scanned forward at most once.
+    fn get_many_bytes(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        self.reads += keys.len() as u64;
+        let mut entries = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, key)| self.live_entry(key).map(|entry| (idx, entry)))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|(_, entry)| (entry.generation, entry.offset));
+
+        let mut values = vec![None; keys.len()];
+        let mut reader: Option<(u64, BufReader<File>)> = None;
+        for (idx, entry) in entries {
+            match reader {
+                Some((generation, _)) if generation == entry.generation => {}
+                _ => {
+                    let file = self.get_file(entry.generation)?.try_clone()?;
+                    reader = Some((entry.generation, BufReader::new(file)));
+                }
+            }
+            let (_, reader) = reader.as_mut().unwrap();
+            let position = reader.stream_position()?;
+            reader.seek_relative(entry.offset as i64 - position as i64)?;
+            values[idx] = Some(Self::decode_value(
+                &self.codecs[&entry.generation],
+                &mut self.blobs,
+                &mut *reader,
+            )?);
+        }
+        Ok(values)
+    }
+
+    /// set all `pairs`, flushing the log only once
+    fn set_many_bytes(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let ns = self.write_ns()?;
+        for (key, value) in pairs {
+            let do_compaction = self.keydir().is_some_and(|x| x.contains_key(&key));
+            self.seq += 1;
+            self.append_set(ns, key, value, self.seq, None)?;
+            if do_compaction {
+                self.try_compaction()?;
+            }
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// set `key` to `value` if the current value equals `expected`
+    fn set_if_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        let current = match expected {
+            Some(_) => self.get_bytes(key.clone())?,
+            None if self.live_entry(&key).is_some() => return Ok(false),
+            None => None,
         };
+        if current != expected {
+            return Ok(false);
+        }
+        self.set_bytes(key, value)?;
+        Ok(true)
+    }
+
+    /// set `key` to `value` if the sequence number in keydir equals `expected`
+    fn set_if_version_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<u64>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        if self.live_entry(&key).map(|entry| entry.seq) != expected {
+            return Ok(false);
+        }
+        self.set_bytes(key, value)?;
+        Ok(true)
+    }
+
+    /// set `key` to `value` if `key` is in keydir
+    fn set_if_exists_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        if self.live_entry(&key).is_none() {
+            return Ok(false);
+        }
+        self.set_bytes(key, value)?;
+        Ok(true)
+    }
+
+    /// set the corresponding `key` to `value`, which expires after `ttl`
+    fn set_with_ttl_bytes(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = unix_millis() + ttl.as_millis() as u64;
+        self.write(key, value, Some(expires_at))
+    }
+
+    /// get remaining time to live of `key` from keydir
+    fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let entry = self
+            .live_entry(&key)
+            .ok_or_else(|| KvStoreError::key_not_found(&key))?;
+        let now = unix_millis();
+        Ok(entry
+            .expires_at
+            .map(|x| Duration::from_millis(x.saturating_sub(now))))
+    }
+
+    /// rewrite `key` without expiry
+    fn persist_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let entry = self
+            .live_entry(&key)
+            .ok_or_else(|| KvStoreError::key_not_found(&key))?;
+        if entry.expires_at.is_some() {
+            let value = self.read_value(entry)?;
+            self.write(key, value, None)?;
+        }
+        Ok(())
+    }
+
+    /// drop expired keys of all namespaces from keydirs
+    ///
+    /// Their records are dropped on replay and compaction, so nothing is written to log.
+    fn purge_expired(&mut self) -> Result<usize> {
+        let now = unix_millis();
+        let mut cnt = 0;
+        let mut blob_garbage = 0;
+        for keydir in self.keydirs.values_mut() {
+            let len = keydir.len();
+            keydir.retain(|_, entry| {
+                if !entry.is_expired(now) {
+                    return true;
+                }
+                blob_garbage += entry.blob.map_or(0, |x| x.len);
+                false
+            });
+            cnt += len - keydir.len();
+        }
+        // blob files are compacted by the next write once enough is garbage
+        self.blob_garbage += blob_garbage;
+        Ok(cnt)
+    }
+
+    /// check versions of `reads` and append all `writes` in one flush
+    ///
+    /// Records of a commit are written one by one, so a crash during commit may
+    /// leave only part of the writes on disk.
+    fn commit_bytes(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<bool> {
+        for (key, expected) in reads {
+            if self.live_entry(&key).map(|x| x.seq) != expected {
+                return Ok(false);
+            }
+        }
+        if writes.is_empty() {
+            return Ok(true);
+        }
+        let ns = self.write_ns()?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    let do_compaction = self.keydir().is_some_and(|x| x.contains_key(&key));
+                    self.seq += 1;
+                    self.append_set(ns, key, value, self.seq, None)?;
+                    if do_compaction {
+                        self.try_compaction()?;
+                    }
+                }
+                None => {
+                    if self.live_entry(&key).is_some() {
+                        self.seq += 1;
+                        self.append_remove(ns, key, self.seq)?;
+                        self.try_compaction()?;
+                    }
+                }
+            }
+        }
+        self.writer.flush()?;
+        Ok(true)
+    }
+
+    /// seal active generation and blob file, and link all of them into `dest`
+    ///
+    /// Generations and blob files are immutable once sealed, so they are
+    /// hard-linked if possible, and copied otherwise.
+    fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        create_checkpoint_dir(dest)?;
+        self.seal()?;
+        self.blob_writer = None;
+        for id in all_blob_files(&self.path)? {
+            Self::link_or_copy(&blob_path(&self.path, id), &blob_path(dest, id))?;
+        }
+        for generation in Self::all_generations(&self.path)? {
+            if generation == self.generation_cnt {
+                continue;
+            }
+            Self::link_or_copy(
+                &Self::generation_path(&self.path, generation),
+                &Self::generation_path(dest, generation),
+            )?;
+        }
+        std::fs::write(dest.join(".config"), "kvs")?;
+        Ok(())
+    }
 
+    /// sum up sizes of generations and blob files, and of live records in keydirs
+    fn stats(&mut self) -> Result<Stats> {
         self.writer.flush()?;
+        let now = unix_millis();
+        let mut live_bytes = HashMap::new();
+        let mut uncompressed_live_bytes = 0;
+        let mut keys = 0;
+        let mut namespaces = vec![];
+        for (ns, namespace) in &self.namespaces {
+            let mut stats = NamespaceStats {
+                name: namespace.name.clone(),
+                keys: 0,
+                live_bytes: 0,
+            };
+            let entries = self.keydirs.get(ns).into_iter().flat_map(|x| x.values());
+            for entry in entries.filter(|x| !x.is_expired(now)) {
+                stats.keys += 1;
+                stats.live_bytes += entry.len + entry.blob.map_or(0, |x| x.len);
+                uncompressed_live_bytes += entry.uncompressed_len;
+                *live_bytes.entry(entry.generation).or_insert(0) += entry.len;
+            }
+            keys += stats.keys;
+            namespaces.push(stats);
+        }
+        namespaces.sort_by(|x, y| x.name.cmp(&y.name));
+        let mut generations = self
+            .files
+            .iter()
+            .map(|(generation, file)| Ok((*generation, file.metadata()?.len())))
+            .collect::<Result<Vec<_>>>()?;
+        generations.push((self.generation_cnt, self.writer.bytes_written()));
+        generations.sort();
+        let generations = generations
+            .into_iter()
+            .map(|(generation, total_bytes)| GenerationStats {
+                generation,
+                total_bytes,
+                live_bytes: live_bytes.get(&generation).cloned().unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        let blob_live_bytes = self.blob_live_bytes();
+        let mut blob_files = self
+            .blobs
+            .sizes()?
+            .into_iter()
+            .map(|(id, total_bytes)| GenerationStats {
+                generation: id,
+                total_bytes,
+                live_bytes: blob_live_bytes.get(&id).cloned().unwrap_or(0),
+            })
+            .collect::<Vec<_>>();
+        blob_files.sort_by_key(|x| x.generation);
+        let all_files = generations.iter().chain(blob_files.iter());
+        Ok(Stats {
+            keys,
+            total_bytes: all_files.clone().map(|x| x.total_bytes).sum(),
+            live_bytes: all_files.map(|x| x.live_bytes).sum(),
+            uncompressed_live_bytes,
+            generations,
+            blob_files,
+            compactions: self.compactions,
+            blob_compactions: self.blob_compactions,
+            last_compaction_ms: self.last_compaction.map(|x| x.as_millis() as u64),
+            reads: self.reads,
+            writes: self.writes,
+            namespaces,
+            replication: None,
+            cluster: None,
+        })
+    }
+
+    fn watch(&mut self, prefix: Vec<u8>, capacity: usize) -> Result<Receiver<WatchEvent>> {
+        Ok(self.watchers.add(Some(&self.namespace), prefix, capacity))
+    }
+
+    fn watch_all(&mut self, capacity: usize) -> Result<Receiver<WatchEvent>> {
+        Ok(self.watchers.add(None, vec![], capacity))
+    }
 
+    fn seq(&mut self) -> Result<u64> {
+        Ok(self.seq)
+    }
+
+    /// append a record with the sequence number of `event`
+    fn apply_event(&mut self, event: WatchEvent) -> Result<()> {
+        self.seq = self.seq.max(event.seq());
+        match event {
+            WatchEvent::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+                namespace,
+            } => {
+                let ns = self.get_or_create_namespace(&namespace)?;
+                let do_compaction = self.live_entry_in(ns, &key).is_some();
+                self.append_set(ns, key, value, seq, expires_at)?;
+                if do_compaction {
+                    self.try_compaction()?;
+                }
+            }
+            WatchEvent::Remove {
+                key,
+                seq,
+                namespace,
+            } => {
+                let ns = self.get_or_create_namespace(&namespace)?;
+                self.append_remove(ns, key, seq)?;
+                self.try_compaction()?;
+            }
+            WatchEvent::DropNamespace { namespace, seq } => {
+                if let Some(ns) = self.namespace_id(&namespace).filter(|x| *x != 0) {
+                    self.append_drop_namespace(ns, seq)?;
+                }
+            }
+        }
+        self.writer.flush()?;
         Ok(())
     }
+
+    /// switch to namespace `name`, which is only created by the first write to it
+    fn use_namespace(&mut self, name: &str) -> Result<()> {
+        self.namespace = name.to_owned();
+        self.ns = self.namespace_id(name);
+        Ok(())
+    }
+
+    /// append a `DropNamespace` record, records of the namespace are dropped on
+    /// replay and compaction
+    fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(KvStoreError::DropDefaultNamespace {});
+        }
+        let ns = self
+            .namespace_id(name)
+            .ok_or_else(|| KvStoreError::NamespaceNotFound {
+                name: name.to_owned(),
+            })?;
+        self.seq += 1;
+        self.append_drop_namespace(ns, self.seq)?;
+        self.try_compaction()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// copy keydir and open all generations and blob files it refers to
+    ///
+    /// These files are pinned until the snapshot is dropped.
+    fn snapshot(&mut self) -> Result<Box<dyn KvsSnapshot>> {
+        self.writer.flush()?;
+        let now = unix_millis();
+        let keydir = self
+            .keydir()
+            .into_iter()
+            .flatten()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), *entry))
+            .collect::<HashMap<_, _>>();
+        let mut files = HashMap::new();
+        let mut codecs = HashMap::new();
+        let mut pinned = vec![];
+        for generation in keydir
+            .values()
+            .map(|x| x.generation)
+            .collect::<HashSet<_>>()
+        {
+            let path = Self::generation_path(&self.path, generation);
+            files.insert(generation, File::open(&path)?);
+            codecs.insert(generation, self.codecs[&generation].clone());
+            pinned.push(path);
+        }
+        let mut blobs = BlobFiles::default();
+        for id in keydir
+            .values()
+            .filter_map(|x| x.blob)
+            .map(|x| x.file)
+            .collect::<HashSet<_>>()
+        {
+            blobs.open(&self.path, id, self.options.keyring.as_ref())?;
+            pinned.push(blob_path(&self.path, id));
+        }
+        let mut pins = self.pins.lock().unwrap();
+        for path in pinned.iter() {
+            pins.pin(path.clone());
+        }
+        Ok(Box::new(KvStoreSnapshot {
+            keydir,
+            files,
+            codecs,
+            blobs,
+            pinned,
+            pins: self.pins.clone(),
+        }))
+    }
 }
 
 #[cfg(test)]
@@ -327,7 +1670,7 @@ mod tests {
         }
         let backend = KvStore::open(PathBuf::from(DB_FILE)).unwrap();
         for i in 0..100 {
-            assert!(backend.keydir.contains_key(&i.to_string()));
+            assert!(backend.keydirs[&0].contains_key(i.to_string().as_bytes()));
         }
     }
 
@@ -344,7 +1687,7 @@ mod tests {
         }
         let mut backend = KvStore::open(PathBuf::from(DB_FILE)).unwrap();
         for i in 0..100 {
-            assert!(backend.keydir.contains_key(&i.to_string()));
+            assert!(backend.keydirs[&0].contains_key(i.to_string().as_bytes()));
             assert_eq!(backend.get(i.to_string()).unwrap(), Some("9".to_string()))
         }
     }
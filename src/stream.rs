@@ -0,0 +1,157 @@
+//! defines connections between clients and servers, over TCP or Unix domain
+//! sockets, which may be wrapped in TLS
+//!
+//! Addresses are either `host:port`, or `unix:PATH` for a Unix domain socket,
+//! e.g. `unix:/run/kvs.sock`. Only users with write permission on the socket
+//! file can connect to it, so its mode serves as an access control.
+
+use crate::error::KvStoreError;
+use crate::Result;
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+/// prefix of addresses of Unix domain sockets
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// path of the socket if `addr` is the address of a Unix domain socket
+pub fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_PREFIX)
+}
+
+/// a bidirectional byte stream, e.g. a `TcpStream` or a TLS stream over it
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// open a connection to `addr`
+pub(crate) fn connect(addr: &str) -> Result<Box<dyn Stream>> {
+    match unix_path(addr) {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(unsupported()),
+        None => Ok(Box::new(TcpStream::connect(addr)?)),
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> KvStoreError {
+    KvStoreError::IOError(io::Error::new(
+        io::ErrorKind::Other,
+        "unix domain sockets are not supported",
+    ))
+}
+
+/// Listener of a `KvsServer`
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+impl Listener {
+    /// listen on `addr`, with mode `mode` of the socket file if it's a Unix
+    /// domain socket, or the mode given by umask if `None`
+    ///
+    /// A socket file left by a server no longer running is replaced.
+    pub fn bind(addr: &str, mode: Option<u32>) -> Result<Self> {
+        match unix_path(addr) {
+            #[cfg(unix)]
+            Some(path) => Ok(Listener::Unix(Self::bind_unix(Path::new(path), mode)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(unsupported()),
+            None => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+        }
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+                return Err(KvStoreError::IOError(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                )));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let mode = match mode {
+            Some(mode) => mode,
+            None => return Ok(UnixListener::bind(path)?),
+        };
+        // bind to a temporary name and set the mode before moving the socket in
+        // place, so that it's never reachable with a wider mode
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".{}.tmp", std::process::id()));
+        let _ = std::fs::remove_file(&tmp_path);
+        let listener = UnixListener::bind(&tmp_path)?;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    }
+
+    /// wait for a connection, and return it with a description of the peer
+    pub(crate) fn accept(&self) -> Result<(Box<dyn Stream>, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                Ok((Box::new(stream), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Box::new(stream), "unix".to_owned()))
+            }
+        }
+    }
+}
+
+/// A connection served by one thread, which reads requests and writes
+/// responses through separate shared references
+pub(crate) struct Connection {
+    stream: RefCell<Box<dyn Stream>>,
+}
+
+impl Connection {
+    pub fn new(stream: Box<dyn Stream>) -> Self {
+        Self {
+            stream: RefCell::new(stream),
+        }
+    }
+}
+
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.borrow_mut().read(buf)
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.borrow_mut().flush()
+    }
+}
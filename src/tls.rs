@@ -0,0 +1,120 @@
+//! defines TLS for connections between clients and servers
+//!
+//! Certificates and private keys are read from PEM files. A server may require
+//! clients to present a certificate signed by a given CA. Clients verify the
+//! server certificate against a given CA, by the host of the address connected to,
+//! which may be a DNS name or an IP address, or `localhost` for a Unix domain socket.
+
+use crate::error::KvStoreError;
+use crate::stream::{unix_path, Stream};
+use crate::Result;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName, StreamOwned,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+fn invalid(reason: String) -> KvStoreError {
+    KvStoreError::InvalidTlsConfig { reason }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(invalid(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// Accepts TLS connections for `KvsServer`
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// serve certificate chain `cert` with private key `key`, and require clients
+    /// to present a certificate signed by a CA in `client_ca` if given
+    pub fn new(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(client_ca) => builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(load_roots(client_ca)?).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// wrap `stream`, the handshake is done on first read or write
+    pub(crate) fn accept(&self, stream: Box<dyn Stream>) -> Result<Box<dyn Stream>> {
+        let connection = ServerConnection::new(self.config.clone())?;
+        Ok(Box::new(StreamOwned::new(connection, stream)))
+    }
+}
+
+/// Opens TLS connections for `KvsClient`
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    /// trust servers with a certificate signed by a CA in `ca`, and present
+    /// certificate chain `cert` with private key `key` if given
+    pub fn new(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Self> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// wrap `stream` to server `addr`, the handshake is done on first read or write
+    pub(crate) fn connect(&self, addr: &str, stream: Box<dyn Stream>) -> Result<Box<dyn Stream>> {
+        let host = match unix_path(addr) {
+            Some(_) => "localhost",
+            None => addr.rsplit_once(':').map_or(addr, |(host, _)| host),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host)
+            .map_err(|_| invalid(format!("invalid server name {}", host)))?;
+        let connection = ClientConnection::new(self.config.clone(), name)?;
+        Ok(Box::new(StreamOwned::new(connection, stream)))
+    }
+}
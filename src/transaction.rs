@@ -0,0 +1,83 @@
+//! defines optimistic transactions over a KvsEngine
+
+use crate::error::KvStoreError;
+use crate::{KvsEngine, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// Optimistic multi-key transaction
+///
+/// Versions of keys read are recorded and writes are buffered locally, so that
+/// the engine is not held between operations. On commit, writes are applied only
+/// if no key read has been changed by others in the meantime.
+#[derive(Default)]
+pub struct Transaction {
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub fn begin() -> Self {
+        Self::default()
+    }
+
+    /// get value of `key`, including writes of this transaction
+    pub fn get_bytes<E: KvsEngine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+        key: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = match engine.get_versioned_bytes(key.clone())? {
+            Some((value, version)) => (Some(value), Some(version)),
+            None => (None, None),
+        };
+        // commit validates against the version first read
+        self.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn remove_bytes<E: KvsEngine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+        key: Vec<u8>,
+    ) -> Result<()> {
+        if self.get_bytes(engine, key.clone())?.is_none() {
+            return Err(KvStoreError::key_not_found(&key));
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// apply all writes, returns `false` if the transaction conflicts with others
+    pub fn commit<E: KvsEngine + ?Sized>(self, engine: &mut E) -> Result<bool> {
+        engine.commit_bytes(
+            self.reads.into_iter().collect(),
+            self.writes.into_iter().collect(),
+        )
+    }
+
+    pub fn get<E: KvsEngine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+        key: String,
+    ) -> Result<Option<String>> {
+        Ok(match self.get_bytes(engine, key.into_bytes())? {
+            Some(value) => Some(String::from_utf8(value)?),
+            None => None,
+        })
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove<E: KvsEngine + ?Sized>(&mut self, engine: &mut E, key: String) -> Result<()> {
+        self.remove_bytes(engine, key.into_bytes())
+    }
+}
@@ -0,0 +1,453 @@
+//! defines offline verification and inspection of KvStore data directories
+
+use crate::blob::{all_blob_files, BlobFiles};
+use crate::encryption::Keyring;
+use crate::engine::unix_millis;
+use crate::log::{Codec, Command, Header};
+use crate::{KvStore, Result};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// result of verifying one generation
+#[derive(Debug)]
+pub struct GenerationReport {
+    pub generation: u64,
+    /// number of valid records
+    pub records: u64,
+    pub total_bytes: u64,
+    /// bytes of records that are the latest of a live key
+    pub live_bytes: u64,
+    /// byte range that can't be decoded, till the end of file
+    pub corrupt: Option<(u64, u64)>,
+    /// id of the key the generation is encrypted with
+    pub key_id: Option<String>,
+    /// why records can't be decrypted, e.g. the key is not in keyring or is wrong
+    pub key_error: Option<String>,
+}
+
+/// result of verifying a data directory
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub generations: Vec<GenerationReport>,
+    /// files in data directory that are never replayed, e.g. generations and blob
+    /// files dropped by compaction while pinned by a snapshot, and left behind by a
+    /// crash before the snapshot was dropped
+    pub orphaned: Vec<PathBuf>,
+    /// blob files that can't be opened by id, with why
+    pub blob_errors: Vec<(u64, String)>,
+    /// keys whose latest record can't be read back
+    pub dangling: Vec<Vec<u8>>,
+}
+
+impl VerifyReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.generations.iter().map(|x| x.total_bytes).sum()
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.generations.iter().map(|x| x.live_bytes).sum()
+    }
+
+    /// number of problems found
+    pub fn problems(&self) -> usize {
+        self.generations
+            .iter()
+            .filter(|x| x.corrupt.is_some() || x.key_error.is_some())
+            .count()
+            + self.orphaned.len()
+            + self.blob_errors.len()
+            + self.dangling.len()
+    }
+}
+
+/// percentage of `part` in `total`
+fn ratio(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for g in &self.generations {
+            write!(
+                f,
+                "generation {}: {} records, {} bytes, {} live ({:.1}%)",
+                g.generation,
+                g.records,
+                g.total_bytes,
+                g.live_bytes,
+                ratio(g.live_bytes, g.total_bytes)
+            )?;
+            if let Some(key_id) = &g.key_id {
+                write!(f, ", key {}", key_id)?;
+            }
+            if let Some(key_error) = &g.key_error {
+                write!(f, ", {}", key_error)?;
+            }
+            if let Some((start, end)) = g.corrupt {
+                write!(f, ", corrupt range {}..{}", start, end)?;
+            }
+            writeln!(f)?;
+        }
+        for path in &self.orphaned {
+            writeln!(f, "orphaned file: {}", path.display())?;
+        }
+        for (id, error) in &self.blob_errors {
+            writeln!(f, "blob file {}: {}", id, error)?;
+        }
+        for key in &self.dangling {
+            writeln!(f, "dangling entry: {}", String::from_utf8_lossy(key))?;
+        }
+        writeln!(
+            f,
+            "total: {} bytes, {} live ({:.1}%)",
+            self.total_bytes(),
+            self.live_bytes(),
+            ratio(self.live_bytes(), self.total_bytes())
+        )?;
+        match self.problems() {
+            0 => write!(f, "OK"),
+            cnt => write!(f, "{} problems found", cnt),
+        }
+    }
+}
+
+/// location of the latest record of a key
+struct Location {
+    generation: u64,
+    offset: u64,
+    len: u64,
+}
+
+/// check every record in data directory `path` of a KvStore, which must not be in use
+///
+/// Encrypted generations are decrypted with keys in `keyring`, and reported if
+/// their key is missing or wrong.
+pub fn verify(path: &Path, keyring: Option<&Keyring>) -> Result<VerifyReport> {
+    let path = path.to_path_buf();
+    let mut report = VerifyReport::default();
+
+    for f in std::fs::read_dir(&path)? {
+        let f = f?.path();
+        let is_generation = f
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse::<u64>().ok())
+            .is_some();
+        let ext = f.extension().and_then(|x| x.to_str());
+        if f.is_file() && (ext == Some("obsolete") || ext == Some("db") && !is_generation) {
+            report.orphaned.push(f);
+        }
+    }
+    report.orphaned.sort();
+
+    // replay all generations like `KvStore::open`, keys are paired with the id
+    // of their namespace
+    let now = unix_millis();
+    let mut keydir = HashMap::new();
+    let mut codecs = HashMap::new();
+    for generation in KvStore::all_generations(&path)? {
+        let file = File::open(KvStore::generation_path(&path, generation))?;
+        let total_bytes = file.metadata()?.len();
+        let mut report_generation = GenerationReport {
+            generation,
+            records: 0,
+            total_bytes,
+            live_bytes: 0,
+            corrupt: None,
+            key_id: None,
+            key_error: None,
+        };
+        let mut reader = BufReader::new(file);
+        let header = match Header::read(&mut reader) {
+            Ok(header) => header,
+            Err(_) => {
+                report_generation.corrupt = Some((0, total_bytes));
+                report.generations.push(report_generation);
+                continue;
+            }
+        };
+        report_generation.key_id = header.as_ref().map(|x| x.key_id.clone());
+        let codec = match Codec::from_header(header.as_ref(), keyring) {
+            Ok(codec) => codec,
+            Err(e) => {
+                report_generation.key_error = Some(e.to_string());
+                report.generations.push(report_generation);
+                continue;
+            }
+        };
+        let mut records = codec.records(&mut reader)?;
+        loop {
+            let offset = records.offset();
+            match records.next() {
+                Some(Ok(cmd)) => {
+                    report_generation.records += 1;
+                    let len = records.offset() - offset;
+                    match cmd {
+                        Command::Set {
+                            key,
+                            expires_at: Some(expires_at),
+                            ns,
+                            ..
+                        } if expires_at <= now => {
+                            keydir.remove(&(ns, key));
+                        }
+                        Command::Set { key, ns, .. } => {
+                            let location = Location {
+                                generation,
+                                offset,
+                                len,
+                            };
+                            keydir.insert((ns, key), location);
+                        }
+                        Command::Remove { key, ns, .. } => {
+                            keydir.remove(&(ns, key));
+                        }
+                        Command::Namespace { .. } | Command::Seq { .. } => {}
+                        Command::DropNamespace { ns, .. } => {
+                            keydir.retain(|(x, _), _| *x != ns);
+                        }
+                    }
+                }
+                Some(Err(_)) => {
+                    report_generation.corrupt = Some((offset, total_bytes));
+                    break;
+                }
+                None => break,
+            }
+        }
+        report.generations.push(report_generation);
+        codecs.insert(generation, codec);
+    }
+
+    // blob files that can't be opened also leave keys with values in them dangling
+    let mut blobs = BlobFiles::default();
+    for id in all_blob_files(&path)? {
+        if let Err(e) = blobs.open(&path, id, keyring) {
+            report.blob_errors.push((id, e.to_string()));
+        }
+    }
+
+    // read back the latest record of every key, and its value if it's in a blob file
+    let mut files = HashMap::new();
+    let mut live_bytes = HashMap::new();
+    for ((ns, key), location) in keydir {
+        let file = match files.entry(location.generation) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(File::open(KvStore::generation_path(
+                &path,
+                location.generation,
+            ))?),
+        };
+        file.seek(SeekFrom::Start(location.offset))?;
+        let valid = match codecs[&location.generation].decode(BufReader::new(&mut *file)) {
+            Ok(Command::Set {
+                key: record_key,
+                ns: record_ns,
+                blob,
+                ..
+            }) => {
+                record_key == key && record_ns == ns && blob.is_none_or(|x| blobs.read(&x).is_ok())
+            }
+            _ => false,
+        };
+        if valid {
+            *live_bytes.entry(location.generation).or_insert(0) += location.len;
+        } else {
+            report.dangling.push(key);
+        }
+    }
+    report.dangling.sort();
+    for g in &mut report.generations {
+        g.live_bytes = live_bytes.get(&g.generation).cloned().unwrap_or(0);
+    }
+
+    Ok(report)
+}
+
+/// filter of records printed by `log_dump`
+#[derive(Debug, Default)]
+pub struct LogFilter {
+    /// only records of this key
+    pub key: Option<Vec<u8>>,
+    /// only records starting at or after this offset
+    pub start: Option<u64>,
+    /// only records starting before this offset
+    pub end: Option<u64>,
+}
+
+impl LogFilter {
+    /// whether the record at `offset` of `key` matches, records of namespaces
+    /// have no key
+    fn matches(&self, offset: u64, key: Option<&[u8]>) -> bool {
+        if let Some(x) = &self.key {
+            if Some(x.as_slice()) != key {
+                return false;
+            }
+        }
+        offset >= self.start.unwrap_or(0) && offset < self.end.unwrap_or(u64::MAX)
+    }
+}
+
+/// printable representation of a binary key
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|x| std::ascii::escape_default(*x))
+        .map(char::from)
+        .collect()
+}
+
+/// print every record of generation file `path` matching `filter` into `out`,
+/// one per line, returns number of records printed
+///
+/// Records of an encrypted generation are decrypted with keys in `keyring`, and
+/// its header is printed first. Values in blob files are printed as their
+/// location. Printing stops at the first record that can't be decoded.
+pub fn log_dump(
+    path: &Path,
+    keyring: Option<&Keyring>,
+    filter: &LogFilter,
+    mut out: impl Write,
+) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = Header::read(&mut reader)?;
+    let codec = Codec::from_header(header.as_ref(), keyring)?;
+    if let Some(header) = header {
+        writeln!(out, "header key_id={}", escape(header.key_id.as_bytes()))?;
+    }
+    let mut records = codec.records(&mut reader)?;
+    let mut cnt = 0;
+    loop {
+        let offset = records.offset();
+        let cmd = match records.next() {
+            Some(Ok(cmd)) => cmd,
+            Some(Err(e)) => {
+                writeln!(out, "{} corrupt: {}", offset, e)?;
+                break;
+            }
+            None => break,
+        };
+        let len = records.offset() - offset;
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                seq,
+                expires_at,
+                timestamp,
+                ns,
+                compression,
+                blob,
+            } => {
+                if !filter.matches(offset, Some(&key)) {
+                    continue;
+                }
+                write!(
+                    out,
+                    "{} len={} set seq={} timestamp={} key={} value_size={}",
+                    offset,
+                    len,
+                    seq,
+                    timestamp,
+                    escape(&key),
+                    value.len()
+                )?;
+                if let Some(expires_at) = expires_at {
+                    write!(out, " expires_at={}", expires_at)?;
+                }
+                if ns != 0 {
+                    write!(out, " ns={}", ns)?;
+                }
+                if let Some(blob) = blob {
+                    write!(
+                        out,
+                        " blob={} blob_offset={} blob_len={}",
+                        blob.file, blob.offset, blob.len
+                    )?;
+                }
+                if !compression.is_none() {
+                    let uncompressed_len = match blob {
+                        Some(blob) => blob.value_len,
+                        None => compression.uncompressed_len(&value) as u64,
+                    };
+                    write!(
+                        out,
+                        " compression={} uncompressed_size={}",
+                        compression, uncompressed_len
+                    )?;
+                }
+                writeln!(out)?;
+            }
+            Command::Remove {
+                key,
+                seq,
+                timestamp,
+                ns,
+            } => {
+                if !filter.matches(offset, Some(&key)) {
+                    continue;
+                }
+                write!(
+                    out,
+                    "{} len={} remove seq={} timestamp={} key={}",
+                    offset,
+                    len,
+                    seq,
+                    timestamp,
+                    escape(&key)
+                )?;
+                if ns != 0 {
+                    write!(out, " ns={}", ns)?;
+                }
+                writeln!(out)?;
+            }
+            Command::Namespace {
+                ns,
+                name,
+                seq,
+                timestamp,
+            } => {
+                if !filter.matches(offset, None) {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{} len={} namespace seq={} timestamp={} ns={} name={}",
+                    offset,
+                    len,
+                    seq,
+                    timestamp,
+                    ns,
+                    escape(name.as_bytes())
+                )?;
+            }
+            Command::DropNamespace { ns, seq, timestamp } => {
+                if !filter.matches(offset, None) {
+                    continue;
+                }
+                writeln!(
+                    out,
+                    "{} len={} drop_namespace seq={} timestamp={} ns={}",
+                    offset, len, seq, timestamp, ns
+                )?;
+            }
+            Command::Seq { seq } => {
+                if !filter.matches(offset, None) {
+                    continue;
+                }
+                writeln!(out, "{} len={} seq seq={}", offset, len, seq)?;
+            }
+        }
+        cnt += 1;
+    }
+    out.flush()?;
+    Ok(cnt)
+}
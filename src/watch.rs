@@ -0,0 +1,118 @@
+//! defines change events of keys delivered to watchers
+
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+/// change of a key, see `KvsEngine::watch`
+///
+/// `namespace` is the namespace of the key, empty for the default namespace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        seq: u64,
+        /// expiry timestamp in milliseconds since unix epoch
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        namespace: String,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        seq: u64,
+        #[serde(default)]
+        namespace: String,
+    },
+    /// namespace dropped with all its keys, sent to all watchers of the namespace
+    DropNamespace { namespace: String, seq: u64 },
+}
+
+impl WatchEvent {
+    /// key changed, empty for `DropNamespace`
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key, .. } => key,
+            WatchEvent::DropNamespace { .. } => &[],
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        match self {
+            WatchEvent::Set { seq, .. }
+            | WatchEvent::Remove { seq, .. }
+            | WatchEvent::DropNamespace { seq, .. } => *seq,
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        match self {
+            WatchEvent::Set { namespace, .. }
+            | WatchEvent::Remove { namespace, .. }
+            | WatchEvent::DropNamespace { namespace, .. } => namespace,
+        }
+    }
+}
+
+/// a watcher of keys with `prefix` in `namespace`, or in all namespaces if `None`
+struct Watcher {
+    namespace: Option<String>,
+    prefix: Vec<u8>,
+    /// buffers a limited number of events, see `Watchers::notify`
+    sender: SyncSender<WatchEvent>,
+}
+
+impl Watcher {
+    fn matches(&self, event: &WatchEvent) -> bool {
+        match &self.namespace {
+            Some(namespace) if namespace != event.namespace() => false,
+            _ => match event {
+                WatchEvent::DropNamespace { .. } => true,
+                event => event.key().starts_with(&self.prefix),
+            },
+        }
+    }
+}
+
+/// watchers registered on an engine, each watching keys with a prefix
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Vec<Watcher>,
+}
+
+impl Watchers {
+    /// register a watcher of keys starting with `prefix` in `namespace`, or in
+    /// all namespaces if `None`, which is dropped once `capacity` events are pending
+    pub(crate) fn add(
+        &mut self,
+        namespace: Option<&str>,
+        prefix: Vec<u8>,
+        capacity: usize,
+    ) -> Receiver<WatchEvent> {
+        let (sender, receiver) = sync_channel(capacity);
+        self.watchers.push(Watcher {
+            namespace: namespace.map(ToOwned::to_owned),
+            prefix,
+            sender,
+        });
+        receiver
+    }
+
+    /// whether any watcher is interested in `key` of `namespace`, so that events
+    /// are only built when needed
+    pub(crate) fn watching(&self, namespace: &str, key: &[u8]) -> bool {
+        self.watchers.iter().any(|x| {
+            !matches!(&x.namespace, Some(x) if x != namespace) && key.starts_with(&x.prefix)
+        })
+    }
+
+    /// send `event` to its watchers, dropping those no longer received or too far
+    /// behind
+    pub(crate) fn notify(&mut self, event: WatchEvent) {
+        self.watchers
+            .retain(|x| !x.matches(&event) || x.sender.try_send(event.clone()).is_ok());
+    }
+}
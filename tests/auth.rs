@@ -0,0 +1,182 @@
+use kvs::auth::{AuthConfig, Grant, Permission, TokenConfig};
+use kvs::client::KvsClient;
+use kvs::error::KvStoreError;
+use kvs::server::KvsServer;
+use kvs::{CommandRequest, CommandResponse, KvStore, Result};
+use serde_bytes::ByteBuf;
+use slog::{o, Discard, Logger};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+fn grant(namespace: Option<&str>, prefix: &str, permission: Permission) -> Grant {
+    Grant {
+        namespace: namespace.map(Into::into),
+        prefix: prefix.to_owned(),
+        permission,
+    }
+}
+
+fn token(token: &str, grants: Vec<Grant>) -> TokenConfig {
+    TokenConfig {
+        token: token.to_owned(),
+        name: token.to_owned(),
+        grants,
+    }
+}
+
+fn set(key: &str, value: &str) -> CommandRequest {
+    CommandRequest::Set {
+        key: key.into(),
+        value: value.into(),
+        ttl: None,
+    }
+}
+
+fn get(key: &str) -> CommandRequest {
+    CommandRequest::Get { key: key.into() }
+}
+
+fn scan(start: &str, end: Option<&str>) -> CommandRequest {
+    CommandRequest::Scan {
+        start: start.into(),
+        end: end.map(Into::into),
+        limit: None,
+    }
+}
+
+// Permissions are granted on key prefixes and namespaces, and higher
+// permissions include lower ones
+#[test]
+fn token_permissions() {
+    let config = token(
+        "app",
+        vec![
+            grant(None, "app/", Permission::Write),
+            grant(None, "shared/", Permission::Read),
+            grant(Some("team-a"), "", Permission::Admin),
+        ],
+    );
+    assert!(config.allows(&set("app/key", "value")));
+    assert!(config.allows(&get("app/key")));
+    assert!(config.allows(&get("shared/key")));
+    assert!(!config.allows(&set("shared/key", "value")));
+    assert!(!config.allows(&get("other")));
+    assert!(config.allows(&set("other", "value").in_namespace("team-a")));
+    assert!(!config.allows(&set("other", "value").in_namespace("team-b")));
+
+    let pairs = vec![
+        (ByteBuf::from("app/1"), ByteBuf::from("value")),
+        (ByteBuf::from("shared/1"), ByteBuf::from("value")),
+    ];
+    assert!(!config.allows(&CommandRequest::MSet { pairs }));
+    let keys = vec![ByteBuf::from("app/1"), ByteBuf::from("shared/1")];
+    assert!(config.allows(&CommandRequest::MGet { keys }));
+
+    // scans and watches must stay within a prefix
+    assert!(config.allows(&scan("app/", Some("app0"))));
+    assert!(config.allows(&scan("app/a", Some("app/b"))));
+    assert!(!config.allows(&scan("app/", Some("app1"))));
+    assert!(!config.allows(&scan("app/", None)));
+    assert!(!config.allows(&scan("", Some("app0"))));
+    assert!(config.allows(&scan("", None).in_namespace("team-a")));
+    assert!(config.allows(&CommandRequest::Watch {
+        prefix: b"shared/".to_vec()
+    }));
+    assert!(!config.allows(&CommandRequest::Watch { prefix: vec![] }));
+
+    assert!(config.allows(&CommandRequest::DropNamespace {
+        namespace: "team-a".to_owned()
+    }));
+    assert!(!config.allows(&CommandRequest::DropNamespace {
+        namespace: "team-b".to_owned()
+    }));
+    assert!(!config.allows(&CommandRequest::Stats {}));
+    assert!(!config.allows(&CommandRequest::Replicate { since: None }));
+    assert!(config.allows(&CommandRequest::Begin {}));
+
+    let admin = token("admin", vec![grant(None, "", Permission::Admin)]);
+    assert!(admin.allows(&CommandRequest::Stats {}));
+    assert!(admin.allows(&CommandRequest::Checkpoint {
+        path: "backup".to_owned()
+    }));
+    assert!(admin.allows(&scan("", None).in_namespace("team-b")));
+}
+
+// Clients must authenticate before any request, and requests the token lacks
+// permission for are denied
+#[test]
+fn authenticated_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let mut server = KvsServer::new(listener, Box::new(KvStore::open(temp_dir.path())?));
+    server.require_auth(AuthConfig {
+        tokens: vec![
+            token("writer", vec![grant(None, "app/", Permission::Write)]),
+            token("reader", vec![grant(None, "app/", Permission::Read)]),
+        ],
+    });
+    thread::spawn(move || server.serve(&Logger::root(Discard, o!())));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.request(&get("app/key"))?,
+        CommandResponse::PermissionDenied {}
+    ));
+    assert!(matches!(
+        client.authenticate("unknown"),
+        Err(KvStoreError::PermissionDenied {})
+    ));
+    assert!(matches!(
+        client.request(&get("app/key"))?,
+        CommandResponse::PermissionDenied {}
+    ));
+
+    client.authenticate("writer")?;
+    assert!(matches!(
+        client.request(&set("app/key", "value"))?,
+        CommandResponse::Success {}
+    ));
+    assert!(matches!(
+        client.request(&set("other", "value"))?,
+        CommandResponse::PermissionDenied {}
+    ));
+    assert!(matches!(
+        client.request(&CommandRequest::Stats {})?,
+        CommandResponse::PermissionDenied {}
+    ));
+    // requests in a transaction are checked one by one
+    assert!(matches!(
+        client.request(&CommandRequest::Begin {})?,
+        CommandResponse::Success {}
+    ));
+    assert!(matches!(
+        client.request(&set("other", "value"))?,
+        CommandResponse::PermissionDenied {}
+    ));
+    assert!(matches!(
+        client.request(&set("app/key2", "value2"))?,
+        CommandResponse::Success {}
+    ));
+    assert!(matches!(
+        client.request(&CommandRequest::Commit {})?,
+        CommandResponse::Success {}
+    ));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.authenticate("reader")?;
+    match client.request(&get("app/key2"))? {
+        CommandResponse::Value { value, .. } => assert_eq!(value, Some(b"value2".to_vec())),
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(matches!(
+        client.request(&set("app/key", "value"))?,
+        CommandResponse::PermissionDenied {}
+    ));
+    assert!(matches!(
+        KvsClient::connect(addr)?.watch(b"app/".to_vec()),
+        Err(KvStoreError::PermissionDenied {})
+    ));
+    Ok(())
+}
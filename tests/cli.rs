@@ -1,7 +1,13 @@
 use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::{CommandRequest, CommandResponse};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -335,3 +341,1561 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_conditional_set() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setnx", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setnx", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "value2",
+            "--expected",
+            "value0",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "value2",
+            "--expected",
+            "value1",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_expire_key() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["persist", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("No expiry\n");
+
+    thread::sleep(Duration::from_secs(2));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_multiple_keys() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "key1", "value1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "key2", "key3", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\nKey not found\nvalue1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_binary_key_value() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "00ff", "c328ff", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "00ff", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c328ff\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "6b657931", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("76616c756531\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "not-hex", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client txn` should apply commands read from stdin atomically.
+#[test]
+fn cli_transaction() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["txn", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key1\nset key2 value2\nrm key1\nget key2\n")
+        .assert()
+        .success()
+        .stdout("value1\nvalue2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["txn", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3 value3\nabort\n")
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client backup` should write a checkpoint that can be read by `kvs`.
+#[test]
+fn cli_backup() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let backup_path = backup_dir.path().join("backup");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", backup_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["backup", backup_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(
+        fs::read_to_string(backup_path.join(".config")).unwrap(),
+        "kvs"
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&backup_path)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs restore` should rebuild the store as of a sequence number.
+#[test]
+fn cli_restore() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let dest = restore_dir.path().join("1");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", dest.to_str().unwrap(), "--until", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let dest = restore_dir.path().join("latest");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", dest.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["restore", dest.to_str().unwrap(), "--until", "@abc"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs dump`, `kvs load` and `kvs migrate` should move data between stores.
+#[test]
+fn cli_dump_load_migrate() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let load_dir = TempDir::new().expect("unable to create temporary working directory");
+    let migrate_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"format\":\"kvs-dump\",\"version\":2}\n{\"key\":\"key1\",\"value\":\"value1\"}\n",
+        );
+
+    let dump_file = temp_dir.path().join("dump.bin");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", dump_file.to_str().unwrap(), "--format", "binary"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["load", dump_file.to_str().unwrap(), "--format", "binary"])
+        .current_dir(&load_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&load_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let dest = migrate_dir.path().join("sled");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "migrate",
+            dest.to_str().unwrap(),
+            "--from",
+            "sled",
+            "--to",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "migrate",
+            dest.to_str().unwrap(),
+            "--from",
+            "kvs",
+            "--to",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(fs::read_to_string(dest.join(".config")).unwrap(), "sled");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs verify` should exit with non-zero code on corrupt records.
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&data_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["verify", data_dir.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("OK"));
+
+    fs::write(data_dir.join("100.db"), &[0xff, 0xff]).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["verify", data_dir.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(
+            "generation 100: 0 records, 2 bytes, 0 live (0.0%), corrupt range 0..2",
+        ));
+}
+
+// `kvs log-dump` should print records of a generation file.
+#[test]
+fn cli_log_dump() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let file = fs::read_dir(&temp_dir)
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .find(|x| fs::metadata(x).unwrap().len() > 0)
+        .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log-dump", file.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(" set seq=1 ").and(contains(" key=key1 value_size=6\n")));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log-dump", file.to_str().unwrap(), "--key", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log-dump", file.to_str().unwrap(), "--start", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+}
+
+// `kvs-client stats` should print statistics of the store.
+#[test]
+fn cli_stats() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("keys: 1\n")
+                .and(contains("reads: 1\n"))
+                .and(contains("writes: 1\n"))
+                .and(contains("compactions: 0\n")),
+        );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server --compression` should compress values at least as long as
+// `--compression-threshold`, and `kvs-client stats` should print the ratio.
+#[test]
+fn cli_compression() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4034";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .args(&["--compression", "lz4", "--compression-threshold", "16"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "0123456789".repeat(100);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("compression ratio: ").and(contains("compression ratio: 1.00").not()));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server --blob-threshold` should store large values in blob files.
+#[test]
+fn cli_blob_threshold() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4035";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--blob-threshold", "100"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let value = "0123456789".repeat(100);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &value, "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", value));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("blob file 0: "));
+    assert!(temp_dir.path().join("0.blob").exists());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+/// `request` wrapped in `namespace`, even if it's the default one
+fn wrap(namespace: &str, request: CommandRequest) -> CommandRequest {
+    CommandRequest::InNamespace {
+        namespace: namespace.to_owned(),
+        request: Box::new(request),
+    }
+}
+
+// Requests changing the connection wrapped in a namespace should be rejected or
+// served as if not wrapped, without bringing the server down.
+#[test]
+fn cli_nested_requests() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4036";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // turns into a replication stream, like an unwrapped request
+    let mut client = KvsClient::connect(addr).unwrap();
+    client
+        .request(&wrap("", CommandRequest::Replicate { since: None }))
+        .unwrap();
+    drop(client);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    let auth = CommandRequest::Auth {
+        token: "token".to_owned(),
+    };
+    assert!(matches!(
+        client.request(&wrap("", auth.clone())),
+        Ok(CommandResponse::Success {})
+    ));
+    for request in [
+        wrap("ns", auth),
+        wrap("ns", CommandRequest::Replicate { since: None }),
+        wrap("", wrap("", CommandRequest::Replicate { since: None })),
+        wrap("other", wrap("ns", CommandRequest::Stats {})),
+    ] {
+        assert!(matches!(
+            client.request(&request),
+            Ok(CommandResponse::Error { .. })
+        ));
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server --metrics-addr` should serve metrics of requests and engine.
+#[test]
+fn cli_metrics() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let metrics_addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--metrics-addr",
+            metrics_addr,
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut stream = TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_requests_total{command=\"set\"} 1\n"));
+    assert!(response.contains("kvs_requests_total{command=\"get\"} 1\n"));
+    assert!(
+        response.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 1\n")
+    );
+    assert!(response.contains("kvs_request_duration_seconds_count{command=\"rm\"} 1\n"));
+    assert!(response.contains("kvs_errors_total{kind=\"key_not_found\"} 1\n"));
+    assert!(response.contains("kvs_open_connections "));
+    assert!(response.contains("kvs_engine_keys 1\n"));
+    assert!(response.contains("kvs_engine_compactions_total 0\n"));
+}
+
+// `kvs-client watch` should print changes of keys with prefix.
+#[test]
+fn cli_watch() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "other", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    watcher.kill().unwrap();
+    let output = watcher.wait_with_output().unwrap();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 set key1 value1\n3 rm key1\n"
+    );
+}
+
+/// start kvs-server with `args` in `dir`, which is killed once the returned
+/// sender is dropped
+fn start_server(args: &[&str], dir: &Path) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel::<()>(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+fn check_replication(engine: &str, addr: &str, replica_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let primary_args = ["--engine", engine, "--addr", addr];
+    let replica_args = [
+        "--engine",
+        engine,
+        "--addr",
+        replica_addr,
+        "--replica-of",
+        addr,
+    ];
+    let mut primary = start_server(&primary_args, temp_dir.path());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let replica = start_server(&replica_args, replica_dir.path());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", replica_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", replica_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--addr", replica_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("writes are not allowed on replica"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", replica_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains(format!("replica of: {}\n", addr))
+                .and(contains("connected: true\n"))
+                .and(contains("replication lag: ")),
+        );
+
+    // a restarted replica resumes after the latest change it applied
+    drop(replica.0);
+    replica.1.join().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let replica = start_server(&replica_args, replica_dir.path());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", replica_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    // replicas reconnect to a restarted primary, which only kvs can resume from,
+    // as sequence numbers of sled skip ahead on restart
+    if engine == "kvs" {
+        drop(primary.0);
+        primary.1.join().unwrap();
+        primary = start_server(&primary_args, temp_dir.path());
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key4", "value4", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        thread::sleep(Duration::from_secs(2));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key4", "--addr", replica_addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value4\n");
+    }
+
+    for (sender, handle) in [replica, primary] {
+        drop(sender);
+        handle.join().unwrap();
+    }
+}
+
+// `kvs-server --replica-of` should follow the primary and reject writes.
+#[test]
+fn cli_replication() {
+    check_replication("kvs", "127.0.0.1:4016", "127.0.0.1:4017");
+    check_replication("sled", "127.0.0.1:4018", "127.0.0.1:4019");
+}
+
+// `kvs-server --node-id` should serve writes and reads through a Raft cluster.
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4020", "127.0.0.1:4021", "127.0.0.1:4022"];
+    let raft_addrs = ["127.0.0.1:4023", "127.0.0.1:4024", "127.0.0.1:4025"];
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dirs = (0..3).map(|_| TempDir::new().unwrap()).collect::<Vec<_>>();
+    let mut children = (0..3)
+        .map(|i| {
+            let peers = (0..3)
+                .filter(|x| *x != i)
+                .map(|x| format!("{}={}", x + 1, raft_addrs[x]))
+                .collect::<Vec<_>>()
+                .join(",");
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&[
+                    "--engine",
+                    "kvs",
+                    "--addr",
+                    addrs[i],
+                    "--node-id",
+                    &(i + 1).to_string(),
+                    "--raft-addr",
+                    raft_addrs[i],
+                    "--peers",
+                    &peers,
+                    "--raft-token",
+                    "secret",
+                ])
+                .current_dir(&temp_dirs[i])
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for child in children.iter_mut() {
+            child.kill().expect("server exited before killed");
+        }
+    });
+    thread::sleep(Duration::from_secs(2));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addrs[0]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", addrs[1]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addrs[2]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success();
+    for addr in addrs.iter() {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dirs[0])
+            .assert()
+            .success()
+            .stdout("Key not found\n");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key2", "--addr", addr])
+            .current_dir(&temp_dirs[0])
+            .assert()
+            .success()
+            .stdout("value2\n");
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addrs[1]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout(
+            contains("node: 2 (")
+                .and(contains(format!("member 1: {}\n", raft_addrs[0])))
+                .and(contains(format!("member 3: {}", raft_addrs[2]))),
+        );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["txn", "--addr", addrs[0]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .failure()
+        .stderr(contains("transactions are not supported in cluster mode"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client --shards` should route keys to shards, and `rebalance` should move
+// keys to an added shard.
+#[test]
+fn cli_sharding() {
+    let addrs = ["127.0.0.1:4026", "127.0.0.1:4027", "127.0.0.1:4028"];
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dirs = (0..3).map(|_| TempDir::new().unwrap()).collect::<Vec<_>>();
+    let mut children = (0..3)
+        .map(|i| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--engine", "kvs", "--addr", addrs[i]])
+                .current_dir(&temp_dirs[i])
+                .spawn()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        for child in children.iter_mut() {
+            child.kill().expect("server exited before killed");
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let shards = format!("{},{}=2", addrs[0], addrs[1]);
+    let mut expected = String::new();
+    for i in 0..20 {
+        let key = format!("key{:02}", i);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &key, "value", "--shards", &shards])
+            .current_dir(&temp_dirs[0])
+            .assert()
+            .success();
+        expected.push_str(&format!("{} value\n", key));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--shards", &shards])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout(expected.clone());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "key05", "key08", "--limit", "2", "--addr", addrs[1]])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success();
+
+    let shards = format!("{},{}", shards, addrs[2]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rebalance", "--shards", &shards])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout(contains(" keys moved\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rebalance", "--shards", &shards])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout("0 keys moved\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--shards", &shards])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout(expected);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key07", "--shards", &shards])
+        .current_dir(&temp_dirs[0])
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn check_namespaces(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--ns", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--ns", "team-a", "mset", "key2", "value3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--ns", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 value2\nkey2 value3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--ns", "team-b", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["txn", "--ns", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key2\nset key3 value4\n")
+        .assert()
+        .success()
+        .stdout("value3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("keys: 4\n")
+                .and(contains("namespace \"\": 1 keys"))
+                .and(contains("namespace \"team-a\": 3 keys")),
+        );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["drop-namespace", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["drop-namespace", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("NamespaceNotFound"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--ns", "team-a", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client --ns` should keep keys of namespaces apart, and `drop-namespace`
+// should remove a namespace with its keys.
+#[test]
+fn cli_namespaces() {
+    check_namespaces("kvs", "127.0.0.1:4029");
+    check_namespaces("sled", "127.0.0.1:4030");
+}
+
+// `kvs-server --auth-config` should require `kvs-client --token` and check
+// permissions of the token, and replicas should authenticate with `--primary-token`.
+#[test]
+fn cli_auth() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("auth.json");
+    fs::write(
+        &config,
+        r#"{"tokens": [
+            {"token": "admin-token", "grants": [{"permission": "admin"}]},
+            {"token": "app-token", "grants": [{"prefix": "app/", "permission": "write"}]}
+        ]}"#,
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4031",
+            "--auth-config",
+        ])
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "app/key1", "value1", "--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "app/key1", "value1", "--token", "wrong-token"])
+        .args(&["--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("PermissionDenied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "app/key1", "value1", "--token", "app-token"])
+        .args(&["--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--token", "app-token"])
+        .args(&["--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "app/key1", "--token", "app-token"])
+        .args(&["--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4032"])
+        .args(&[
+            "--replica-of",
+            "127.0.0.1:4031",
+            "--primary-token",
+            "admin-token",
+        ])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        replica.kill().expect("replica exited before killed");
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "app/key1", "--addr", "127.0.0.1:4032"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-server --addr unix:PATH --socket-mode` should serve `kvs-client` on a
+// Unix domain socket with the given mode, and replace the socket left behind
+// after being killed.
+#[cfg(unix)]
+#[test]
+fn cli_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let other_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", socket.display());
+    let start_server = || {
+        let (sender, receiver) = mpsc::sync_channel::<()>(0);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", &addr, "--socket-mode", "600"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            child.wait().unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+        (sender, handle)
+    };
+
+    let (sender, handle) = start_server();
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let (sender, handle) = start_server();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // a socket in use isn't replaced
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", &addr])
+        .current_dir(&other_dir)
+        .assert()
+        .failure()
+        .stderr(contains("is in use"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,240 @@
+use assert_cmd::prelude::*;
+use kvs::encryption::{Keyring, KEY_ENV};
+use kvs::error::KvStoreError;
+use kvs::verify;
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+const KEY1: &str = "k1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY2: &str = "k2:202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f";
+/// same id as `KEY1`, another key
+const WRONG_KEY1: &str = "k1:ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+fn open(path: &Path, keys: Option<&str>) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        keyring: keys.map(Keyring::parse).transpose()?,
+        ..Default::default()
+    };
+    KvStore::open_with_options(path, options)
+}
+
+/// whether any generation in `path` contains `bytes`
+fn contains_plaintext(path: &Path, bytes: &[u8]) -> bool {
+    WalkDir::new(path)
+        .into_iter()
+        .map(|x| x.unwrap().into_path())
+        .filter(|x| x.is_file())
+        .any(|x| {
+            fs::read(x)
+                .unwrap()
+                .windows(bytes.len())
+                .any(|window| window == bytes)
+        })
+}
+
+// Keyrings should hold keys of 32 bytes in hex with unique ids
+#[test]
+fn parse_keyring() -> Result<()> {
+    let keyring = Keyring::parse(&format!("{}\n{}\n", KEY2, KEY1))?;
+    assert_eq!(keyring.active_id(), "k2");
+    assert_eq!(
+        Keyring::parse(&format!("{},{}", KEY1, KEY2))?.active_id(),
+        "k1"
+    );
+    assert!(Keyring::parse("").is_err());
+    assert!(Keyring::parse("k1").is_err());
+    assert!(Keyring::parse("k1:0011").is_err());
+    assert!(Keyring::parse(&format!("{}\n{}", KEY1, WRONG_KEY1)).is_err());
+    Ok(())
+}
+
+// Records should be unreadable without the key, and a missing or wrong key
+// should be reported on open
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), Some(KEY1))?;
+    store.set("key1".to_owned(), "secret-value1".to_owned())?;
+    store.set("key2".to_owned(), "secret-value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value1".to_owned())
+    );
+    drop(store);
+    assert!(!contains_plaintext(temp_dir.path(), b"secret-value"));
+    assert!(!contains_plaintext(temp_dir.path(), b"key1"));
+
+    let mut store = open(temp_dir.path(), Some(KEY1))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let snapshot_value = store.snapshot()?.get("key1".to_owned())?;
+    assert_eq!(snapshot_value, Some("secret-value1".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        open(temp_dir.path(), None),
+        Err(KvStoreError::EncryptionKeyNotFound { id }) if id == "k1"
+    ));
+    assert!(matches!(
+        open(temp_dir.path(), Some(KEY2)),
+        Err(KvStoreError::EncryptionKeyNotFound { .. })
+    ));
+    assert!(matches!(
+        open(temp_dir.path(), Some(WRONG_KEY1)),
+        Err(KvStoreError::WrongEncryptionKey { id }) if id == "k1"
+    ));
+    Ok(())
+}
+
+// Compaction should rewrite plain generations and blob files, and those
+// encrypted with older keys, with the active key
+#[test]
+fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), None)?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions {
+        keyring: Some(Keyring::parse(KEY1)?),
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("blob".to_owned(), "b".repeat(4096))?;
+    drop(store);
+
+    let keys = format!("{}\n{}", KEY2, KEY1);
+    let mut store = open(temp_dir.path(), Some(&keys))?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    // overwrite enough to trigger compaction
+    for iter in 0..10000 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    drop(store);
+
+    let mut store = open(temp_dir.path(), Some(KEY2))?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("9999".to_owned()));
+    assert_eq!(store.get("blob".to_owned())?, Some("b".repeat(4096)));
+    drop(store);
+
+    let report = verify::verify(temp_dir.path(), Some(&Keyring::parse(KEY2)?))?;
+    assert_eq!(report.problems(), 0);
+    assert!(report
+        .generations
+        .iter()
+        .all(|x| x.key_id.as_deref() == Some("k2")));
+    Ok(())
+}
+
+// Verify should report generations with a missing or wrong key instead of
+// corrupt records
+#[test]
+fn verify_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), Some(KEY1))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let blob_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        keyring: Some(Keyring::parse(KEY1)?),
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(blob_dir.path(), options)?;
+    store.set("blob".to_owned(), "b".repeat(4096))?;
+    drop(store);
+
+    let report = verify::verify(temp_dir.path(), Some(&Keyring::parse(KEY1)?))?;
+    assert_eq!(report.problems(), 0);
+    assert_eq!(report.generations[0].records, 1);
+    assert!(report.live_bytes() > 0);
+
+    let report = verify::verify(temp_dir.path(), Some(&Keyring::parse(WRONG_KEY1)?))?;
+    assert_eq!(report.problems(), report.generations.len());
+    for g in &report.generations {
+        assert_eq!(g.corrupt, None);
+        assert_eq!(g.key_error.as_deref(), Some("wrong encryption key: k1"));
+    }
+    let report = verify::verify(temp_dir.path(), None)?;
+    assert_eq!(
+        report.generations[0].key_error.as_deref(),
+        Some("encryption key not found: k1")
+    );
+
+    // blob files with a missing key are reported too
+    let report = verify::verify(blob_dir.path(), Some(&Keyring::parse(KEY1)?))?;
+    assert_eq!(report.problems(), 0);
+    let report = verify::verify(blob_dir.path(), Some(&Keyring::parse(KEY2)?))?;
+    assert_eq!(
+        report.blob_errors,
+        vec![(0, "encryption key not found: k1".to_owned())]
+    );
+    assert_eq!(report.problems(), report.generations.len() + 1);
+    Ok(())
+}
+
+// `kvs` should read keys from `--encryption-key-file` or `KVS_ENCRYPTION_KEY`,
+// and `kvs verify` should fail on a wrong key
+#[test]
+fn cli_encryption() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let key_file = temp_dir.path().join("keys");
+    fs::write(&key_file, format!("{}\n", KEY1)).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--encryption-key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env(KEY_ENV, KEY1)
+        .current_dir(&data_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env_remove(KEY_ENV)
+        .current_dir(&data_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", data_dir.to_str().unwrap()])
+        .env(KEY_ENV, KEY1)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(", key k1").and(contains("OK")));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", data_dir.to_str().unwrap()])
+        .env(KEY_ENV, WRONG_KEY1)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("wrong encryption key: k1"));
+}
@@ -1,4 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::compression::Compression;
+use kvs::dump::{self, DumpFormat};
+use kvs::error::KvStoreError;
+use kvs::verify::{self, LogFilter};
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, RestorePoint, Result, SledEngine, Transaction, WatchEvent,
+    DEFAULT_NAMESPACE,
+};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +136,1339 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should only overwrite value when the current value matches
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert!(store.set_if("key1".to_owned(), None, "value1".to_owned())?);
+    assert!(!store.set_if("key1".to_owned(), None, "value2".to_owned())?);
+    assert!(!store.set_if(
+        "key1".to_owned(),
+        Some("value0".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert!(store.set_if(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        "value2".to_owned()
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn set_if_exists_or_not() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if_exists("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.set_if_not_exists("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_not_exists("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set_if_exists("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should return increasing versions and accept them for conditional writes
+#[test]
+fn versioned_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.get_versioned("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, version1) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let (_, version2) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert!(version2 > version1);
+
+    assert!(!store.set_if_version("key1".to_owned(), Some(version1), "value4".to_owned())?);
+    assert!(!store.set_if_version("key1".to_owned(), None, "value4".to_owned())?);
+    assert!(store.set_if_version("key3".to_owned(), None, "value4".to_owned())?);
+    assert!(store.set_if_version("key1".to_owned(), Some(version2), "value4".to_owned())?);
+    let (value, version3) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value4");
+
+    // Open from disk again and check versions are kept
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value4".to_owned(), version3))
+    );
+    store.set("key1".to_owned(), "value5".to_owned())?;
+    let (_, version4) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert!(version4 > version3);
+
+    // versions should keep increasing after compaction dropped the latest write,
+    // which leaves a single generation
+    let generations = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter(|x| x.as_ref().unwrap().path().extension() == Some("db".as_ref()))
+            .count()
+    };
+    let mut version5 = 0;
+    while generations() > 1 {
+        store.set("key2".to_owned(), "value6".to_owned())?;
+        version5 = store.get_versioned("key2".to_owned())?.unwrap().1;
+        store.remove("key2".to_owned())?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value7".to_owned())?;
+    let (_, version6) = store.get_versioned("key2".to_owned())?.unwrap();
+    assert!(version6 > version5 + 1);
+
+    Ok(())
+}
+
+// Should drop keys after their time to live
+#[test]
+fn expire_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(100),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.ttl("key1".to_owned())? <= Some(Duration::from_millis(200)));
+    assert_eq!(store.ttl("key3".to_owned())?, None);
+    assert!(store.ttl("key4".to_owned()).is_err());
+
+    store.persist("key2".to_owned())?;
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // Open from disk again and check expired keys are dropped
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    drop(store);
+    thread::sleep(Duration::from_millis(300));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn purge_expired_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set("key10".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.purge_expired()?, 10);
+    assert_eq!(store.purge_expired()?, 0);
+    assert_eq!(store.get("key10".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should get and set multiple keys at once
+#[test]
+fn get_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let pairs = (0..100)
+        .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+        .collect();
+    store.set_many(pairs)?;
+    store.set("key50".to_owned(), "value".to_owned())?;
+
+    let keys = vec![
+        "key99".to_owned(),
+        "key".to_owned(),
+        "key50".to_owned(),
+        "key0".to_owned(),
+    ];
+    let expected = vec![
+        Some("value99".to_owned()),
+        None,
+        Some("value".to_owned()),
+        Some("value0".to_owned()),
+    ];
+    assert_eq!(store.get_many(keys.clone())?, expected);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_many(keys)?, expected);
+
+    Ok(())
+}
+
+// Reading an old record should not affect following writes
+#[test]
+fn read_between_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.set("key1000".to_owned(), "value1000".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1001 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Should store arbitrary bytes as keys and values
+#[test]
+fn binary_key_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0, 159, 146, 150, 255];
+    let value = (0..=255).collect::<Vec<u8>>();
+    store.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    store.set_bytes(b"key1".to_vec(), vec![255, 254])?;
+    assert!(store.get("key1".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+// Generations written in JSON should be migrated on open, and generations that
+// can't be decoded should fail opening the store
+#[test]
+fn json_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("0.db"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2","seq":2}}{"Remove":{"key":"key1","seq":3}}{"Set":{"key":"ke"#,
+    )?;
+    std::fs::write(
+        temp_dir.path().join("1.db"),
+        r#"{"Set":{"key":"key3","value":"value3","seq":4}}"#,
+    )?;
+
+    for _ in 0..2 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(
+            store.get_versioned("key2".to_owned())?,
+            Some(("value2".to_owned(), 2))
+        );
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    let (_, version) = store.get_versioned("key4".to_owned())?.unwrap();
+    assert!(version > 4);
+    drop(store);
+
+    std::fs::write(temp_dir.path().join("9.db"), [0xff, 0x00, 0x01])?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Snapshot should not see later writes, and should survive compaction
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut snapshot = store.snapshot()?;
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(
+        snapshot.keys_bytes(),
+        vec![b"key1".to_vec(), b"key2".to_vec()]
+    );
+
+    // overwrite enough to trigger compaction
+    for iter in 0..10000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("9999".to_owned()));
+
+    // compacted generations are removed after snapshot is dropped
+    drop(snapshot);
+    let obsolete = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|x| x.as_ref().unwrap().path().extension() == Some("obsolete".as_ref()))
+        .count();
+    assert_eq!(obsolete, 0);
+
+    Ok(())
+}
+
+// Scan should return live pairs in range up to the limit on both engines
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        Box::new(KvStore::open(temp_dir.path().join("kvs"))?),
+        Box::new(SledEngine::open(temp_dir.path().join("sled"))?),
+    ];
+    for mut engine in engines {
+        assert_eq!(engine.scan_bytes(vec![], None, None)?, vec![]);
+        for key in &["a", "b", "c", "d"] {
+            engine.set(key.to_string(), format!("value_{}", key))?;
+        }
+        engine.set_with_ttl("bb".to_owned(), "gone".to_owned(), Duration::from_millis(1))?;
+        engine.remove("c".to_owned())?;
+        thread::sleep(Duration::from_millis(10));
+
+        let pair = |key: &str| {
+            (
+                key.as_bytes().to_vec(),
+                format!("value_{}", key).into_bytes(),
+            )
+        };
+        assert_eq!(
+            engine.scan_bytes(b"b".to_vec(), None, None)?,
+            vec![pair("b"), pair("d")]
+        );
+        assert_eq!(
+            engine.scan_bytes(b"a".to_vec(), Some(b"d".to_vec()), None)?,
+            vec![pair("a"), pair("b")]
+        );
+        assert_eq!(
+            engine.scan_bytes(vec![], None, Some(2))?,
+            vec![pair("a"), pair("b")]
+        );
+        assert_eq!(
+            engine.scan_bytes(b"d".to_vec(), Some(b"a".to_vec()), None)?,
+            vec![]
+        );
+    }
+
+    Ok(())
+}
+
+// Transaction should only commit if keys read haven't been changed by others
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "1".to_owned())?;
+    store.set("key2".to_owned(), "2".to_owned())?;
+
+    let mut txn = Transaction::begin();
+    let value1 = txn.get(&mut store, "key1".to_owned())?.unwrap();
+    txn.set("key3".to_owned(), value1);
+    txn.remove(&mut store, "key2".to_owned())?;
+    assert_eq!(txn.get(&mut store, "key2".to_owned())?, None);
+    assert_eq!(
+        txn.get(&mut store, "key3".to_owned())?,
+        Some("1".to_owned())
+    );
+    assert!(txn.remove(&mut store, "key4".to_owned()).is_err());
+    // writes are invisible before commit
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(txn.commit(&mut store)?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("1".to_owned()));
+
+    // conflict with a write after read
+    let mut txn = Transaction::begin();
+    txn.get(&mut store, "key1".to_owned())?;
+    txn.set("key2".to_owned(), "3".to_owned());
+    store.set("key1".to_owned(), "4".to_owned())?;
+    assert!(!txn.commit(&mut store)?);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // conflict with a key created after read
+    let mut txn = Transaction::begin();
+    assert_eq!(txn.get(&mut store, "key5".to_owned())?, None);
+    txn.set("key5".to_owned(), "5".to_owned());
+    store.set("key5".to_owned(), "6".to_owned())?;
+    assert!(!txn.commit(&mut store)?);
+    assert_eq!(store.get("key5".to_owned())?, Some("6".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Checkpoint should be a consistent copy that can be opened as a store
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.checkpoint(checkpoint_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(store.checkpoint(checkpoint_dir.path()).is_err());
+
+    let mut checkpoint = KvStore::open(checkpoint_dir.path())?;
+    assert_eq!(
+        checkpoint.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(checkpoint.get("key2".to_owned())?, None);
+    assert_eq!(checkpoint.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Versions should keep increasing in a checkpoint of sled engine
+#[test]
+fn sled_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = SledEngine::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let (_, version) = engine.get_versioned("key1".to_owned())?.unwrap();
+    engine.checkpoint(checkpoint_dir.path())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let mut checkpoint = SledEngine::open(checkpoint_dir.path())?;
+    assert_eq!(
+        checkpoint.get_versioned("key1".to_owned())?,
+        Some(("value1".to_owned(), version))
+    );
+    checkpoint.set("key1".to_owned(), "value3".to_owned())?;
+    let (_, new_version) = checkpoint.get_versioned("key1".to_owned())?.unwrap();
+    assert!(new_version > version);
+
+    Ok(())
+}
+
+// Plain values written before sled engine prefixed headers should be migrated
+// on open, and values without header found later should be reported
+#[test]
+fn sled_plain_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = checkpoint_dir.path().join("checkpoint");
+
+    let db = sled::open(temp_dir.path())?;
+    db.insert("key1", "value1")?;
+    db.insert("key2", "")?;
+    db.flush()?;
+    drop(db);
+    let mut engine = wait_unlocked(|| SledEngine::open(temp_dir.path()))?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("".to_owned()));
+    assert!(engine.get_versioned("key1".to_owned())?.is_some());
+    engine.checkpoint(&checkpoint_dir)?;
+
+    // values of a database with headers are not migrated again
+    let db = wait_unlocked(|| Ok(sled::open(&checkpoint_dir)?))?;
+    db.insert("key3", "short")?;
+    db.flush()?;
+    drop(db);
+    let mut checkpoint = wait_unlocked(|| SledEngine::open(&checkpoint_dir))?;
+    assert_eq!(
+        checkpoint.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        checkpoint.get("key3".to_owned()),
+        Err(KvStoreError::InvalidRecord { .. })
+    ));
+    Ok(())
+}
+
+// sled releases the lock of a dropped database in background, so opening it
+// again is retried for a while
+fn wait_unlocked<T>(open: impl Fn() -> Result<T>) -> Result<T> {
+    for _ in 0..100 {
+        if let Ok(x) = open() {
+            return Ok(x);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    open()
+}
+
+// Store should be restored as of a sequence number or time from archived generations
+#[test]
+fn restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        archive_dir: Some(archive_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (_, version) = store.get_versioned("key2".to_owned())?.unwrap();
+    thread::sleep(Duration::from_millis(10));
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    thread::sleep(Duration::from_millis(10));
+    store.remove("key2".to_owned())?;
+    // overwrite enough to trigger compaction
+    for iter in 0..10000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    let restore = |until, name| -> Result<KvStore> {
+        let dest = restore_dir.path().join(name);
+        KvStore::restore(temp_dir.path(), Some(archive_dir.path()), &dest, until)?;
+        KvStore::open(dest)
+    };
+
+    let mut store = restore(RestorePoint::Seq(version), "seq")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_versioned("key2".to_owned())?.unwrap().1, version);
+
+    let mut store = restore(RestorePoint::Time(time), "time")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let mut store = restore(RestorePoint::Seq(version + 1), "removed")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let mut store = restore(RestorePoint::Latest, "latest")?;
+    assert_eq!(store.get("key1".to_owned())?, Some("9999".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // without archive, only the state kept by compaction is available
+    let dest = restore_dir.path().join("no-archive");
+    KvStore::restore(temp_dir.path(), None, &dest, RestorePoint::Seq(version))?;
+    let mut store = KvStore::open(dest)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Dump should be loaded into any engine, keeping binary data and expiry
+#[test]
+fn dump_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes(vec![0, 255], vec![255, 1])?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(10),
+    )?;
+    thread::sleep(Duration::from_millis(20));
+
+    let mut json = vec![];
+    assert_eq!(dump::dump(&mut store, &mut json, DumpFormat::Json)?, 3);
+    let text = String::from_utf8(json.clone()).unwrap();
+    assert!(text.starts_with("{\"format\":\"kvs-dump\",\"version\":2}\n"));
+    assert!(text.contains("{\"key\":{\"hex\":\"00ff\"},\"value\":{\"hex\":\"ff01\"}}"));
+    assert!(text.contains("{\"key\":\"key1\",\"value\":\"value1\"}"));
+
+    let mut binary = vec![];
+    assert_eq!(dump::dump(&mut store, &mut binary, DumpFormat::Binary)?, 3);
+
+    for (name, data, format) in [
+        ("json", json, DumpFormat::Json),
+        ("binary", binary, DumpFormat::Binary),
+    ]
+    .iter()
+    {
+        let mut engine = SledEngine::open(temp_dir.path().join(name))?;
+        assert_eq!(dump::load(&mut engine, data.as_slice(), *format)?, 3);
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get_bytes(vec![0, 255])?, Some(vec![255, 1]));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert!(engine.ttl("key2".to_owned())?.unwrap() <= Duration::from_secs(60));
+        assert_eq!(engine.get("key3".to_owned())?, None);
+    }
+
+    let invalid = "{\"format\":\"kvs-dump\",\"version\":100}\n";
+    assert!(dump::load(&mut store, invalid.as_bytes(), DumpFormat::Json).is_err());
+
+    let mut engine = SledEngine::open(temp_dir.path().join("migrate"))?;
+    assert_eq!(dump::migrate(&mut store, &mut engine)?, 3);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get_bytes(vec![0, 255])?, Some(vec![255, 1]));
+
+    Ok(())
+}
+
+// Dump, load and migrate should keep keys of every namespace in their namespace
+#[test]
+fn dump_load_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path().join("kvs"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.use_namespace("users")?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.use_namespace(DEFAULT_NAMESPACE)?;
+
+    let mut json = vec![];
+    assert_eq!(dump::dump(&mut store, &mut json, DumpFormat::Json)?, 3);
+    let text = String::from_utf8(json.clone()).unwrap();
+    assert!(text.contains("{\"key\":\"key1\",\"value\":\"value1\"}"));
+    assert!(text.contains("{\"namespace\":\"users\",\"key\":\"key1\",\"value\":\"value2\"}"));
+    let mut binary = vec![];
+    assert_eq!(dump::dump(&mut store, &mut binary, DumpFormat::Binary)?, 3);
+
+    let check = |engine: &mut dyn KvsEngine| -> Result<()> {
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, None);
+        engine.use_namespace("users")?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+        engine.use_namespace(DEFAULT_NAMESPACE)
+    };
+    for (name, data, format) in [
+        ("json", json, DumpFormat::Json),
+        ("binary", binary, DumpFormat::Binary),
+    ] {
+        let mut engine = KvStore::open(temp_dir.path().join(name))?;
+        assert_eq!(dump::load(&mut engine, data.as_slice(), format)?, 3);
+        check(&mut engine)?;
+        let mut engine = SledEngine::open(temp_dir.path().join(name).join("sled"))?;
+        assert_eq!(dump::load(&mut engine, data.as_slice(), format)?, 3);
+        check(&mut engine)?;
+    }
+
+    // dumps without namespaces are loaded into the default namespace
+    let old = "{\"format\":\"kvs-dump\",\"version\":1}\n{\"key\":\"key3\",\"value\":\"value4\"}\n";
+    let mut engine = SledEngine::open(temp_dir.path().join("old"))?;
+    assert_eq!(
+        dump::load(&mut engine, old.as_bytes(), DumpFormat::Json)?,
+        1
+    );
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    let mut engine = SledEngine::open(temp_dir.path().join("migrate"))?;
+    assert_eq!(dump::migrate(&mut store, &mut engine)?, 3);
+    check(&mut engine)?;
+    let mut store = KvStore::open(temp_dir.path().join("migrate_back"))?;
+    assert_eq!(dump::migrate(&mut engine, &mut store)?, 3);
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Verify should report live bytes, corrupt ranges and orphaned files
+#[test]
+fn verify_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = verify::verify(temp_dir.path(), None)?;
+    assert_eq!(report.problems(), 0);
+    assert_eq!(report.generations.len(), 1);
+    assert_eq!(report.generations[0].records, 4);
+    assert!(report.live_bytes() > 0);
+    assert!(report.live_bytes() < report.total_bytes());
+
+    // append a truncated record to the last generation
+    let generation = report.generations[0].generation;
+    let path = temp_dir.path().join(format!("{}.db", generation));
+    let mut file = OpenOptions::new().append(true).open(&path)?;
+    file.write_all(&[0xa1, 0x63])?;
+    drop(file);
+    std::fs::write(temp_dir.path().join("3.obsolete"), b"")?;
+
+    let report = verify::verify(temp_dir.path(), None)?;
+    assert_eq!(report.problems(), 2);
+    let total_bytes = report.generations[0].total_bytes;
+    assert_eq!(
+        report.generations[0].corrupt,
+        Some((total_bytes - 2, total_bytes))
+    );
+    assert_eq!(report.orphaned, vec![temp_dir.path().join("3.obsolete")]);
+    assert_eq!(report.generations[0].records, 4);
+
+    Ok(())
+}
+
+// Log dump should print records matching filter
+#[test]
+fn log_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let report = verify::verify(temp_dir.path(), None)?;
+    let path = temp_dir
+        .path()
+        .join(format!("{}.db", report.generations[0].generation));
+
+    let mut out = vec![];
+    assert_eq!(
+        verify::log_dump(&path, None, &LogFilter::default(), &mut out)?,
+        3
+    );
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("0 len="));
+    assert!(lines[0].contains(" set seq=1 "));
+    assert!(lines[0].ends_with(" key=key1 value_size=6"));
+    assert!(lines[2].contains(" remove seq=3 "));
+
+    let filter = LogFilter {
+        key: Some(b"key1".to_vec()),
+        start: Some(1),
+        end: None,
+    };
+    let mut out = vec![];
+    assert_eq!(verify::log_dump(&path, None, &filter, &mut out)?, 1);
+    assert!(String::from_utf8(out).unwrap().contains(" remove seq=3 "));
+
+    Ok(())
+}
+
+// Stats should count keys, bytes, operations and compactions
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get_many(vec!["key1".to_owned(), "key3".to_owned()])?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.reads, 3);
+    assert_eq!(stats.writes, 3);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction_ms, None);
+    assert_eq!(stats.generations.len(), 1);
+    let generation = &stats.generations[0];
+    assert_eq!(generation.total_bytes, stats.total_bytes);
+    assert_eq!(generation.live_bytes, stats.live_bytes);
+    // one of three records of the same size is overwritten
+    assert_eq!(stats.live_bytes * 3, stats.total_bytes * 2);
+    assert!((generation.dead_ratio() - 1.0 / 3.0).abs() < 1e-9);
+
+    for i in 0..5000 {
+        store.set("key1".to_owned(), i.to_string())?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.last_compaction_ms.is_some());
+    assert!(stats.live_bytes <= stats.total_bytes);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledEngine::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.reads, 1);
+    assert_eq!(stats.writes, 3);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.generations.is_empty());
+
+    Ok(())
+}
+
+// Values at least as long as the threshold should be compressed, and read back
+// regardless of the options the store is opened with
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compression: Compression::Lz4,
+        compression_threshold: 64,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let large = format!("[{}]", vec![r#"{"name":"value","id":1}"#; 1000].join(","));
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    // incompressible values are stored as is
+    let random = (0..1000u32)
+        .map(|x| (x.wrapping_mul(2_654_435_761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    store.set_bytes(b"random".to_vec(), random.clone())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let stats = store.stats()?;
+    assert!(stats.total_bytes < large.len() as u64 / 10);
+    assert!(stats.uncompressed_live_bytes > large.len() as u64 + random.len() as u64);
+    assert!(stats.compression_ratio() > 2.0);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.get_bytes(b"random".to_vec())?, Some(random));
+    let values = store.get_many(vec!["large".to_owned(), "small".to_owned()])?;
+    assert_eq!(values, vec![Some(large.clone()), Some("value".to_owned())]);
+    let mut snapshot = store.snapshot()?;
+    assert_eq!(snapshot.get("large".to_owned())?, Some(large.clone()));
+    assert!(store.stats()?.compression_ratio() > 2.0);
+
+    // compaction keeps values compressed
+    for i in 0..5000 {
+        store.set("small".to_owned(), i.to_string())?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.compression_ratio() > 2.0);
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    Ok(())
+}
+
+// Values at least as long as the blob threshold should be stored in blob files,
+// which compaction of generations doesn't copy, and which are compacted once
+// mostly garbage
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        archive_dir: Some(archive_dir.path().to_path_buf()),
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let large = |i: usize| format!("{}{}", i, "x".repeat(100_000));
+    for i in 0..3 {
+        store.set(format!("key{}", i), large(i))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    let (_, version) = store.get_versioned("small".to_owned())?.unwrap();
+    let stats = store.stats()?;
+    assert_eq!(stats.blob_files.len(), 1);
+    assert!(stats.generations.iter().map(|x| x.total_bytes).sum::<u64>() < 1000);
+    assert!(stats.live_bytes > 300_000);
+    let blob_bytes = stats.blob_files[0].total_bytes;
+
+    // compaction of generations leaves blob files as is
+    for i in 0..5000 {
+        store.set("small".to_owned(), i.to_string())?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.blob_compactions, 0);
+    assert_eq!(stats.blob_files[0].total_bytes, blob_bytes);
+    assert_eq!(store.get("key1".to_owned())?, Some(large(1)));
+
+    // blob files are compacted once more than half of their bytes are garbage
+    let mut snapshot = store.snapshot()?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), large(i + 10))?;
+    }
+    assert_eq!(store.stats()?.blob_compactions, 0);
+    store.remove("key0".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.blob_compactions, 1);
+    assert_eq!(stats.blob_files.len(), 1);
+    assert_eq!(stats.blob_files[0].dead_ratio(), 0.0);
+    assert_eq!(store.get("key1".to_owned())?, Some(large(11)));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some(large(1)));
+    drop(snapshot);
+    let checkpoint_dir = restore_dir.path().join("checkpoint");
+    store.checkpoint(&checkpoint_dir)?;
+    drop(store);
+
+    // values are read back regardless of the options the store is opened with
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    let values = store.get_many(vec!["key1".to_owned(), "key2".to_owned()])?;
+    assert_eq!(values, vec![Some(large(11)), Some(large(12))]);
+    drop(store);
+    assert_eq!(verify::verify(temp_dir.path(), None)?.problems(), 0);
+    let mut checkpoint = KvStore::open(&checkpoint_dir)?;
+    assert_eq!(checkpoint.get("key2".to_owned())?, Some(large(12)));
+
+    // values in blob files dropped by compaction are only restored from archive
+    let dest = restore_dir.path().join("archive");
+    KvStore::restore(
+        temp_dir.path(),
+        Some(archive_dir.path()),
+        &dest,
+        RestorePoint::Seq(version),
+    )?;
+    let mut restored = KvStore::open(dest)?;
+    assert_eq!(restored.get("key0".to_owned())?, Some(large(0)));
+    assert_eq!(restored.get("key1".to_owned())?, Some(large(1)));
+    let dest = restore_dir.path().join("no-archive");
+    assert!(matches!(
+        KvStore::restore(temp_dir.path(), None, &dest, RestorePoint::Seq(version)),
+        Err(KvStoreError::BlobFileNotFound { .. })
+    ));
+    let dest = restore_dir.path().join("latest");
+    KvStore::restore(temp_dir.path(), None, &dest, RestorePoint::Latest)?;
+    let mut restored = KvStore::open(dest)?;
+    assert_eq!(restored.get("key0".to_owned())?, None);
+    assert_eq!(restored.get("key1".to_owned())?, Some(large(11)));
+    Ok(())
+}
+
+// Compaction of blob files should keep appending to the active blob file
+// unless it's compacted too
+#[test]
+fn blob_compaction_active_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("old".to_owned(), "x".repeat(300_000))?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("live".to_owned(), "y".repeat(100_000))?;
+    store.set("old".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.blob_compactions, 1);
+    store.set("new".to_owned(), "z".repeat(100_000))?;
+    let stats = store.stats()?;
+    let files = stats
+        .blob_files
+        .iter()
+        .map(|x| x.generation)
+        .collect::<Vec<_>>();
+    assert_eq!(files, vec![1]);
+    assert_eq!(store.get("live".to_owned())?, Some("y".repeat(100_000)));
+    assert_eq!(store.get("new".to_owned())?, Some("z".repeat(100_000)));
+    Ok(())
+}
+
+// Blob files holding only values of expired keys should be compacted, whether
+// the keys are dropped on read or by purge
+#[test]
+fn blob_compaction_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..Default::default()
+    };
+    let blob_files = |store: &mut KvStore| -> Result<usize> { Ok(store.stats()?.blob_files.len()) };
+    let ttl = Duration::from_millis(50);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key1".to_owned(), "x".repeat(300_000), ttl)?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    thread::sleep(ttl * 2);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    // overwrites try compaction
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.blob_compactions, 1);
+    assert_eq!(blob_files(&mut store)?, 0);
+
+    store.set_with_ttl("key3".to_owned(), "y".repeat(300_000), ttl)?;
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    thread::sleep(ttl * 2);
+    assert_eq!(store.purge_expired()?, 1);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats()?.blob_compactions, 1);
+    assert_eq!(blob_files(&mut store)?, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn check_watch(engine: &mut dyn KvsEngine) -> Result<()> {
+    let receiver = engine.watch(b"key".to_vec(), 100)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("other".to_owned(), "value2".to_owned())?;
+    engine.set_many(vec![("key2".to_owned(), "value3".to_owned())])?;
+    engine.remove("key1".to_owned())?;
+    engine.set_with_ttl(
+        "key3".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    let mut transaction = Transaction::begin();
+    transaction.remove(engine, "key2".to_owned())?;
+    transaction.commit(engine)?;
+
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    let keys = events
+        .iter()
+        .map(|x| String::from_utf8(x.key().to_vec()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec!["key1", "key2", "key1", "key3", "key2"]);
+    assert!(events.windows(2).all(|x| x[0].seq() < x[1].seq()));
+    match &events[0] {
+        WatchEvent::Set {
+            value, expires_at, ..
+        } => {
+            assert_eq!(value, b"value1");
+            assert_eq!(*expires_at, None);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert_eq!(
+        events[0].seq(),
+        engine.get_versioned("other".to_owned())?.unwrap().1 - 1
+    );
+    if let WatchEvent::Set { expires_at, .. } = &events[3] {
+        assert!(expires_at.is_some());
+    } else {
+        panic!("unexpected event {:?}", events[3]);
+    }
+    if let WatchEvent::Remove { .. } = &events[4] {
+    } else {
+        panic!("unexpected event {:?}", events[4]);
+    }
+
+    // watcher is dropped once its receiver is gone
+    drop(receiver);
+    engine.set("key4".to_owned(), "value5".to_owned())?;
+    Ok(())
+}
+
+// Watchers should receive changes of keys with their prefix
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch(&mut KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch(&mut SledEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+fn check_apply_event(primary: &mut dyn KvsEngine, replica: &mut dyn KvsEngine) -> Result<()> {
+    let receiver = primary.watch(vec![], 100)?;
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    primary.remove("key1".to_owned())?;
+    primary.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    for event in receiver.try_iter() {
+        replica.apply_event(event)?;
+    }
+
+    for key in &["key1", "key2", "key3"] {
+        assert_eq!(
+            replica.get_versioned(key.to_string())?,
+            primary.get_versioned(key.to_string())?
+        );
+    }
+    assert!(replica.ttl("key3".to_owned())?.is_some());
+
+    // writes after promotion get sequence numbers above replicated ones
+    let (_, seq) = primary.get_versioned("key3".to_owned())?.unwrap();
+    replica.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(replica.get_versioned("key4".to_owned())?.unwrap().1 > seq);
+    Ok(())
+}
+
+// Replicated changes should be applied with their sequence numbers
+#[test]
+fn apply_event() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_event(
+        &mut KvStore::open(primary_dir.path())?,
+        &mut KvStore::open(replica_dir.path())?,
+    )?;
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_event(
+        &mut SledEngine::open(primary_dir.path())?,
+        &mut SledEngine::open(replica_dir.path())?,
+    )?;
+    Ok(())
+}
+
+fn check_namespaces(engine: &mut dyn KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.use_namespace("a")?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    let receiver = engine.watch(vec![], 100)?;
+    engine.use_namespace("b")?;
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    engine.use_namespace(DEFAULT_NAMESPACE)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.use_namespace("a")?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        engine.snapshot()?.keys_bytes(),
+        vec![b"key1".to_vec(), b"key2".to_vec()]
+    );
+    // reads don't create namespaces
+    engine.use_namespace("c")?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.snapshot()?.keys_bytes().is_empty());
+    assert!(!engine.set_if_version("key1".to_owned(), Some(1), "value5".to_owned())?);
+    assert!(engine.commit_bytes(vec![(b"key1".to_vec(), None)], vec![])?);
+    drop(engine.watch(vec![], 100)?);
+    engine.use_namespace("a")?;
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 4);
+    let namespaces = stats
+        .namespaces
+        .iter()
+        .map(|x| (x.name.as_str(), x.keys))
+        .collect::<Vec<_>>();
+    assert_eq!(namespaces, vec![("", 1), ("a", 2), ("b", 1)]);
+
+    engine.drop_namespace("a")?;
+    assert!(matches!(
+        engine.drop_namespace("a"),
+        Err(KvStoreError::NamespaceNotFound { .. })
+    ));
+    assert!(matches!(
+        engine.drop_namespace(DEFAULT_NAMESPACE),
+        Err(KvStoreError::DropDefaultNamespace {})
+    ));
+    // the dropped namespace was in use
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(&events[0], WatchEvent::DropNamespace { namespace, .. } if namespace == "a"));
+
+    engine.use_namespace("a")?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.use_namespace("b")?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value4".to_owned()));
+    engine.use_namespace(DEFAULT_NAMESPACE)?;
+    Ok(())
+}
+
+// Namespaces should keep keys apart, and dropping one should remove its keys only
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(&mut KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_namespaces(&mut SledEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// Namespaces of KvStore should survive reopen, compaction and restore
+#[test]
+fn namespaces_persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.use_namespace("a")?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.use_namespace("b")?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.drop_namespace("b")?;
+    drop(store);
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        store.use_namespace(DEFAULT_NAMESPACE)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.use_namespace("a")?;
+        assert!(store.get("key1".to_owned())?.is_some());
+        let names = store
+            .stats()?
+            .namespaces
+            .into_iter()
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["", "a"]);
+        Ok(())
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    // a namespace created after the drop may reuse its id
+    store.use_namespace("c")?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.use_namespace("a")?;
+    // overwrite enough to trigger compaction
+    for iter in 0..10000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.stats()?.compactions > 0);
+    store.drop_namespace("c")?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("9999".to_owned()));
+    drop(store);
+    assert_eq!(verify::verify(temp_dir.path(), None)?.problems(), 0);
+
+    let dest = restore_dir.path().join("latest");
+    KvStore::restore(temp_dir.path(), None, &dest, RestorePoint::Latest)?;
+    check(&mut KvStore::open(dest)?)?;
+    Ok(())
+}
+
+fn check_apply_namespaces(primary: &mut dyn KvsEngine, replica: &mut dyn KvsEngine) -> Result<()> {
+    let receiver = primary.watch_all(16)?;
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    primary.use_namespace("a")?;
+    primary.set("key1".to_owned(), "value2".to_owned())?;
+    primary.use_namespace("b")?;
+    primary.set("key1".to_owned(), "value3".to_owned())?;
+    primary.drop_namespace("b")?;
+    for event in receiver.try_iter() {
+        replica.apply_event(event)?;
+    }
+
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+    replica.use_namespace("a")?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value2".to_owned()));
+    let names = replica
+        .stats()?
+        .namespaces
+        .into_iter()
+        .map(|x| x.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["", "a"]);
+    Ok(())
+}
+
+// Changes of all namespaces should be watched and replicated
+#[test]
+fn apply_event_namespaces() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_namespaces(
+        &mut KvStore::open(primary_dir.path())?,
+        &mut KvStore::open(replica_dir.path())?,
+    )?;
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_namespaces(
+        &mut SledEngine::open(primary_dir.path())?,
+        &mut SledEngine::open(replica_dir.path())?,
+    )?;
+    Ok(())
+}
+
+fn check_watch_capacity(engine: &mut dyn KvsEngine) -> Result<()> {
+    let all = engine.watch_all(2)?;
+    let prefixed = engine.watch(b"key".to_vec(), 2)?;
+    let other = engine.watch(b"other".to_vec(), 2)?;
+    for i in 0..3 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for receiver in [&all, &prefixed] {
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert!(engine.seq()? >= events[1].seq());
+    }
+    engine.set("key3".to_owned(), "value".to_owned())?;
+    for receiver in [&all, &prefixed] {
+        assert!(matches!(
+            receiver.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        ));
+    }
+    assert!(matches!(other.try_recv(), Err(mpsc::TryRecvError::Empty)));
+    Ok(())
+}
+
+// Watchers should be dropped once too many changes are pending
+#[test]
+fn watch_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch_capacity(&mut KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch_capacity(&mut SledEngine::open(temp_dir.path())?)
+}
@@ -0,0 +1,314 @@
+use kvs::raft::{MemoryTransport, Message, NodeId, RaftConfig, RaftNode, TcpTransport};
+use kvs::{CommandRequest, CommandResponse, KvStore, KvsEngine, Result};
+use slog::{o, Discard, Logger};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+type Engine = Arc<Mutex<Box<dyn KvsEngine>>>;
+
+/// a node of a cluster in this process, with its engine and data directory
+struct TestNode {
+    node: RaftNode,
+    engine: Engine,
+    dir: TempDir,
+}
+
+fn start_node(
+    id: NodeId,
+    members: BTreeMap<NodeId, String>,
+    transport: &Arc<MemoryTransport>,
+) -> Result<TestNode> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Engine = Arc::new(Mutex::new(Box::new(KvStore::open(dir.path())?)));
+    let node = restart_node(id, members, transport, &engine, &dir)?;
+    Ok(TestNode { node, engine, dir })
+}
+
+fn restart_node(
+    id: NodeId,
+    members: BTreeMap<NodeId, String>,
+    transport: &Arc<MemoryTransport>,
+    engine: &Engine,
+    dir: &TempDir,
+) -> Result<RaftNode> {
+    let config = RaftConfig {
+        id,
+        members,
+        dir: Some(dir.path().join("raft")),
+    };
+    let log = Logger::root(Discard, o!());
+    let node = RaftNode::start(config, engine.clone(), transport.clone(), &log)?;
+    transport.register(&node);
+    Ok(node)
+}
+
+fn start_cluster(n: u64) -> Result<(Arc<MemoryTransport>, Vec<TestNode>)> {
+    let transport = Arc::new(MemoryTransport::default());
+    let members: BTreeMap<_, _> = (1..=n).map(|id| (id, format!("node{}", id))).collect();
+    let nodes = (1..=n)
+        .map(|id| start_node(id, members.clone(), &transport))
+        .collect::<Result<_>>()?;
+    Ok((transport, nodes))
+}
+
+/// poll `cond` until it holds, panics after a few seconds
+fn wait_for(mut cond: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !cond() {
+        assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// id of the leader agreed by `nodes`, with a term greater than `term`
+fn wait_for_leader(nodes: &[&TestNode], term: u64) -> NodeId {
+    let mut leader = None;
+    wait_for(|| {
+        let stats = nodes.iter().map(|x| x.node.stats()).collect::<Vec<_>>();
+        leader = stats
+            .iter()
+            .find(|x| x.role == "leader" && x.term > term)
+            .map(|x| x.id);
+        leader.is_some() && stats.iter().all(|x| x.leader == leader)
+    });
+    leader.unwrap()
+}
+
+fn set(node: &RaftNode, key: &str, value: &str) -> CommandResponse {
+    node.request(CommandRequest::Set {
+        key: key.into(),
+        value: value.into(),
+        ttl: None,
+    })
+}
+
+fn get(node: &RaftNode, key: &str) -> Option<Vec<u8>> {
+    match node.request(CommandRequest::Get { key: key.into() }) {
+        CommandResponse::Value { value, .. } => value,
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+fn engine_get(engine: &Engine, key: &str) -> Option<String> {
+    engine.lock().unwrap().get(key.to_owned()).unwrap()
+}
+
+// Writes through any node are applied on every node, and reads from any node
+// see the latest write
+#[test]
+fn raft_replicate() -> Result<()> {
+    let (_transport, nodes) = start_cluster(3)?;
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>(), 0);
+    let follower = nodes.iter().find(|x| x.node.id() != leader).unwrap();
+
+    assert!(matches!(
+        set(&follower.node, "key1", "value1"),
+        CommandResponse::Success {}
+    ));
+    for i in 0..20 {
+        let node = &nodes[i % 3].node;
+        assert!(matches!(
+            set(node, "key2", &i.to_string()),
+            CommandResponse::Success {}
+        ));
+        for x in nodes.iter() {
+            assert_eq!(get(&x.node, "key2"), Some(i.to_string().into_bytes()));
+        }
+    }
+    assert!(matches!(
+        follower.node.request(CommandRequest::SetIfNotExists {
+            key: b"key1".to_vec(),
+            value: b"value2".to_vec()
+        }),
+        CommandResponse::ConditionFailed {}
+    ));
+    // versions differ between nodes
+    assert!(matches!(
+        follower
+            .node
+            .request(CommandRequest::CompareVersionAndSwap {
+                key: b"key1".to_vec(),
+                expected: None,
+                value: b"value2".to_vec()
+            }),
+        CommandResponse::Error { .. }
+    ));
+
+    for x in nodes.iter() {
+        wait_for(|| engine_get(&x.engine, "key2") == Some("19".to_owned()));
+        assert_eq!(engine_get(&x.engine, "key1"), Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A new leader is elected when the leader is partitioned away, and the old
+// leader catches up after the partition heals
+#[test]
+fn raft_leader_failure() -> Result<()> {
+    let (transport, nodes) = start_cluster(3)?;
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>(), 0);
+    assert!(matches!(
+        set(&nodes[0].node, "key1", "value1"),
+        CommandResponse::Success {}
+    ));
+    let term = nodes[leader as usize - 1].node.stats().term;
+
+    transport.isolate(leader);
+    let old = &nodes[leader as usize - 1];
+    // the isolated leader can't commit or confirm reads
+    assert!(matches!(
+        set(&old.node, "key2", "lost"),
+        CommandResponse::Error { .. }
+    ));
+    let rest = nodes
+        .iter()
+        .filter(|x| x.node.id() != leader)
+        .collect::<Vec<_>>();
+    let new_leader = wait_for_leader(&rest, term);
+    assert_ne!(new_leader, leader);
+    assert!(matches!(
+        set(&rest[0].node, "key2", "value2"),
+        CommandResponse::Success {}
+    ));
+    assert_eq!(get(&rest[1].node, "key1"), Some(b"value1".to_vec()));
+
+    transport.heal(leader);
+    wait_for(|| engine_get(&old.engine, "key2") == Some("value2".to_owned()));
+    wait_for(|| old.node.stats().leader == Some(new_leader));
+    assert_eq!(get(&old.node, "key2"), Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Nodes can be added and removed, including the leader
+#[test]
+fn raft_membership() -> Result<()> {
+    let (transport, mut nodes) = start_cluster(3)?;
+    let leader = wait_for_leader(&nodes.iter().collect::<Vec<_>>(), 0);
+    for i in 0..10 {
+        assert!(matches!(
+            set(&nodes[0].node, &format!("key{}", i), "value"),
+            CommandResponse::Success {}
+        ));
+    }
+
+    nodes.push(start_node(4, BTreeMap::new(), &transport)?);
+    assert!(matches!(
+        nodes[1].node.request(CommandRequest::AddNode {
+            id: 4,
+            addr: "node4".to_owned()
+        }),
+        CommandResponse::Success {}
+    ));
+    wait_for(|| engine_get(&nodes[3].engine, "key9") == Some("value".to_owned()));
+    wait_for(|| nodes[3].node.stats().members.len() == 4);
+
+    let term = nodes[3].node.stats().term;
+    assert!(matches!(
+        nodes[3]
+            .node
+            .request(CommandRequest::RemoveNode { id: leader }),
+        CommandResponse::Success {}
+    ));
+    let rest = nodes
+        .iter()
+        .filter(|x| x.node.id() != leader)
+        .collect::<Vec<_>>();
+    let new_leader = wait_for_leader(&rest, term);
+    assert_ne!(new_leader, leader);
+    assert!(matches!(
+        set(&rest[0].node, "key10", "value"),
+        CommandResponse::Success {}
+    ));
+    for x in rest.iter() {
+        wait_for(|| engine_get(&x.engine, "key10") == Some("value".to_owned()));
+        assert!(!x.node.stats().members.contains_key(&leader));
+    }
+    // the removed node is no longer updated
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(
+        engine_get(&nodes[leader as usize - 1].engine, "key10"),
+        None
+    );
+    Ok(())
+}
+
+// A restarted node keeps its log and doesn't apply entries twice
+#[test]
+fn raft_restart() -> Result<()> {
+    let transport = Arc::new(MemoryTransport::default());
+    let members: BTreeMap<_, _> = vec![(1, "node1".to_owned())].into_iter().collect();
+    let mut node = start_node(1, members.clone(), &transport)?;
+    wait_for_leader(&[&node], 0);
+    assert!(matches!(
+        set(&node.node, "key1", "value1"),
+        CommandResponse::Success {}
+    ));
+    assert!(matches!(
+        node.node.request(CommandRequest::Remove {
+            key: b"key1".to_vec()
+        }),
+        CommandResponse::Success {}
+    ));
+    node.node.stop();
+    let applied = node.node.stats().applied_index;
+
+    node.node = restart_node(1, members, &transport, &node.engine, &node.dir)?;
+    assert_eq!(node.node.stats().applied_index, applied);
+    let term = node.node.stats().term;
+    wait_for_leader(&[&node], term);
+    assert_eq!(get(&node.node, "key1"), None);
+    assert!(matches!(
+        set(&node.node, "key2", "value2"),
+        CommandResponse::Success {}
+    ));
+    assert_eq!(get(&node.node, "key2"), Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Messages from peers connecting over TCP with another token than the one of
+// the cluster are dropped
+#[test]
+fn raft_tcp_token() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let members: BTreeMap<_, _> = vec![(1, addr.clone())].into_iter().collect();
+    let token = Some("secret".to_owned());
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Engine = Arc::new(Mutex::new(Box::new(KvStore::open(dir.path())?)));
+    let config = RaftConfig {
+        id: 1,
+        members: members.clone(),
+        dir: Some(dir.path().join("raft")),
+    };
+    let log = Logger::root(Discard, o!());
+    let transport = Arc::new(TcpTransport::new(members, token.clone()));
+    let node = RaftNode::start(config, engine, transport, &log)?;
+    TcpTransport::listen(listener, node.clone(), token, &log);
+    wait_for(|| node.stats().role == "leader");
+    let term = node.stats().term;
+
+    let send_term = |token: &str| -> Result<()> {
+        let message = Message::AppendResponse {
+            term: term + 10,
+            success: false,
+            match_index: 0,
+            heartbeat: 0,
+        };
+        let mut frames = serde_cbor::to_vec(&token)?;
+        frames.extend(serde_cbor::to_vec(&(2, message))?);
+        TcpStream::connect(&addr)?.write_all(&frames)?;
+        Ok(())
+    };
+    send_term("wrong")?;
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(node.stats().term, term);
+    send_term("secret")?;
+    wait_for(|| node.stats().term >= term + 10);
+    node.stop();
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use kvs::client::{ClientOptions, KvsClient};
+use kvs::replication::{Replication, REPLICA_FILE};
+use kvs::server::KvsServer;
+use kvs::{CommandRequest, CommandResponse, KvStore, KvsEngine, Result};
+use slog::{o, Discard, Logger};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A replica should resume after its checkpoint even if it stops before any
+// change or heartbeat is applied
+#[test]
+fn resume_after_bootstrap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut server = KvsServer::new(listener, Box::new(KvStore::open(temp_dir.path())?));
+    thread::spawn(move || server.serve(&Logger::root(Discard, o!())));
+
+    let set = |key: &str| -> Result<()> {
+        let request = CommandRequest::Set {
+            key: key.as_bytes().to_vec(),
+            value: b"value".to_vec(),
+            ttl: None,
+        };
+        match KvsClient::connect(&addr)?.request(&request)? {
+            CommandResponse::Success {} => Ok(()),
+            response => panic!("unexpected response {:?}", response),
+        }
+    };
+    set("key1")?;
+
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ClientOptions::default();
+    drop(Replication::bootstrap(&addr, &options, replica_dir.path())?);
+    let saved = std::fs::read_to_string(replica_dir.path().join(REPLICA_FILE))?;
+    assert!(saved.parse::<u64>().unwrap() > 0);
+
+    set("key2")?;
+    let replication = Replication::resume(&addr, &options, replica_dir.path())?;
+    let replica: Arc<Mutex<Box<dyn KvsEngine>>> =
+        Arc::new(Mutex::new(Box::new(KvStore::open(replica_dir.path())?)));
+    let engine = replica.clone();
+    thread::spawn(move || replication.follow(&engine));
+
+    let start = Instant::now();
+    while replica.lock().unwrap().get("key2".to_owned())?.is_none() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        replica.lock().unwrap().get("key1".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
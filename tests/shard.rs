@@ -0,0 +1,152 @@
+use kvs::client::KvsClient;
+use kvs::server::KvsServer;
+use kvs::shard::{HashRing, ShardedClient};
+use kvs::{CommandRequest, CommandResponse, KvStore, Result};
+use serde_bytes::ByteBuf;
+use slog::{o, Discard, Logger};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+/// serve a store in `dir` in background, and return its address
+fn start_server(dir: &TempDir) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut server = KvsServer::new(listener, Box::new(KvStore::open(dir.path())?));
+    thread::spawn(move || server.serve(&Logger::root(Discard, o!())));
+    Ok(addr)
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:04}", i).into_bytes()
+}
+
+// Keys are spread by weight, and adding a shard only moves keys to it
+#[test]
+fn hash_ring() {
+    let mut ring = HashRing::default();
+    ring.add("a", 1);
+    ring.add("b", 1);
+    ring.add("c", 2);
+    let owners = (0..10000)
+        .map(|i| ring.shard(&key(i)).unwrap().to_owned())
+        .collect::<Vec<_>>();
+    let mut counts = HashMap::new();
+    for owner in owners.iter() {
+        *counts.entry(owner.as_str()).or_insert(0) += 1;
+    }
+    for (shard, expected) in [("a", 2500), ("b", 2500), ("c", 5000)].iter() {
+        let count = counts[shard];
+        assert!(
+            (count as f64 - *expected as f64).abs() < *expected as f64 * 0.15,
+            "{} owns {} keys",
+            shard,
+            count
+        );
+    }
+
+    let mut new_ring = ring.clone();
+    new_ring.add("d", 1);
+    let mut moved = 0;
+    for (i, owner) in owners.iter().enumerate() {
+        let new_owner = new_ring.shard(&key(i)).unwrap();
+        if new_owner != owner {
+            assert_eq!(new_owner, "d");
+            moved += 1;
+        }
+    }
+    assert!(moved > 1500 && moved < 2500, "{} keys moved", moved);
+
+    new_ring.remove("d");
+    for (i, owner) in owners.iter().enumerate() {
+        assert_eq!(new_ring.shard(&key(i)), Some(owner.as_str()));
+    }
+    assert_eq!(HashRing::default().shard(b"key"), None);
+}
+
+// Requests are routed to the shard owning the key, multi-key requests are split,
+// and rebalance moves keys to a new shard
+#[test]
+fn sharded_client() -> Result<()> {
+    let dirs = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect::<Vec<_>>();
+    let addrs = dirs.iter().map(start_server).collect::<Result<Vec<_>>>()?;
+    let mut ring = HashRing::default();
+    ring.add(&addrs[0], 1);
+    ring.add(&addrs[1], 1);
+    let mut client = ShardedClient::new(ring.clone());
+
+    for i in 0..100 {
+        let response = client.request(&CommandRequest::Set {
+            key: key(i),
+            value: key(i),
+            ttl: None,
+        })?;
+        assert!(matches!(response, CommandResponse::Success {}));
+    }
+    let pairs = (100..200)
+        .map(|i| (ByteBuf::from(key(i)), ByteBuf::from(key(i))))
+        .collect();
+    let response = client.request(&CommandRequest::MSet { pairs })?;
+    assert!(matches!(response, CommandResponse::Success {}));
+
+    // every key is stored on its owner only
+    for (i, owner) in (0..200).map(|i| (i, ring.shard(&key(i)).unwrap())) {
+        for addr in addrs[..2].iter() {
+            let value =
+                match KvsClient::connect(addr)?.request(&CommandRequest::Get { key: key(i) })? {
+                    CommandResponse::Value { value, .. } => value,
+                    response => panic!("unexpected response {:?}", response),
+                };
+            assert_eq!(value.is_some(), addr == owner);
+        }
+    }
+
+    let keys = (190..210).map(|i| ByteBuf::from(key(i))).collect();
+    match client.request(&CommandRequest::MGet { keys })? {
+        CommandResponse::Values { values } => {
+            for (i, value) in (190..210).zip(values) {
+                assert_eq!(
+                    value.map(ByteBuf::into_vec),
+                    Some(key(i)).filter(|_| i < 200)
+                );
+            }
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    let scan = CommandRequest::Scan {
+        start: key(50),
+        end: Some(key(150)),
+        limit: Some(60),
+    };
+    match client.request(&scan)? {
+        CommandResponse::Pairs { pairs } => {
+            let keys = pairs
+                .into_iter()
+                .map(|(key, _)| key.into_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(keys, (50..110).map(key).collect::<Vec<_>>());
+        }
+        response => panic!("unexpected response {:?}", response),
+    }
+    assert!(client.request(&CommandRequest::Begin {}).is_err());
+
+    ring.add(&addrs[2], 1);
+    let mut client = ShardedClient::new(ring.clone());
+    let moved = client.rebalance()?;
+    let expected = (0..200)
+        .filter(|i| ring.shard(&key(*i)) == Some(addrs[2].as_str()))
+        .count();
+    assert_eq!(moved, expected as u64);
+    assert!(moved > 0);
+    assert_eq!(client.rebalance()?, 0);
+    for i in 0..200 {
+        match client.request(&CommandRequest::Get { key: key(i) })? {
+            CommandResponse::Value { value, .. } => assert_eq!(value, Some(key(i))),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use assert_cmd::prelude::*;
+use kvs::client::{ClientOptions, KvsClient};
+use kvs::server::KvsServer;
+use kvs::tls::{TlsAcceptor, TlsConnector};
+use kvs::{CommandRequest, CommandResponse, KvStore, Result};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use slog::{o, Discard, Logger};
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn self_signed_ca() -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+/// write certificate for `names` signed by `ca` and its key into `dir`
+fn write_cert(dir: &Path, name: &str, names: &[&str], ca: &Certificate) {
+    let names = names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let cert = Certificate::from_params(CertificateParams::new(names)).unwrap();
+    fs::write(
+        dir.join(format!("{}.pem", name)),
+        cert.serialize_pem_with_signer(ca).unwrap(),
+    )
+    .unwrap();
+    fs::write(
+        dir.join(format!("{}.key", name)),
+        cert.serialize_private_key_pem(),
+    )
+    .unwrap();
+}
+
+/// generate self-signed CAs `ca` and `other-ca`, and certificates `server` and
+/// `client` signed by `ca`, as PEM files in a temporary directory
+fn generate_certs() -> TempDir {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = self_signed_ca();
+    fs::write(dir.path().join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    fs::write(
+        dir.path().join("other-ca.pem"),
+        self_signed_ca().serialize_pem().unwrap(),
+    )
+    .unwrap();
+    write_cert(dir.path(), "server", &["localhost", "127.0.0.1"], &ca);
+    write_cert(dir.path(), "client", &["client"], &ca);
+    dir
+}
+
+/// serve a store over TLS in background, and return its address
+fn start_server(dir: &TempDir, acceptor: TlsAcceptor) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut server = KvsServer::new(listener, Box::new(KvStore::open(dir.path())?));
+    server.use_tls(acceptor);
+    thread::spawn(move || server.serve(&Logger::root(Discard, o!())));
+    Ok(addr)
+}
+
+fn set(client: &mut KvsClient, key: &str, value: &str) -> Result<CommandResponse> {
+    client.request(&CommandRequest::Set {
+        key: key.into(),
+        value: value.into(),
+        ttl: None,
+    })
+}
+
+// Clients trusting the CA of the server can connect over TLS by IP address or
+// DNS name, and others can't
+#[test]
+fn tls_server() -> Result<()> {
+    let certs = generate_certs();
+    let cert = |name: &str| certs.path().join(name);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acceptor = TlsAcceptor::new(&cert("server.pem"), &cert("server.key"), None)?;
+    let addr = start_server(&temp_dir, acceptor)?;
+
+    let options = ClientOptions {
+        tls: Some(TlsConnector::new(&cert("ca.pem"), None)?),
+        ..Default::default()
+    };
+    let mut client = KvsClient::connect_with_options(&addr, &options)?;
+    assert!(matches!(
+        set(&mut client, "key1", "value1")?,
+        CommandResponse::Success {}
+    ));
+    let port = addr.rsplit(':').next().unwrap();
+    let mut client = KvsClient::connect_with_options(&format!("localhost:{}", port), &options)?;
+    match client.request(&CommandRequest::Get { key: "key1".into() })? {
+        CommandResponse::Value { value, .. } => assert_eq!(value, Some(b"value1".to_vec())),
+        response => panic!("unexpected response {:?}", response),
+    }
+
+    let options = ClientOptions {
+        tls: Some(TlsConnector::new(&cert("other-ca.pem"), None)?),
+        ..Default::default()
+    };
+    let mut client = KvsClient::connect_with_options(&addr, &options)?;
+    assert!(set(&mut client, "key2", "value2").is_err());
+    let mut client = KvsClient::connect(&addr)?;
+    assert!(set(&mut client, "key2", "value2").is_err());
+    Ok(())
+}
+
+// Servers verifying client certificates only accept clients presenting one
+// signed by the client CA
+#[test]
+fn tls_client_certificates() -> Result<()> {
+    let certs = generate_certs();
+    let cert = |name: &str| certs.path().join(name);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acceptor = TlsAcceptor::new(
+        &cert("server.pem"),
+        &cert("server.key"),
+        Some(&cert("ca.pem")),
+    )?;
+    let addr = start_server(&temp_dir, acceptor)?;
+
+    let options = ClientOptions {
+        tls: Some(TlsConnector::new(
+            &cert("ca.pem"),
+            Some((&cert("client.pem"), &cert("client.key"))),
+        )?),
+        ..Default::default()
+    };
+    let mut client = KvsClient::connect_with_options(&addr, &options)?;
+    assert!(matches!(
+        set(&mut client, "key1", "value1")?,
+        CommandResponse::Success {}
+    ));
+
+    let options = ClientOptions {
+        tls: Some(TlsConnector::new(&cert("ca.pem"), None)?),
+        ..Default::default()
+    };
+    let mut client = KvsClient::connect_with_options(&addr, &options)?;
+    assert!(set(&mut client, "key2", "value2").is_err());
+    Ok(())
+}
+
+// `kvs-server --tls-cert --tls-key --tls-client-ca` should serve `kvs-client`
+// given `--tls-ca` and a client certificate only.
+#[test]
+fn cli_tls() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let certs = generate_certs();
+    let cert = |name: &str| certs.path().join(name);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4033"])
+        .arg("--tls-cert")
+        .arg(cert("server.pem"))
+        .arg("--tls-key")
+        .arg(cert("server.key"))
+        .arg("--tls-client-ca")
+        .arg(cert("ca.pem"))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4033"])
+        .arg("--tls-ca")
+        .arg(cert("ca.pem"))
+        .arg("--tls-cert")
+        .arg(cert("client.pem"))
+        .arg("--tls-key")
+        .arg(cert("client.key"))
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4033"])
+        .arg("--tls-ca")
+        .arg(cert("ca.pem"))
+        .arg("--tls-cert")
+        .arg(cert("client.pem"))
+        .arg("--tls-key")
+        .arg(cert("client.key"))
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4033"])
+        .arg("--tls-ca")
+        .arg(cert("ca.pem"))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}